@@ -1,3 +1,9 @@
+use crate::gopher::neighbor::NeighborTable;
+use crate::gopher::stats::NetStats;
+use crate::shm::{SHM_SLOT_SIZE, ShmPool};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::Endpoint;
 use glenda::client::ResourceClient;
 use glenda::drivers::client::net::NetClient;
@@ -5,17 +11,90 @@ use glenda::drivers::client::{RingParams, ShmParams};
 use glenda::drivers::interface::{DriverClient, NetDriver};
 use glenda::drivers::protocol::net::MacAddress;
 use glenda::error::Error;
+use glenda::io::uring::{IoUringCqe, IoUringSqe};
+use glenda::utils::align::align_up;
 use smoltcp::phy;
-use smoltcp::phy::{Device, DeviceCapabilities, Medium};
+use smoltcp::phy::{Checksum, ChecksumCapabilities, Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
 
 use glenda::interface::{CSpaceService, VSpaceService};
 
+pub const DEFAULT_RX_BUFFERS: usize = 4;
+pub const DEFAULT_TX_BUFFERS: usize = 4;
+pub const DEFAULT_MTU: usize = 1500;
+pub const RX_BUFFER_SIZE: usize = 2048;
+const RX_ID_BASE: usize = 0x100;
+
+/// Bytes of a driver ring with `entries` SQEs and as many CQEs: a page for the ring
+/// header and indices, then the entries rounded up to whole pages.
+pub fn ring_size(entries: usize) -> usize {
+    let bytes = entries * (size_of::<IoUringSqe>() + size_of::<IoUringCqe>());
+    PGSIZE + align_up(bytes, PGSIZE)
+}
+
+pub struct RxSlot {
+    pub id: usize,
+    pub shm_idx: usize,
+}
+
+pub struct RxCompletion {
+    pub id: usize,
+    pub shm_idx: usize,
+    pub len: usize,
+}
+
+/// Checksum work the hardware takes over, as reported by the driver.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChecksumOffload {
+    pub tx: bool,
+    pub rx: bool,
+}
+
+/// Speed and duplex the link negotiated, as far as the driver can tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkMode {
+    pub speed_mbps: Option<u32>,
+    pub duplex: Duplex,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Duplex {
+    Full,
+    Half,
+    #[default]
+    Unknown,
+}
+
+impl Duplex {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Half => "half",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
 pub struct GlendaNetDevice {
     pub client: NetClient,
-    pub rx_pending: bool,
-    pub rx_id: usize,
+    pub rx_slots: Vec<RxSlot>,
+    pub rx_inflight: BTreeMap<usize, usize>, // user_data -> shm_idx
+    pub rx_ready: VecDeque<RxCompletion>,
+    pub tx_slots: Vec<usize>,
+    pub next_tx: usize,
+    pub stats: NetStats,
+    pub mtu: usize,
+    pub csum_offload: ChecksumOffload,
+    pub shm_size: usize,
+    // First slot of this device's window in the server-wide ShmPool
+    pub shm_base: usize,
+    // Gopher's address range for the driver ring, as (vaddr, size), freed on removal
+    pub ring_va: (usize, usize),
+    pub neighbors: NeighborTable,
     pub name: alloc::string::String,
+    // Set through SET_MAC, replacing the address the driver reports
+    pub mac_override: Option<EthernetAddress>,
 }
 
 impl DriverClient for GlendaNetDevice {
@@ -39,18 +118,163 @@ impl GlendaNetDevice {
         res: &mut ResourceClient,
         ring: RingParams,
         shm: ShmParams,
+        shm_base: usize,
+        rx_buffers: usize,
+        tx_buffers: usize,
     ) -> Self {
+        let shm_size = shm.size;
+        // Slot indices are relative to this device's own window of the global pool
+        let mut pool = ShmPool::new(shm_size);
+        // RX buffers and TX staging each get their own page so DMA into an RX buffer
+        // never races a frame being built for TX
+        let rx_slots = (0..rx_buffers.max(1))
+            .map_while(|i| pool.alloc_slot().map(|shm_idx| RxSlot { id: RX_ID_BASE + i, shm_idx }))
+            .collect();
+        let tx_slots = (0..tx_buffers.max(1)).map_while(|_| pool.alloc_slot()).collect();
         Self {
             client: NetClient::new(cap, res, ring, shm),
-            rx_pending: false,
-            rx_id: 0x100,
+            rx_slots,
+            rx_inflight: BTreeMap::new(),
+            rx_ready: VecDeque::new(),
+            tx_slots,
+            next_tx: 0,
+            stats: NetStats::default(),
+            mtu: DEFAULT_MTU,
+            csum_offload: ChecksumOffload::default(),
+            shm_size,
+            shm_base,
+            ring_va: (0, 0),
+            neighbors: NeighborTable::default(),
             name: alloc::string::String::from(name),
+            mac_override: None,
         }
     }
 
+    /// Submit every idle RX buffer that is neither in flight nor waiting to be consumed.
+    fn refill_rx(&mut self) {
+        let base = match self.client.shm() {
+            Some(shm) => shm.as_ptr(),
+            None => return,
+        };
+        for rx in &self.rx_slots {
+            if self.rx_inflight.contains_key(&rx.id)
+                || self.rx_ready.iter().any(|done| done.id == rx.id)
+            {
+                continue;
+            }
+            let buf = unsafe {
+                core::slice::from_raw_parts_mut(base.add(rx.shm_idx * 4096), RX_BUFFER_SIZE)
+            };
+            if self.client.submit_recv(buf, rx.id).is_ok() {
+                self.rx_inflight.insert(rx.id, rx.shm_idx);
+            }
+        }
+    }
+
+    /// TX staging page for the next frame. Slots are handed out in rotation, so a page
+    /// is only reused after every other TX slot has been used once.
+    fn next_tx_slot(&mut self) -> Option<usize> {
+        if self.tx_slots.is_empty() {
+            return None;
+        }
+        let idx = self.tx_slots[self.next_tx % self.tx_slots.len()];
+        self.next_tx = self.next_tx.wrapping_add(1);
+        Some(idx)
+    }
+
+    /// Frames that can be staged for TX before the rotation reuses a page the driver
+    /// may still be sending from.
+    pub fn tx_depth(&self) -> usize {
+        self.tx_slots.len()
+    }
+
+    fn tx_token(&mut self) -> TxToken<'_> {
+        let tx_idx = self.next_tx_slot();
+        TxToken { client: &mut self.client, stats: &mut self.stats, mtu: self.mtu, tx_idx }
+    }
+
+    /// Drain completions and queue finished RX buffers in completion order,
+    /// remembering the SHM page each one was submitted against.
+    fn reap_rx(&mut self) {
+        while let Some(cqe) = self.client.peek_cqe() {
+            let Some(shm_idx) = self.rx_inflight.remove(&cqe.user_data) else {
+                continue;
+            };
+            if cqe.res > 0 {
+                self.rx_ready.push_back(RxCompletion {
+                    id: cqe.user_data,
+                    shm_idx,
+                    len: cqe.res as usize,
+                });
+            } else {
+                self.stats.rx_drops += 1;
+            }
+        }
+    }
+
+    /// Number of SHM slots a device with `rx_buffers` RX buffers and `tx_buffers` TX
+    /// staging pages needs.
+    pub fn shm_slots(rx_buffers: usize, tx_buffers: usize) -> usize {
+        rx_buffers.max(1) + tx_buffers.max(1)
+    }
+
+    /// Return this device's SHM window to the pool. Only valid once the driver has
+    /// stopped using it, i.e. after `disconnect`.
+    pub fn release_shm(&mut self, pool: &mut ShmPool) {
+        self.rx_inflight.clear();
+        self.rx_ready.clear();
+        self.rx_slots.clear();
+        self.tx_slots.clear();
+        pool.release(self.shm_base, self.shm_size / SHM_SLOT_SIZE);
+        self.shm_size = 0;
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Query L4 checksum offload from the driver; falls back to software checksums.
+    pub fn probe_checksum_offload(&mut self) {
+        self.csum_offload = match self.client.checksum_offload() {
+            Ok((tx, rx)) => ChecksumOffload { tx, rx },
+            Err(_) => ChecksumOffload::default(),
+        };
+    }
+
+    /// MAC the interface uses: the SET_MAC override, or the one the driver reports.
+    pub fn ethernet_addr(&self) -> EthernetAddress {
+        self.mac_override.unwrap_or(EthernetAddress(self.client.mac_address().octets))
+    }
+
+    /// Switch the NIC to `mac`, so its RX filter takes unicast frames for the new
+    /// address, and use it from now on.
+    pub fn set_mac(&mut self, mac: EthernetAddress) -> Result<(), Error> {
+        self.client.set_mac_address(MacAddress { octets: mac.0 })?;
+        self.mac_override = Some(mac);
+        Ok(())
+    }
+
+    /// Send a gratuitous ARP for `ip` from the current MAC, so peers update their caches.
+    pub fn announce(&mut self, ip: Ipv4Address) {
+        let frame = crate::gopher::neighbor::gratuitous_arp(self.ethernet_addr(), ip);
+        if self.client.send_packet(&frame).is_ok() {
+            self.stats.tx_packets += 1;
+            self.stats.tx_bytes += frame.len() as u64;
+        } else {
+            self.stats.tx_drops += 1;
+        }
+    }
+
+    /// Ask the driver whether the link currently has carrier.
+    pub fn link_up(&mut self) -> bool {
+        self.client.link_status().unwrap_or(false)
+    }
+
+    /// Speed and duplex of the link. The net driver protocol has no request for them
+    /// yet, so every link reads as unknown until drivers can report it.
+    pub fn link_mode(&mut self) -> LinkMode {
+        LinkMode::default()
+    }
 }
 
 impl NetDriver for GlendaNetDevice {
@@ -61,8 +285,23 @@ impl NetDriver for GlendaNetDevice {
 
 pub struct RxToken {
     pub shm: *mut u8,
+    pub shm_size: usize,
     pub shm_idx: usize,
     pub len: usize,
+    // Frame synthesized by Gopher itself rather than received into SHM
+    pub frame: Option<Vec<u8>>,
+}
+
+impl RxToken {
+    /// Whether the packet lies entirely inside the mapped SHM pool.
+    pub fn in_bounds(&self) -> bool {
+        self.len <= RX_BUFFER_SIZE
+            && self
+                .shm_idx
+                .checked_mul(4096)
+                .and_then(|off| off.checked_add(self.len))
+                .is_some_and(|end| end <= self.shm_size)
+    }
 }
 
 impl phy::RxToken for RxToken {
@@ -70,6 +309,12 @@ impl phy::RxToken for RxToken {
     where
         F: FnOnce(&[u8]) -> R,
     {
+        if let Some(frame) = &self.frame {
+            return f(frame);
+        }
+        if !self.in_bounds() {
+            return f(&[]);
+        }
         let slice =
             unsafe { core::slice::from_raw_parts(self.shm.add(self.shm_idx * 4096), self.len) };
         f(slice)
@@ -78,22 +323,47 @@ impl phy::RxToken for RxToken {
 
 pub struct TxToken<'a> {
     client: &'a mut NetClient,
+    stats: &'a mut NetStats,
+    mtu: usize,
+    tx_idx: Option<usize>,
+}
+
+impl<'a> TxToken<'a> {
+    fn send(&mut self, packet: &[u8]) {
+        if self.client.send_packet(packet).is_ok() {
+            self.stats.tx_packets += 1;
+            self.stats.tx_bytes += packet.len() as u64;
+        } else {
+            self.stats.tx_drops += 1;
+        }
+    }
 }
 
 impl<'a> phy::TxToken for TxToken<'a> {
-    fn consume<R, F>(self, len: usize, f: F) -> R
+    fn consume<R, F>(mut self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        if let Some(shm) = self.client.shm() {
-            let slice = unsafe { shm.as_mut_slice() };
-            let result = f(&mut slice[..len]);
-            let _ = self.client.send_packet(&slice[..len]);
+        if len > self.mtu {
+            warn!("Dropping {} byte frame larger than MTU {}", len, self.mtu);
+            self.stats.tx_drops += 1;
+            let mut buffer = alloc::vec![0u8; len];
+            return f(&mut buffer);
+        }
+        let staging = match (self.client.shm(), self.tx_idx) {
+            (Some(shm), Some(idx)) if len <= 4096 => Some(unsafe { shm.as_ptr().add(idx * 4096) }),
+            _ => None,
+        };
+        if let Some(ptr) = staging {
+            let slice = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+            let result = f(slice);
+            self.send(slice);
             result
         } else {
-            let mut buffer = [0u8; 2048];
-            let result = f(&mut buffer[..len]);
-            let _ = self.client.send_packet(&buffer[..len]);
+            // No staging page, or a frame bigger than one: the MTU has no upper bound
+            let mut buffer = alloc::vec![0u8; len];
+            let result = f(&mut buffer);
+            self.send(&buffer);
             result
         }
     }
@@ -109,45 +379,73 @@ impl Device for GlendaNetDevice {
     where
         Self: 'a;
 
-    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        // Pre-submit up to 4 RX buffers if they are not already pending
-        if let Some(shm) = self.client.shm() {
-            if !self.rx_pending {
-                // Buffer offset for RX (use first page, 4KB)
-                let slice = unsafe { &mut shm.as_mut_slice()[..2048] };
-                if self.client.submit_recv(slice, self.rx_id).is_ok() {
-                    self.rx_pending = true;
-                }
-            }
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let local_mac = self.ethernet_addr();
+        if let Some(frame) = self.neighbors.next_announcement(local_mac, timestamp) {
+            let rx = RxToken {
+                shm: core::ptr::null_mut(),
+                shm_size: 0,
+                shm_idx: 0,
+                len: frame.len(),
+                frame: Some(frame),
+            };
+            return Some((rx, self.tx_token()));
         }
 
-        // Peek for RX completion
-        if self.rx_pending {
-            if let Some(cqe) = self.client.peek_cqe() {
-                if cqe.user_data == self.rx_id {
-                    self.rx_pending = false;
-                    if cqe.res > 0 {
-                        let len = cqe.res as usize;
-                        let shm_ptr = self.client.shm().unwrap().as_ptr();
-                        // Packets in SHM are zero-copy: they were written by VirtIO DMA
-                        let rx = RxToken { shm: shm_ptr, shm_idx: 0, len };
-                        let tx = TxToken { client: &mut self.client };
-                        return Some((rx, tx));
-                    }
-                }
+        // The buffer handed out by the previous call has been consumed by now,
+        // so it goes back into flight together with any other idle slot.
+        self.refill_rx();
+        self.reap_rx();
+
+        // Packets in SHM are zero-copy: they were written by VirtIO DMA
+        let shm_ptr = self.client.shm()?.as_ptr();
+        let rx = loop {
+            let done = self.rx_ready.pop_front()?;
+            let rx = RxToken {
+                shm: shm_ptr,
+                shm_size: self.shm_size,
+                shm_idx: done.shm_idx,
+                len: done.len,
+                frame: None,
+            };
+            if rx.in_bounds() {
+                break rx;
             }
-        }
-        None
+            warn!(
+                "{}: dropping RX completion outside SHM pool (idx={}, len={})",
+                self.name, done.shm_idx, done.len
+            );
+            self.stats.rx_drops += 1;
+        };
+        self.stats.rx_packets += 1;
+        self.stats.rx_bytes += rx.len as u64;
+        let frame = unsafe { core::slice::from_raw_parts(rx.shm.add(rx.shm_idx * 4096), rx.len) };
+        self.neighbors.snoop(frame, timestamp);
+        Some((rx, self.tx_token()))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        Some(TxToken { client: &mut self.client })
+        Some(self.tx_token())
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1500;
+        caps.max_transmission_unit = self.mtu;
         caps.medium = Medium::Ethernet;
+        // Bursts longer than the TX rotation would overwrite frames still in flight
+        caps.max_burst_size = Some(self.tx_depth().max(1));
+
+        // smoltcp only computes/verifies what the hardware does not
+        let l4 = match (!self.csum_offload.rx, !self.csum_offload.tx) {
+            (true, true) => Checksum::Both,
+            (true, false) => Checksum::Rx,
+            (false, true) => Checksum::Tx,
+            (false, false) => Checksum::None,
+        };
+        let mut checksum = ChecksumCapabilities::default();
+        checksum.tcp = l4;
+        checksum.udp = l4;
+        caps.checksum = checksum;
         caps
     }
 }
@@ -0,0 +1,206 @@
+use super::GopherServer;
+use super::network::GopherSocket;
+use super::ops;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use glenda::cap::CapPtr;
+use glenda::error::Error;
+use glenda::interface::SocketService;
+use glenda::ipc::Badge;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{dns, tcp};
+
+/// SEND/RECV/ACCEPT calls on blocking sockets waiting for the socket to become ready.
+/// Each one holds a deferred reply that is answered from `poll()`.
+#[derive(Default)]
+pub struct BlockedCalls {
+    // Reply cap, the most the caller's buffer can take and the RECV flags
+    pub recv: VecDeque<(CapPtr, usize, i32)>,
+    pub send: VecDeque<BlockedSend>,
+    pub accept: VecDeque<CapPtr>,
+}
+
+/// A blocking SEND waiting for room in the TX buffer. `sent` bytes of `data` are
+/// already queued; the reply goes out once the rest is, or on the first error.
+pub struct BlockedSend {
+    pub reply: CapPtr,
+    pub data: Vec<u8>,
+    pub sent: usize,
+    pub flags: i32,
+}
+
+/// A CLOSE on a socket with SO_LINGER, answered once the peer has acknowledged our FIN
+/// or the linger time runs out. The socket itself is already unregistered.
+pub struct LingeringClose {
+    pub handle: SocketHandle,
+    pub reply: CapPtr,
+    pub deadline_ns: u64,
+}
+
+impl BlockedCalls {
+    pub fn is_empty(&self) -> bool {
+        self.recv.is_empty() && self.send.is_empty() && self.accept.is_empty()
+    }
+}
+
+impl<'a> GopherServer<'a> {
+    pub fn is_blocking(&self, badge: Badge) -> bool {
+        self.socket_map.get(&badge).is_some_and(|entry| !entry.nonblocking)
+    }
+
+    /// Whether a SEND or RECV with `flags` may park: the socket is blocking and the
+    /// call did not ask for MSG_DONTWAIT.
+    pub fn may_block(&self, badge: Badge, flags: i32) -> bool {
+        self.is_blocking(badge) && flags & ops::MSG_DONTWAIT == 0
+    }
+
+    /// Whether a RECV (or SEND) is already parked, so a new one must queue behind it.
+    pub fn has_blocked_recv(&self, badge: Badge) -> bool {
+        self.blocked.get(&badge).is_some_and(|calls| !calls.recv.is_empty())
+    }
+
+    pub fn has_blocked_send(&self, badge: Badge) -> bool {
+        self.blocked.get(&badge).is_some_and(|calls| !calls.send.is_empty())
+    }
+
+    pub fn has_blocked_accept(&self, badge: Badge) -> bool {
+        self.blocked.get(&badge).is_some_and(|calls| !calls.accept.is_empty())
+    }
+
+    pub fn block_accept(&mut self, badge: Badge) -> Result<(), Error> {
+        let reply = self.defer_reply()?;
+        self.blocked.entry(badge).or_default().accept.push_back(reply);
+        Ok(())
+    }
+
+    pub fn block_recv(&mut self, badge: Badge, max_len: usize, flags: i32) -> Result<(), Error> {
+        let reply = self.defer_reply()?;
+        self.blocked.entry(badge).or_default().recv.push_back((reply, max_len, flags));
+        Ok(())
+    }
+
+    pub fn block_send(
+        &mut self,
+        badge: Badge,
+        data: &[u8],
+        sent: usize,
+        flags: i32,
+    ) -> Result<(), Error> {
+        let reply = self.defer_reply()?;
+        let call = BlockedSend { reply, data: data.to_vec(), sent, flags };
+        self.blocked.entry(badge).or_default().send.push_back(call);
+        Ok(())
+    }
+
+    /// Whether the connection behind `handle` has sent everything and had its FIN acked.
+    pub fn tcp_flushed(&self, handle: SocketHandle) -> bool {
+        matches!(
+            self.sockets.get::<tcp::Socket>(handle).state(),
+            tcp::State::Closed | tcp::State::TimeWait
+        )
+    }
+
+    /// Park the CLOSE being dispatched until `handle` is flushed or `secs` pass.
+    pub fn block_linger(&mut self, handle: SocketHandle, secs: u32) -> Result<(), Error> {
+        let reply = self.defer_reply()?;
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let deadline_ns = now.saturating_add(secs as u64 * 1_000_000_000);
+        self.lingering.push(LingeringClose { handle, reply, deadline_ns });
+        self.arm_timer(deadline_ns);
+        Ok(())
+    }
+
+    /// Answer lingering CLOSEs whose connection finished or whose time ran out. Data
+    /// still queued after the deadline keeps draining in the background.
+    pub fn drive_linger(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        for linger in core::mem::take(&mut self.lingering) {
+            if self.tcp_flushed(linger.handle) || linger.deadline_ns <= now {
+                self.finish_reply(linger.reply, Ok(0), &[]);
+            } else {
+                self.lingering.push(linger);
+            }
+        }
+    }
+
+    /// Fail every call still parked on a socket that is going away, CONNECT_HOST included.
+    pub fn fail_blocked(&mut self, badge: Badge, err: Error) {
+        if let Some(hc) = self.host_connects.remove(&badge) {
+            if let (Some(query), Some(handle)) = (hc.query, self.dns_handle) {
+                self.sockets.get_mut::<dns::Socket>(handle).cancel_query(query);
+            }
+            self.finish_reply(hc.reply, Err(err), &[]);
+        }
+        let Some(calls) = self.blocked.remove(&badge) else {
+            return;
+        };
+        let replies = calls
+            .recv
+            .into_iter()
+            .map(|(reply, _, _)| reply)
+            .chain(calls.send.into_iter().map(|call| call.reply))
+            .chain(calls.accept);
+        for reply in replies {
+            self.finish_reply(reply, Err(err), &[]);
+        }
+    }
+
+    /// Bytes a single RECV may return: the configured `recv_size`, capped by `capacity`,
+    /// the room in the caller's UTCB buffer.
+    pub fn recv_size(&self, capacity: usize) -> usize {
+        self.config.as_ref().and_then(|c| c.recv_size).map_or(capacity, |size| size.min(capacity))
+    }
+
+    /// Answer every parked call whose socket has become ready.
+    pub fn drive_blocked(&mut self) {
+        let badges: Vec<Badge> = self.blocked.keys().copied().collect();
+        for badge in badges {
+            let Some(mut calls) = self.blocked.remove(&badge) else {
+                continue;
+            };
+            // Received data goes straight into the reply buffer, without a bounce copy
+            while let Some((reply, max_len, flags)) = calls.recv.front().copied() {
+                let answered = self.try_finish_reply(reply, |server, buf| {
+                    let mut socket = GopherSocket { server, badge };
+                    socket.recv(&mut buf[..max_len.min(buf.len())], flags)
+                });
+                if !answered {
+                    break;
+                }
+                calls.recv.pop_front();
+            }
+            while let Some(reply) = calls.accept.front().copied() {
+                let mut socket = GopherSocket { server: self, badge };
+                match socket.accept() {
+                    Err(Error::WouldBlock) => break,
+                    res => {
+                        calls.accept.pop_front();
+                        self.finish_reply(reply, res, &[]);
+                    }
+                }
+            }
+            while let Some(call) = calls.send.front_mut() {
+                let mut socket = GopherSocket { server: self, badge };
+                let res = match socket.send(&call.data[call.sent..], call.flags) {
+                    Ok(len) => {
+                        call.sent += len;
+                        if call.sent < call.data.len() {
+                            break;
+                        }
+                        Ok(call.sent)
+                    }
+                    Err(Error::WouldBlock) => break,
+                    // Bytes already queued still count as sent
+                    Err(_) if call.sent > 0 => Ok(call.sent),
+                    Err(e) => Err(e),
+                };
+                let reply = call.reply;
+                calls.send.pop_front();
+                self.finish_reply(reply, res, &[]);
+            }
+            if !calls.is_empty() {
+                self.blocked.insert(badge, calls);
+            }
+        }
+    }
+}
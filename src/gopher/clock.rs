@@ -0,0 +1,23 @@
+use glenda::cap::CapPtr;
+use glenda::client::TimeClient;
+use glenda::error::Error;
+use glenda::interface::TimeService;
+use glenda::ipc::Badge;
+
+/// The two things Gopher needs from the time server: monotonic time in ns and a
+/// wakeup at a deadline. Under test a fake clock stands in, so the server runs on the
+/// host without IPC.
+pub trait Clock {
+    fn mono_now(&mut self, badge: Badge) -> Result<u64, Error>;
+    fn notify_at(&mut self, badge: Badge, deadline_ns: u64, ep: CapPtr) -> Result<(), Error>;
+}
+
+impl Clock for TimeClient {
+    fn mono_now(&mut self, badge: Badge) -> Result<u64, Error> {
+        TimeService::mono_now(self, badge)
+    }
+
+    fn notify_at(&mut self, badge: Badge, deadline_ns: u64, ep: CapPtr) -> Result<(), Error> {
+        TimeService::notify_at(self, badge, deadline_ns, ep)
+    }
+}
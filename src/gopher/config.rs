@@ -1,32 +1,330 @@
 use alloc::string::String;
 use alloc::vec::Vec;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NetworkInterfaceConfig {
     pub name: String,
-    pub ipv4: String,
+    /// One address or a list of aliases; entries may carry their own `/mask`.
+    #[serde(deserialize_with = "string_or_list")]
+    pub ipv4: Vec<String>,
     #[serde(default = "default_mask")]
     pub mask: u8,
     #[serde(default)]
     pub gateway: Option<String>,
+    // Frame size including the Ethernet header, clamped by `device_mtu` when parsed
+    #[serde(default = "default_mtu", deserialize_with = "device_mtu")]
+    pub mtu: usize,
+    // Ring depth for this device, overriding the global `rx_buffers` and `tx_buffers`
+    #[serde(default)]
+    pub rx_buffers: Option<usize>,
+    #[serde(default)]
+    pub tx_buffers: Option<usize>,
 }
 
 pub fn default_mask() -> u8 {
     24
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl NetworkInterfaceConfig {
+    /// Parsed `(address, mask)` pairs, falling back to `mask` for bare addresses.
+    /// Entries that don't parse are logged and skipped.
+    pub fn addresses(&self) -> Vec<(Ipv4Address, u8)> {
+        parse_addresses(&self.ipv4, self.mask, &self.name)
+    }
+}
+
+fn parse_addresses(entries: &[String], default_mask: u8, name: &str) -> Vec<(Ipv4Address, u8)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (addr, mask) = match entry.split_once('/') {
+                Some((addr, mask)) => (addr, mask.parse::<u8>().ok().filter(|m| *m <= 32)),
+                None => (entry.as_str(), Some(default_mask)),
+            };
+            match (addr.parse::<Ipv4Address>(), mask) {
+                (Ok(addr), Some(mask)) => Some((addr, mask)),
+                _ => {
+                    warn!("Ignoring invalid address {} for {}", entry, name);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Addressing for interfaces that have no entry of their own in `interfaces`. Fields
+/// left out keep the QEMU user-network defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultInterfaceConfig {
+    #[serde(deserialize_with = "string_or_list")]
+    pub ipv4: Vec<String>,
+    pub mask: u8,
+    pub gateway: Option<String>,
+}
+
+impl Default for DefaultInterfaceConfig {
+    fn default() -> Self {
+        Self {
+            ipv4: alloc::vec![String::from("10.0.2.15")],
+            mask: default_mask(),
+            gateway: Some(String::from("10.0.2.2")),
+        }
+    }
+}
+
+impl DefaultInterfaceConfig {
+    pub fn addresses(&self, name: &str) -> Vec<(Ipv4Address, u8)> {
+        parse_addresses(&self.ipv4, self.mask, name)
+    }
+}
+
+/// Address and MTU of the `lo` interface, read once at startup. `"loopback": false`
+/// is shorthand for `{ "enabled": false }`, which leaves `lo` out altogether.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoopbackConfig {
+    pub enabled: bool,
+    pub ipv4: String,
+    pub mask: u8,
+    // No RX buffers to fit on loopback, so only `MIN_MTU` applies
+    #[serde(deserialize_with = "loopback_mtu")]
+    pub mtu: usize,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self { enabled: true, ipv4: String::from("127.0.0.1"), mask: 8, mtu: 65535 }
+    }
+}
+
+impl LoopbackConfig {
+    /// The configured address, or 127.0.0.1/8 with a warning if it does not parse.
+    pub fn address(&self) -> (Ipv4Address, u8) {
+        match (self.ipv4.parse::<Ipv4Address>(), self.mask) {
+            (Ok(addr), mask) if mask <= 32 => (addr, mask),
+            _ => {
+                warn!("Ignoring invalid loopback address {}/{}", self.ipv4, self.mask);
+                (Ipv4Address::new(127, 0, 0, 1), 8)
+            }
+        }
+    }
+}
+
+/// A peer to keep pre-connected TCP sockets to, handed out by POOL_CONNECT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnPoolConfig {
+    pub peer: String,
+    pub port: u16,
+    #[serde(default = "default_pool_size")]
+    pub size: usize,
+    // Pooled connections unused this long are closed, and the pool is only refilled
+    // while it has been asked for within that time
+    #[serde(default = "default_pool_idle_ms")]
+    pub idle_timeout_ms: u64,
+}
+
+impl ConnPoolConfig {
+    /// The peer as an endpoint, or `None` with a warning if it is malformed.
+    pub fn endpoint(&self) -> Option<IpEndpoint> {
+        match self.peer.parse::<Ipv4Address>() {
+            Ok(addr) if self.port != 0 && self.size > 0 => {
+                Some(IpEndpoint::new(IpAddress::Ipv4(addr), self.port))
+            }
+            _ => {
+                warn!("Ignoring invalid connection pool {}:{}", self.peer, self.port);
+                None
+            }
+        }
+    }
+}
+
+fn bool_or_loopback<'de, D>(deserializer: D) -> Result<LoopbackConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Toggle {
+        Enabled(bool),
+        Config(LoopbackConfig),
+    }
+
+    Ok(match Toggle::deserialize(deserializer)? {
+        Toggle::Enabled(enabled) => LoopbackConfig { enabled, ..LoopbackConfig::default() },
+        Toggle::Config(config) => config,
+    })
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => alloc::vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+pub fn default_mtu() -> usize {
+    crate::device::DEFAULT_MTU
+}
+
+/// Smallest MTU accepted from network.json, the IPv4 datagram size every host must take.
+/// Far below it smoltcp's unchecked header arithmetic underflows.
+pub const MIN_MTU: usize = 576;
+
+/// The largest MTU a device can take is one RX buffer, as a longer frame could never be
+/// received into it.
+pub const MAX_MTU: usize = crate::device::RX_BUFFER_SIZE;
+
+/// `mtu` within `[MIN_MTU, MAX_MTU]`, with a warning when it had to be moved.
+pub fn clamp_mtu(mtu: usize) -> usize {
+    let clamped = mtu.clamp(MIN_MTU, MAX_MTU);
+    if clamped != mtu {
+        warn!("Interface mtu {} is outside {}..={}, using {}", mtu, MIN_MTU, MAX_MTU, clamped);
+    }
+    clamped
+}
+
+fn device_mtu<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(clamp_mtu(usize::deserialize(deserializer)?))
+}
+
+fn loopback_mtu<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mtu = usize::deserialize(deserializer)?;
+    if mtu < MIN_MTU {
+        warn!("Loopback mtu {} is below {}, using {}", mtu, MIN_MTU, MIN_MTU);
+    }
+    Ok(mtu.max(MIN_MTU))
+}
+
+/// `tcp_mss_clamp` no lower than what leaves a `MIN_MTU` frame, as the MTU of every
+/// interface is lowered to match it.
+fn mss_clamp<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let min = MIN_MTU - super::TCP_FRAME_OVERHEAD;
+    Ok(Option::<usize>::deserialize(deserializer)?.map(|mss| {
+        if mss < min {
+            warn!("tcp_mss_clamp {} is below {}, using {}", mss, min, min);
+        }
+        mss.max(min)
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RouteConfig {
     pub dest: String,
     pub mask: u8,
     pub via: String,
+    /// Breaks ties between routes of equal prefix length; lower wins.
+    #[serde(default)]
+    pub metric: u32,
+}
+
+impl RouteConfig {
+    /// Parsed `(dest, mask, via)`, or `None` with a warning if the entry is malformed.
+    pub fn parse(&self) -> Option<(Ipv4Address, u8, Ipv4Address)> {
+        match (self.dest.parse::<Ipv4Address>(), self.via.parse::<Ipv4Address>()) {
+            (Ok(dest), Ok(via)) if self.mask <= 32 => Some((dest, self.mask, via)),
+            _ => {
+                warn!("Ignoring invalid route {}/{} via {}", self.dest, self.mask, self.via);
+                None
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkConfig {
     #[serde(default = "default_buffer_size")]
     pub buffer_size: usize,
+    // RX buffers and TX staging pages per device, each taking one SHM page. Applied when
+    // a device is probed; a device they do not fit in the SHM left gets the defaults
+    #[serde(default = "default_rx_buffers")]
+    pub rx_buffers: usize,
+    #[serde(default = "default_tx_buffers")]
+    pub tx_buffers: usize,
+    // Default TCP_USER_TIMEOUT for new TCP sockets; none keeps smoltcp's (no timeout)
+    #[serde(default)]
+    pub tcp_timeout_ms: Option<u64>,
+    // Congestion control for new TCP sockets, as named for TCP_CONGESTION; none keeps
+    // smoltcp's default
+    #[serde(default)]
+    pub tcp_congestion_control: Option<String>,
+    // Caps on open sockets, across all clients and per client; none means unbounded
+    #[serde(default)]
+    pub max_sockets: Option<usize>,
+    #[serde(default)]
+    pub max_sockets_per_client: Option<usize>,
+    // Upper bound on bytes returned by one RECV; defaults to the UTCB buffer size
+    #[serde(default)]
+    pub recv_size: Option<usize>,
+    // Most listening sockets one LISTEN may arm, whatever backlog it asks for
+    #[serde(default = "default_max_backlog")]
+    pub max_listen_backlog: usize,
+    // Receive buffer of new TCP sockets, which is the window they advertise; defaults
+    // to `default_rx_buf`
+    #[serde(default)]
+    pub tcp_rx_window: Option<usize>,
+    // Largest TCP segment to advertise and send, for links with a reduced effective MTU
+    // such as tunnels. smoltcp derives the MSS from the MTU, so this lowers the MTU of
+    // every interface to match, which applies to other traffic as well
+    #[serde(default, deserialize_with = "mss_clamp")]
+    pub tcp_mss_clamp: Option<usize>,
+    // Buffer sizes in bytes for new TCP and UDP sockets
+    #[serde(default = "default_socket_buf")]
+    pub default_rx_buf: usize,
+    #[serde(default = "default_socket_buf")]
+    pub default_tx_buf: usize,
+    // Sockets to reserve room for up front; the set still grows past it when needed
+    #[serde(default)]
+    pub socket_capacity: Option<usize>,
+    // How long a fragmented datagram may take to complete before it is dropped. The
+    // buffer size, the number of datagrams reassembled at once and the out-of-order
+    // holes tracked in each are smoltcp build features (see Cargo.toml), with no
+    // runtime setter, so only the timeout can be configured here
+    #[serde(default)]
+    pub reassembly_timeout_ms: Option<u64>,
+    // How long learned ARP entries are trusted; at most smoltcp's own 60 seconds. The
+    // number of entries is not configurable: every interface's cache holds 32, fixed by
+    // smoltcp's `iface-neighbor-cache-count-*` build feature (see Cargo.toml), and a
+    // full cache evicts to make room, so static entries can be pushed out and are
+    // re-announced from the mirror in `neighbor.rs`
+    #[serde(default)]
+    pub neighbor_ttl_ms: Option<u64>,
+    // Identity handed out to clients; the hostname defaults to one derived from the MAC
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+    // Name servers CONNECT_HOST resolves through, tried in order
+    #[serde(default = "default_dns_servers")]
+    pub dns_servers: Vec<String>,
+    // Fallback for interfaces not listed in `interfaces`
+    #[serde(default)]
+    pub default_interface: DefaultInterfaceConfig,
+    #[serde(default, deserialize_with = "bool_or_loopback")]
+    pub loopback: LoopbackConfig,
+    #[serde(default)]
+    pub conn_pools: Vec<ConnPoolConfig>,
     pub interfaces: Vec<NetworkInterfaceConfig>,
     pub routes: Vec<RouteConfig>,
 }
@@ -34,3 +332,67 @@ pub struct NetworkConfig {
 pub fn default_buffer_size() -> usize {
     1024 * 1024 // 1MB
 }
+
+pub fn default_rx_buffers() -> usize {
+    crate::device::DEFAULT_RX_BUFFERS
+}
+
+pub fn default_tx_buffers() -> usize {
+    crate::device::DEFAULT_TX_BUFFERS
+}
+
+/// The resolver of QEMU user networking, like the `default_interface` addresses.
+pub fn default_dns_servers() -> Vec<String> {
+    alloc::vec![String::from("10.0.2.3")]
+}
+
+pub fn default_max_backlog() -> usize {
+    8
+}
+
+pub fn default_socket_buf() -> usize {
+    4096
+}
+
+pub fn default_pool_size() -> usize {
+    4
+}
+
+pub fn default_pool_idle_ms() -> u64 {
+    60_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface_mtu(mtu: usize) -> usize {
+        let json = alloc::format!(r#"{{"name": "eth0", "ipv4": "10.0.0.2", "mtu": {}}}"#, mtu);
+        serde_json::from_str::<NetworkInterfaceConfig>(&json).unwrap().mtu
+    }
+
+    #[test]
+    fn mtu_is_clamped_to_what_the_device_can_carry() {
+        assert_eq!(interface_mtu(1500), 1500);
+        assert_eq!(interface_mtu(MIN_MTU), MIN_MTU);
+        assert_eq!(interface_mtu(MAX_MTU), MAX_MTU);
+        assert_eq!(interface_mtu(40), MIN_MTU);
+        assert_eq!(interface_mtu(0), MIN_MTU);
+        assert_eq!(interface_mtu(9000), MAX_MTU);
+        let json = r#"{"name": "eth0", "ipv4": "10.0.0.2"}"#;
+        let default = serde_json::from_str::<NetworkInterfaceConfig>(json).unwrap();
+        assert_eq!(default.mtu, crate::device::DEFAULT_MTU);
+    }
+
+    #[test]
+    fn mss_clamp_keeps_the_minimum_mtu() {
+        let parse = |json: &str| serde_json::from_str::<NetworkConfig>(json).unwrap();
+        let config = parse(r#"{"tcp_mss_clamp": 8, "interfaces": [], "routes": []}"#);
+        let mss = config.tcp_mss_clamp.unwrap();
+        assert_eq!(mss + super::super::TCP_FRAME_OVERHEAD, MIN_MTU);
+        let config = parse(r#"{"tcp_mss_clamp": 1200, "interfaces": [], "routes": []}"#);
+        assert_eq!(config.tcp_mss_clamp, Some(1200));
+        let config = parse(r#"{"loopback": {"mtu": 10}, "interfaces": [], "routes": []}"#);
+        assert_eq!(config.loopback.mtu, MIN_MTU);
+    }
+}
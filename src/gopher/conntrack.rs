@@ -0,0 +1,133 @@
+use super::GopherServer;
+use super::network::SocketKind;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::ipc::Badge;
+use serde::Serialize;
+use smoltcp::socket::tcp;
+use smoltcp::wire::IpEndpoint;
+
+/// Flows kept at most. Closed flows make room for new ones, oldest first; once the
+/// table is all live flows, further connections go untracked until one closes.
+pub const CONNTRACK_MAX_FLOWS: usize = 1024;
+// How long a flow stays listed after its socket went away
+const CLOSED_FLOW_TTL_NS: u64 = 30_000_000_000;
+
+/// A flow is identified by protocol and both endpoints, whichever socket carries it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FlowKey {
+    pub tcp: bool,
+    pub local: IpEndpoint,
+    pub remote: IpEndpoint,
+}
+
+pub struct Flow {
+    pub badge: Badge,
+    // Last TCP state seen; none for UDP
+    pub state: Option<tcp::State>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Monotonic ns when the flow showed up, and when its socket went away
+    pub opened_ns: u64,
+    pub closed_ns: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowReport {
+    pub proto: &'static str,
+    pub local: String,
+    pub remote: String,
+    pub badge: usize,
+    pub state: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub age_ms: u64,
+}
+
+impl<'a> GopherServer<'a> {
+    /// Bring the connection table in line with the sockets: every TCP connection and
+    /// connected UDP socket gets a flow, flows whose socket is gone are marked closed
+    /// and dropped a while later. A full table never evicts a live flow, so the
+    /// flows listed stay put instead of being replaced on every poll.
+    pub fn track_flows(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let mut live = BTreeSet::new();
+        for (badge, entry) in &self.socket_map {
+            let (Some(local), Some(remote)) = (entry.local, entry.remote) else {
+                continue;
+            };
+            let key = FlowKey { tcp: entry.kind == SocketKind::Tcp, local, remote };
+            if !self.conntrack.contains_key(&key)
+                && self.conntrack.len() >= CONNTRACK_MAX_FLOWS
+                && !evict_oldest_closed(&mut self.conntrack)
+            {
+                continue;
+            }
+            let state = match entry.kind {
+                SocketKind::Tcp => Some(self.sockets.get::<tcp::Socket>(entry.handle).state()),
+                SocketKind::Udp => None,
+            };
+            let flow = self.conntrack.entry(key).or_insert_with(|| Flow {
+                badge: *badge,
+                state,
+                bytes_sent: 0,
+                bytes_received: 0,
+                opened_ns: now,
+                closed_ns: None,
+            });
+            // The same endpoints reused by another socket are a new flow
+            if flow.badge != *badge || flow.closed_ns.is_some() {
+                flow.badge = *badge;
+                flow.opened_ns = now;
+                flow.closed_ns = None;
+            }
+            flow.state = state;
+            flow.bytes_sent = entry.stats.bytes_sent;
+            flow.bytes_received = entry.stats.bytes_received;
+            live.insert(key);
+        }
+        for (key, flow) in self.conntrack.iter_mut() {
+            if flow.closed_ns.is_none() && !live.contains(key) {
+                flow.closed_ns = Some(now);
+            }
+        }
+        self.conntrack
+            .retain(|_, flow| flow.closed_ns.is_none_or(|t| now - t < CLOSED_FLOW_TTL_NS));
+    }
+
+    /// The connection table for CONNTRACK.
+    pub fn flow_report(&self) -> Vec<FlowReport> {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        self.conntrack
+            .iter()
+            .map(|(key, flow)| FlowReport {
+                proto: if key.tcp { "tcp" } else { "udp" },
+                local: alloc::format!("{}", key.local),
+                remote: alloc::format!("{}", key.remote),
+                badge: flow.badge.bits(),
+                state: match (flow.closed_ns, flow.state) {
+                    (Some(_), _) => String::from("CLOSED"),
+                    (None, Some(state)) => alloc::format!("{}", state),
+                    (None, None) => String::from("OPEN"),
+                },
+                bytes_sent: flow.bytes_sent,
+                bytes_received: flow.bytes_received,
+                age_ms: now.saturating_sub(flow.opened_ns) / 1_000_000,
+            })
+            .collect()
+    }
+}
+
+// Drop the flow that closed first, if any, to make room for a new one.
+fn evict_oldest_closed(table: &mut BTreeMap<FlowKey, Flow>) -> bool {
+    let oldest = table
+        .iter()
+        .filter_map(|(key, flow)| flow.closed_ns.map(|t| (t, *key)))
+        .min()
+        .map(|(_, key)| key);
+    match oldest {
+        Some(key) => table.remove(&key).is_some(),
+        None => false,
+    }
+}
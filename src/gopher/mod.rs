@@ -1,37 +1,81 @@
-use crate::device::GlendaNetDevice;
+use crate::device::{GlendaNetDevice, LinkMode};
 use crate::layout::{RING_VA, SHM_VA};
+use crate::shm::ShmPool;
 use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use alloc::string::String;
 use alloc::vec::Vec;
+use blocking::{BlockedCalls, LingeringClose};
+use clock::Clock;
 use config::*;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use glenda::arch::mem::PGSIZE;
+use conntrack::{Flow, FlowKey};
+use core::sync::atomic::AtomicUsize;
 use glenda::cap::{CapPtr, Endpoint, Reply};
-use glenda::client::{DeviceClient, InitClient, ResourceClient, TimeClient};
+use glenda::client::{DeviceClient, InitClient, ResourceClient};
 use glenda::drivers::client::{RingParams, ShmParams};
 use glenda::drivers::interface::DriverClient;
 use glenda::error::Error;
 use glenda::interface::CSpaceService;
-use glenda::interface::TimeService;
 use glenda::interface::device::DeviceService;
 use glenda::io::uring::IoUringServer;
 use glenda::ipc::Badge;
 use glenda::protocol::device::LogicDeviceType;
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
-use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
-use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, Ipv4Address};
-use stack::{DeviceVariant, InterfaceContext};
+use neighbor::NeighborTable;
+use network::{SocketEntry, SocketIds, SocketKind};
+use ping::PendingPing;
+use pool::ConnPool;
+use reply::DeferredReplies;
+use resolve::HostConnect;
+use retransmit::SegmentLog;
+use smoltcp::iface::{Config, Interface, Route, SocketHandle, SocketSet};
+use smoltcp::phy::Device;
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address};
+use stack::{DeviceVariant, InterfaceContext, InterfaceStatus, NeighborReport};
+use stats::{InterfaceStatsReport, PollStats, ReassemblyStats, SocketStatsReport, StatsReport};
+use uring::{PendingOp, Region, RingMapping, RingVaPool, UringWindow};
 
+pub mod blocking;
+pub mod clock;
 pub mod config;
+pub mod conntrack;
+pub mod neighbor;
 pub mod network;
+pub mod notify;
+pub mod ops;
+pub mod ping;
+pub mod pool;
+pub mod reply;
+pub mod resolve;
+pub mod retransmit;
+#[cfg(debug_assertions)]
+pub mod selftest;
 pub mod server;
+pub mod shutdown;
+pub mod sockaddr;
 pub mod stack;
+pub mod stats;
+pub mod uring;
+
+/// Matches the `reassembly-buffer-size-*` feature smoltcp is built with.
+pub const REASSEMBLY_BUFFER_SIZE: usize = 16384;
+
+/// Probes of one device before it is given up on, and the delay before the first retry,
+/// doubled for each one after.
+const MAX_PROBE_ATTEMPTS: u32 = 5;
+const PROBE_BACKOFF_MS: u64 = 100;
+
+/// Polls in a row that only drop frames before the watchdog gives up on an interface.
+const WEDGED_POLL_LIMIT: u32 = 64;
 
 pub struct GopherIpc {
     pub endpoint: Endpoint,
     pub reply: Reply,
     pub recv: CapPtr,
     pub running: bool,
+    // Set while `shutdown_gracefully` waits for connections to close; client calls are
+    // turned away until the run loop stops
+    pub draining: bool,
 }
 
 pub struct GopherServer<'a> {
@@ -40,21 +84,73 @@ pub struct GopherServer<'a> {
     pub vspace: &'a mut VSpaceManager,
     pub device_client: &'a mut DeviceClient,
     pub init_client: &'a mut InitClient,
-    pub time_client: &'a mut TimeClient,
+    pub time_client: &'a mut dyn Clock,
     pub ipc: GopherIpc,
 
     pub interfaces: Vec<InterfaceContext>,
+    // One set polled by every interface; `poll_order` covers why receiving is safe and
+    // which interface ends up sending a socket's data
     pub sockets: SocketSet<'a>,
-    pub socket_map: BTreeMap<Badge, SocketHandle>,
+    pub socket_map: BTreeMap<Badge, SocketEntry>,
+    pub handle_map: BTreeMap<SocketHandle, Badge>,
+    // Sockets by the local port they are bound to, for `port_in_use`: registered ones by
+    // badge, and TCP connections the client closed but smoltcp is still finishing by
+    // handle. Checked against the socket on lookup, so a stale badge does no harm
+    pub bound_ports: BTreeMap<u16, BTreeSet<Badge>>,
+    pub closing_ports: BTreeMap<u16, BTreeSet<SocketHandle>>,
+    // Slot and generation of every socket id, independent of the smoltcp handle; the
+    // generation is part of the `socket_map` key, so a closed socket's badge fails with
+    // BadHandle even once its slot is reused
+    pub socket_ids: SocketIds,
+    // Client ids HELLO hands out, see `network::SOCKET_ID_BITS`
+    pub next_client_id: usize,
+    pub unreachable: BTreeSet<Badge>,
+    // Owner and badge of TCP sockets reaped on timeout, reported once to the owner, with
+    // when (mono ns) so ones the owner never asks about again age out
+    pub timed_out: BTreeMap<(Badge, Badge), u64>,
+    // Owner and badge of TCP sockets reaped once fully closed, with the error the owner's
+    // next call about them gets
+    pub reaped: BTreeMap<(Badge, Badge), Error>,
     pub uring_servers: BTreeMap<Badge, IoUringServer>,
+    pub uring_windows: BTreeMap<Badge, UringWindow>,
+    // Frames SETUP_IOURING mapped for a socket's ring, unmapped when the socket goes
+    pub ring_maps: BTreeMap<Badge, RingMapping>,
+    // Address space for every frame mapped for a socket, region or device ring
+    pub ring_va: RingVaPool,
+    // Receive buffers SETUP_RECV_SHM mapped, with the size the client gave
+    pub recv_shm: BTreeMap<Badge, (RingMapping, usize)>,
+    // Shared regions registered by each client, indexed by region id; unregistered ones
+    // leave a hole so later ids keep their meaning
+    pub regions: BTreeMap<Badge, Vec<Option<Region>>>,
+    pub pending_uring: BTreeMap<Badge, Vec<PendingOp>>,
+    pub next_wakeup: Option<u64>, // Earliest armed timer, in monotonic ns
+    // Set while poll() runs, so FLUSH never re-enters it
+    pub polling: bool,
+    pub deferred: DeferredReplies,
+    pub blocked: BTreeMap<Badge, BlockedCalls>,
+    pub lingering: Vec<LingeringClose>,
+    pub pending_pings: BTreeMap<Badge, PendingPing>,
+    pub next_ping_ident: u16,
+    pub host_connects: BTreeMap<Badge, HostConnect>,
+    // Per-flow view of the TCP connections and connected UDP sockets, for CONNTRACK
+    pub conntrack: BTreeMap<FlowKey, Flow>,
+    // Retransmits and round trips of TCP connections, seen from the segments polled
+    pub segment_log: SegmentLog,
+    // Pre-established connections per configured peer, for POOL_CONNECT
+    pub pools: BTreeMap<IpEndpoint, ConnPool>,
+    // Resolver socket shared by all CONNECT_HOST queries, created on first use
+    pub dns_handle: Option<SocketHandle>,
 
-    pub next_ring_vaddr: AtomicUsize,
     pub next_shm_vaddr: AtomicUsize,
+    pub next_port: u16,
 
     pub pending_devices: VecDeque<String>,
     pub probed_hardware: BTreeSet<usize>,
+    // Failed probes per device name: attempts so far and when the next is due (mono ns)
+    pub probe_retries: BTreeMap<String, (u32, u64)>,
 
     pub shm_frame: Option<(glenda::cap::Page, usize, usize, usize)>, // Frame, vaddr, size, paddr
+    pub shm_pool: ShmPool,
     pub config: Option<NetworkConfig>,
 }
 
@@ -65,7 +161,7 @@ impl<'a> GopherServer<'a> {
         vspace: &'a mut VSpaceManager,
         device_client: &'a mut DeviceClient,
         init_client: &'a mut InitClient,
-        time_client: &'a mut TimeClient,
+        time_client: &'a mut dyn Clock,
     ) -> Self {
         Self {
             res_client,
@@ -79,31 +175,331 @@ impl<'a> GopherServer<'a> {
                 reply: Reply::from(CapPtr::null()),
                 recv: CapPtr::null(),
                 running: false,
+                draining: false,
             },
             interfaces: Vec::new(),
             sockets: SocketSet::new(Vec::new()),
             socket_map: BTreeMap::new(),
+            handle_map: BTreeMap::new(),
+            bound_ports: BTreeMap::new(),
+            closing_ports: BTreeMap::new(),
+            socket_ids: SocketIds::new(),
+            next_client_id: 1, // Client 0 is the raw endpoint, which names no client
+            unreachable: BTreeSet::new(),
+            timed_out: BTreeMap::new(),
+            reaped: BTreeMap::new(),
             uring_servers: BTreeMap::new(),
-            next_ring_vaddr: AtomicUsize::new(RING_VA),
+            uring_windows: BTreeMap::new(),
+            ring_maps: BTreeMap::new(),
+            ring_va: RingVaPool::new(RING_VA),
+            recv_shm: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            pending_uring: BTreeMap::new(),
+            next_wakeup: None,
+            polling: false,
+            deferred: DeferredReplies::new(),
+            blocked: BTreeMap::new(),
+            lingering: Vec::new(),
+            pending_pings: BTreeMap::new(),
+            next_ping_ident: 1,
+            host_connects: BTreeMap::new(),
+            conntrack: BTreeMap::new(),
+            segment_log: SegmentLog::default(),
+            pools: BTreeMap::new(),
+            dns_handle: None,
             next_shm_vaddr: AtomicUsize::new(SHM_VA),
+            next_port: network::EPHEMERAL_PORT_START,
             pending_devices: VecDeque::new(),
             probed_hardware: BTreeSet::new(),
+            probe_retries: BTreeMap::new(),
             shm_frame: None,
+            shm_pool: ShmPool::new(0),
             config: None,
         }
     }
 
+    /// Loopback runs on the IP medium, so local flows skip Ethernet framing and ARP.
+    /// smoltcp's `Loopback` queue is unbounded; `poll` drains it in a single pass so a
+    /// local exchange does not have to wait for further wakeups.
     pub fn setup_loopback(&mut self) {
-        let mut loopback_device =
-            DeviceVariant::Loopback(smoltcp::phy::Loopback::new(smoltcp::phy::Medium::Ethernet));
-        let loopback_config =
-            Config::new(HardwareAddress::Ethernet(EthernetAddress([0, 0, 0, 0, 0, 0])));
+        let builtin = LoopbackConfig::default();
+        let lo = self.config.as_ref().map_or(&builtin, |c| &c.loopback);
+        let (addr, mask) = lo.address();
+        let mtu = lo.mtu;
         let time = self.get_time();
-        let mut loopback_iface = Interface::new(loopback_config, &mut loopback_device, time);
-        loopback_iface.update_ip_addrs(|addrs| {
-            addrs.push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)).unwrap();
-        });
-        self.interfaces.push(InterfaceContext { device: loopback_device, iface: loopback_iface });
+        self.interfaces.push(InterfaceContext::loopback(addr, mask, mtu, time));
+    }
+
+    pub fn interface_up(&self, name: &str) -> bool {
+        self.interfaces.iter().any(|ctx| ctx.name == name && ctx.up)
+    }
+
+    /// Re-query carrier for every interface, cutting off sockets on links that went down.
+    pub fn refresh_link_state(&mut self) {
+        for idx in 0..self.interfaces.len() {
+            let ctx = &mut self.interfaces[idx];
+            if ctx.disabled {
+                continue;
+            }
+            let up = ctx.device.link_up();
+            if up == ctx.up {
+                continue;
+            }
+            ctx.up = up;
+            ctx.dirty = up;
+            ctx.link = if up { ctx.device.link_mode() } else { LinkMode::default() };
+            if up {
+                log!("Interface {} link is up", ctx.name);
+            } else {
+                warn!("Interface {} link is down", ctx.name);
+                self.mark_unreachable(idx);
+            }
+        }
+    }
+
+    /// Take down interfaces whose last `WEDGED_POLL_LIMIT` polls only dropped frames, so
+    /// a wedged device is not spun on at the expense of the others. They stay down
+    /// until the device is removed and probed again.
+    pub fn disable_wedged_interfaces(&mut self) {
+        for idx in 0..self.interfaces.len() {
+            let ctx = &mut self.interfaces[idx];
+            if ctx.disabled || ctx.failed_polls < WEDGED_POLL_LIMIT {
+                continue;
+            }
+            error!(
+                "Interface {} failed {} polls in a row, disabling it",
+                ctx.name, ctx.failed_polls
+            );
+            ctx.disabled = true;
+            ctx.up = false;
+            ctx.dirty = false;
+            self.mark_unreachable(idx);
+        }
+    }
+
+    /// Cut off sockets bound to a specific address that `idx` no longer has.
+    pub fn mark_orphaned(&mut self, idx: usize) {
+        let iface = &self.interfaces[idx].iface;
+        for (badge, entry) in &self.socket_map {
+            let Some(local) = entry.local else {
+                continue;
+            };
+            if entry.iface != Some(idx)
+                || local.addr.is_unspecified()
+                || iface.has_ip_addr(local.addr)
+            {
+                continue;
+            }
+            if entry.kind == SocketKind::Tcp {
+                self.sockets.get_mut::<tcp::Socket>(entry.handle).abort();
+            }
+            self.unreachable.insert(*badge);
+        }
+    }
+
+    fn mark_unreachable(&mut self, idx: usize) {
+        let iface = &self.interfaces[idx].iface;
+        for (badge, entry) in &self.socket_map {
+            match entry.kind {
+                SocketKind::Tcp => {
+                    let socket = self.sockets.get_mut::<tcp::Socket>(entry.handle);
+                    if let Some(local) = socket.local_endpoint() {
+                        if iface.has_ip_addr(local.addr) {
+                            socket.abort();
+                            self.unreachable.insert(*badge);
+                        }
+                    }
+                }
+                SocketKind::Udp => {
+                    let socket = self.sockets.get::<udp::Socket>(entry.handle);
+                    if socket.endpoint().addr.is_some_and(|addr| iface.has_ip_addr(addr)) {
+                        self.unreachable.insert(*badge);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> StatsReport {
+        let interfaces = self
+            .interfaces
+            .iter()
+            .map(|ctx| InterfaceStatsReport {
+                name: ctx.name.clone(),
+                stats: ctx.device.stats(),
+                poll: ctx.poll_stats,
+                reassembly: ReassemblyStats {
+                    completed: ctx.fragments.completed,
+                    timed_out: ctx.fragments.timed_out,
+                    oversize: ctx.fragments.oversize,
+                    timeout_ms: ctx.iface.reassembly_timeout().total_millis(),
+                    buffer_size: REASSEMBLY_BUFFER_SIZE,
+                },
+                disabled: ctx.disabled,
+            })
+            .collect();
+        let sockets = self
+            .socket_map
+            .iter()
+            .map(|(badge, entry)| {
+                let (send_queue, recv_queue, tcp) = match entry.kind {
+                    SocketKind::Tcp => {
+                        let socket = self.sockets.get::<tcp::Socket>(entry.handle);
+                        (socket.send_queue(), socket.recv_queue(), Some(self.sender_stats(socket)))
+                    }
+                    SocketKind::Udp => {
+                        let socket = self.sockets.get::<udp::Socket>(entry.handle);
+                        (socket.send_queue(), socket.recv_queue(), None)
+                    }
+                };
+                SocketStatsReport {
+                    badge: badge.bits(),
+                    stats: entry.stats,
+                    send_queue,
+                    recv_queue,
+                    tcp,
+                }
+            })
+            .collect();
+        StatsReport { interfaces, sockets }
+    }
+
+    pub fn neighbors(&mut self) -> Vec<NeighborReport> {
+        let now = self.get_time();
+        let mut report = Vec::new();
+        for ctx in &mut self.interfaces {
+            let DeviceVariant::Net(dev) = &mut ctx.device else {
+                continue;
+            };
+            for (ip, neighbor) in dev.neighbors.entries(now) {
+                report.push(NeighborReport {
+                    iface: ctx.name.clone(),
+                    ip: alloc::format!("{}", ip),
+                    mac: neighbor.mac.0,
+                    expires_in_ms: (!neighbor.is_static())
+                        .then(|| (neighbor.expires_at - now).total_millis()),
+                    permanent: neighbor.is_static(),
+                });
+            }
+        }
+        report
+    }
+
+    /// Pin `ip` to `mac` on the interface whose subnet contains it.
+    pub fn add_static_neighbor(
+        &mut self,
+        ip: Ipv4Address,
+        mac: EthernetAddress,
+    ) -> Result<(), Error> {
+        let now = self.get_time();
+        for ctx in &mut self.interfaces {
+            let local_ip = ctx.iface.ip_addrs().iter().find_map(|cidr| match cidr {
+                IpCidr::Ipv4(v4) if v4.contains_addr(&ip) => Some(v4.address()),
+                _ => None,
+            });
+            if let (DeviceVariant::Net(dev), Some(local_ip)) = (&mut ctx.device, local_ip) {
+                log!("Static neighbor {} -> {} on {}", ip, mac, ctx.name);
+                dev.neighbors.add_static(ip, mac, local_ip, now);
+                ctx.dirty = true;
+                return Ok(());
+            }
+        }
+        Err(Error::NetworkUnreachable)
+    }
+
+    /// Forget learned neighbors on interface `name`, or on all of them when `name` is
+    /// empty. Static entries are kept and re-announced.
+    pub fn flush_neighbors(&mut self, name: &str) -> Result<(), Error> {
+        let now = self.get_time();
+        let mut found = false;
+        for ctx in self.interfaces.iter_mut().filter(|ctx| name.is_empty() || ctx.name == name) {
+            log!("Flushing neighbor cache of {}", ctx.name);
+            flush_neighbor_cache(ctx, now);
+            found = true;
+        }
+        if found { Ok(()) } else { Err(Error::NotFound) }
+    }
+
+    /// Current MAC of interface `name`.
+    pub fn interface_mac(&self, name: &str) -> Result<EthernetAddress, Error> {
+        let ctx = self.interfaces.iter().find(|ctx| ctx.name == name).ok_or(Error::NotFound)?;
+        Ok(ctx.device.mac_address())
+    }
+
+    /// Give interface `name` a new unicast MAC. The driver has to accept it first, so the
+    /// NIC's RX filter follows; learned neighbors are then dropped and every address of
+    /// the interface announced with a gratuitous ARP. Refused while sockets are pinned
+    /// to the interface, since peers that miss the announcement keep sending to the old
+    /// address until their ARP entries age out.
+    pub fn set_interface_mac(&mut self, name: &str, mac: EthernetAddress) -> Result<(), Error> {
+        if !mac.is_unicast() || mac == EthernetAddress([0; 6]) {
+            return Err(Error::InvalidArgs);
+        }
+        let idx = self.interfaces.iter().position(|ctx| ctx.name == name).ok_or(Error::NotFound)?;
+        if self.socket_map.values().any(|entry| entry.iface == Some(idx)) {
+            return Err(Error::AddrInUse);
+        }
+        let now = self.get_time();
+        let ctx = &mut self.interfaces[idx];
+        let DeviceVariant::Net(device) = &mut ctx.device else {
+            return Err(Error::NotSupported);
+        };
+        log!("Changing MAC of {} from {} to {}", name, device.ethernet_addr(), mac);
+        device.set_mac(mac)?;
+        for cidr in ctx.iface.ip_addrs() {
+            let IpAddress::Ipv4(ip) = cidr.address();
+            device.announce(ip);
+        }
+        ctx.iface.set_hardware_addr(HardwareAddress::Ethernet(mac));
+        flush_neighbor_cache(ctx, now);
+        Ok(())
+    }
+
+    /// Configured hostname, or `glenda-` plus the low half of the first NIC's MAC.
+    pub fn hostname(&self) -> String {
+        if let Some(hostname) = self.config.as_ref().and_then(|c| c.hostname.clone()) {
+            return hostname;
+        }
+        match self.interfaces.iter().find(|ctx| !ctx.device.is_loopback()) {
+            Some(ctx) => {
+                let mac = ctx.device.mac_address().0;
+                alloc::format!("glenda-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+            }
+            None => String::from("glenda"),
+        }
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        self.config.as_ref().and_then(|c| c.domain.as_deref())
+    }
+
+    pub fn interface_status(&self) -> Vec<InterfaceStatus> {
+        self.interfaces
+            .iter()
+            .map(|ctx| {
+                let mut flags = 0;
+                if ctx.up {
+                    flags |= ops::IFF_UP;
+                }
+                if ctx.device.is_loopback() {
+                    flags |= ops::IFF_LOOPBACK;
+                }
+                InterfaceStatus {
+                    name: ctx.name.clone(),
+                    mac: ctx.device.mac_address().0,
+                    ips: ctx
+                        .iface
+                        .ip_addrs()
+                        .iter()
+                        .map(|cidr| alloc::format!("{}", cidr))
+                        .collect(),
+                    mtu: ctx.device.capabilities().max_transmission_unit,
+                    flags,
+                    speed_mbps: ctx.link.speed_mbps,
+                    duplex: ctx.link.duplex.as_str(),
+                }
+            })
+            .collect()
     }
 
     pub fn get_time(&mut self) -> smoltcp::time::Instant {
@@ -119,6 +515,16 @@ impl<'a> GopherServer<'a> {
             dev_type: Some(LogicDeviceType::Net),
         };
         let names = self.device_client.query(Badge::null(), query)?;
+        // Interfaces whose device is no longer listed have been unplugged
+        let gone: Vec<usize> = self
+            .interfaces
+            .iter()
+            .filter(|ctx| !names.contains(&ctx.name))
+            .filter_map(|ctx| ctx.hw_id)
+            .collect();
+        for hw_id in gone {
+            self.remove_interface(hw_id);
+        }
         for name in names {
             if !self.pending_devices.contains(&name) {
                 self.pending_devices.push_back(name);
@@ -127,27 +533,104 @@ impl<'a> GopherServer<'a> {
         Ok(())
     }
 
+    /// Tear down the interface probed from `hw_id`: its sockets become unreachable,
+    /// its SHM window goes back to the pool and the hardware may be probed again.
+    pub fn remove_interface(&mut self, hw_id: usize) {
+        let Some(idx) = self.interfaces.iter().position(|ctx| ctx.hw_id == Some(hw_id)) else {
+            return;
+        };
+        warn!("Removing interface {} (hw_id={:x})", self.interfaces[idx].name, hw_id);
+        self.mark_unreachable(idx);
+        let mut ctx = self.interfaces.remove(idx);
+        if let DeviceVariant::Net(device) = &mut ctx.device {
+            if let Err(e) = device.disconnect() {
+                warn!("Failed to disconnect {}: {:?}", ctx.name, e);
+            }
+            // The device is gone either way, so nothing DMAs into its pages anymore
+            device.release_shm(&mut self.shm_pool);
+            let (va, range) = device.ring_va;
+            self.ring_va.free(va, range);
+        }
+        // Interface indices after the removed one shift down by one
+        for entry in self.socket_map.values_mut() {
+            entry.iface = match entry.iface {
+                Some(i) if i == idx => None,
+                Some(i) if i > idx => Some(i - 1),
+                other => other,
+            };
+            entry.groups.retain(|(i, _)| *i != idx);
+            for (i, _) in &mut entry.groups {
+                if *i > idx {
+                    *i -= 1;
+                }
+            }
+        }
+        self.probed_hardware.remove(&hw_id);
+    }
+
+    /// Probe every queued device that is due. A failed probe goes back in the queue with
+    /// exponential backoff until `MAX_PROBE_ATTEMPTS` is reached; one bad device never
+    /// keeps the others from coming up.
     pub fn process_pending_probes(&mut self) -> Result<(), Error> {
-        while let Some(name) = self.pending_devices.pop_front() {
-            let (hw_id, desc) = self.device_client.get_logic_desc(Badge::null(), &name)?;
-            if let LogicDeviceType::Net = desc.dev_type {
-                if !self.probed_hardware.contains(&hw_id) {
-                    log!("Found new network device to probe: {} (hw_id={:x})", name, hw_id);
-                    let hw_slot = self.cspace.alloc(self.res_client)?;
-                    let hw_ep = self.device_client.alloc_logic(
-                        Badge::null(),
-                        LogicDeviceType::Net,
-                        &name,
-                        hw_slot,
-                    )?;
-                    self.probe(&name, hw_id, desc, hw_ep)?;
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        for _ in 0..self.pending_devices.len() {
+            let Some(name) = self.pending_devices.pop_front() else {
+                break;
+            };
+            if self.probe_retries.get(&name).is_some_and(|(_, due)| *due > now) {
+                self.pending_devices.push_back(name);
+                continue;
+            }
+            match self.probe_pending(&name) {
+                Ok(()) => {
+                    self.probe_retries.remove(&name);
                 }
+                Err(e) => self.retry_probe(name, e, now),
             }
         }
         Ok(())
     }
 
+    fn probe_pending(&mut self, name: &str) -> Result<(), Error> {
+        let (hw_id, desc) = self.device_client.get_logic_desc(Badge::null(), name)?;
+        if !matches!(desc.dev_type, LogicDeviceType::Net) {
+            log!("Skipping {}: not a network device", name);
+            return Ok(());
+        }
+        if self.probed_hardware.contains(&hw_id) {
+            return Ok(());
+        }
+        log!("Found new network device to probe: {} (hw_id={:x})", name, hw_id);
+        let hw_slot = self.cspace.alloc(self.res_client)?;
+        let res = self
+            .device_client
+            .alloc_logic(Badge::null(), LogicDeviceType::Net, name, hw_slot)
+            .and_then(|hw_ep| self.probe(name, hw_id, desc, hw_ep));
+        if res.is_err() {
+            // Dropping the endpoint hands the logic device back, so every retry starts
+            // from a fresh slot and allocation instead of piling them up
+            self.release_slot(hw_slot);
+        }
+        res
+    }
+
+    fn retry_probe(&mut self, name: String, err: Error, now: u64) {
+        let attempts = self.probe_retries.get(&name).map_or(0, |(attempts, _)| *attempts) + 1;
+        if attempts >= MAX_PROBE_ATTEMPTS {
+            error!("Giving up on {} after {} failed probes: {:?}", name, attempts, err);
+            self.probe_retries.remove(&name);
+            return;
+        }
+        let delay_ns = (PROBE_BACKOFF_MS << (attempts - 1)) * 1_000_000;
+        warn!("Failed to probe {} ({:?}), retrying in {}ms", name, err, delay_ns / 1_000_000);
+        let due = now.saturating_add(delay_ns);
+        self.probe_retries.insert(name.clone(), (attempts, due));
+        self.pending_devices.push_back(name);
+        self.arm_timer(due);
+    }
+
     pub fn handle_notify_sync(&mut self) -> Result<(), Error> {
+        self.refresh_link_state();
         self.sync_devices()
     }
 
@@ -160,91 +643,247 @@ impl<'a> GopherServer<'a> {
     ) -> Result<(), Error> {
         log!("Probing network device {} (hw_id={:x})", desc.name, hw_id);
 
-        let ring_va = self.next_ring_vaddr.fetch_add(PGSIZE, Ordering::SeqCst);
-        let (shm_frame, shm_va, shm_size, shm_paddr) =
-            self.shm_frame.as_ref().ok_or_else(|| {
-                error!("Global SHM not initialized");
-                Error::NotInitialized
-            })?;
+        let (shm_frame, shm_va, _, shm_paddr) = *self.shm_frame.as_ref().ok_or_else(|| {
+            error!("Global SHM not initialized");
+            Error::NotInitialized
+        })?;
+
+        // Each device gets a disjoint window of the global SHM so DMA never overlaps
+        let (rx_buffers, tx_buffers) = ring_buffers(name, self.config.as_ref());
+        let window = self.shm_pool.reserve_window(rx_buffers, tx_buffers).ok_or_else(|| {
+            error!("No room in SHM pool for {}", name);
+            Error::OutOfMemory
+        })?;
+        if window.rx_buffers != rx_buffers || window.tx_buffers != tx_buffers {
+            warn!(
+                "{} RX / {} TX buffers for {} do not fit in the SHM pool, using {} / {}",
+                rx_buffers, tx_buffers, name, window.rx_buffers, window.tx_buffers
+            );
+        }
+        let (rx_buffers, tx_buffers) = (window.rx_buffers, window.tx_buffers);
+        // Room in the rings for every RX buffer in flight and every TX submission
+        let ring_entries = (rx_buffers + tx_buffers).next_power_of_two();
+        let shm_offset = window.offset();
+        // Deep rings outgrow a page, so the ring memory is sized from the entries
+        let ring_size = crate::device::ring_size(ring_entries);
+        let (ring_va, ring_range) = self.alloc_ring_va(ring_size);
 
         let net_device = GlendaNetDevice::new(
             hardware_ep,
             &desc.name,
             self.res_client,
             RingParams {
-                sq_entries: 4,
-                cq_entries: 4,
+                sq_entries: ring_entries,
+                cq_entries: ring_entries,
                 notify_ep: self.ipc.endpoint,
                 recv_slot: self.ipc.recv,
                 vaddr: ring_va,
-                size: PGSIZE,
+                size: ring_size,
             },
             ShmParams {
-                frame: *shm_frame,
-                vaddr: *shm_va,
-                paddr: *shm_paddr as usize,
-                size: *shm_size,
+                frame: shm_frame,
+                vaddr: shm_va + shm_offset,
+                paddr: shm_paddr + shm_offset,
+                size: window.size(),
                 recv_slot: CapPtr::null(),
             },
+            window.base,
+            rx_buffers,
+            tx_buffers,
         );
 
         let mut net_device = net_device;
-        net_device.connect(self.vspace, self.cspace)?;
+        net_device.ring_va = (ring_va, ring_range);
+        if let Err(e) = net_device.connect(self.vspace, self.cspace) {
+            net_device.release_shm(&mut self.shm_pool);
+            self.ring_va.free(ring_va, ring_range);
+            return Err(e);
+        }
+        net_device.probe_checksum_offload();
+        configure_neighbor_ttl(&mut net_device.neighbors, self.config.as_ref());
+        // The interface picks up its MTU from the device capabilities at construction
+        net_device.mtu = interface_mtu(name, self.config.as_ref());
         let mut device = DeviceVariant::Net(net_device);
+        let up = device.link_up();
+        if !up {
+            warn!("Device {} has no link yet, interface stays down", name);
+        }
+        let link = if up { device.link_mode() } else { LinkMode::default() };
         let mac = device.mac_address();
         let config = Config::new(HardwareAddress::Ethernet(mac));
         let time = self.get_time();
 
         let mut iface = Interface::new(config, &mut device, time);
         log!("Probed device {} with MAC {}", name, mac);
-        // Apply configuration from network.json if available
-        let mut configured = false;
-        if let Some(config) = &self.config {
-            if let Some(iface_config) = config.interfaces.iter().find(|i| i.name == name) {
-                if let Ok(addr) = iface_config.ipv4.parse::<Ipv4Address>() {
-                    iface.update_ip_addrs(|addrs| {
-                        log!(
-                            "Configuring interface {} with IP {}/{}",
-                            name,
-                            addr,
-                            iface_config.mask
-                        );
-                        addrs.push(IpCidr::new(IpAddress::Ipv4(addr), iface_config.mask)).unwrap();
-                    });
-                    if let Some(gw) = &iface_config.gateway {
-                        if let Ok(gw_addr) = gw.parse::<Ipv4Address>() {
-                            log!("Setting default gateway for {} to {}", name, gw_addr);
-                            iface.routes_mut().add_default_ipv4_route(gw_addr).unwrap();
-                        }
+        configure_reassembly(&mut iface, self.config.as_ref());
+        let gateway = configure_interface(&mut iface, name, self.config.as_ref());
+
+        self.interfaces.push(InterfaceContext {
+            name: String::from(name),
+            hw_id: Some(hw_id),
+            device,
+            iface,
+            up,
+            link,
+            dirty: true,
+            poll_stats: PollStats::default(),
+            failed_polls: 0,
+            disabled: false,
+            gateway,
+            fragments: Default::default(),
+        });
+        self.probed_hardware.insert(hw_id);
+
+        Ok(())
+    }
+}
+
+/// Ethernet, IPv4 and TCP headers without options, on top of the MSS
+pub const TCP_FRAME_OVERHEAD: usize = 14 + 20 + 20;
+
+/// Device MTU for interface `name`: the configured one, lowered far enough that the MSS
+/// smoltcp derives from it stays within `tcp_mss_clamp`.
+pub fn interface_mtu(name: &str, config: Option<&NetworkConfig>) -> usize {
+    let mtu = config
+        .and_then(|c| c.interfaces.iter().find(|i| i.name == name))
+        .map_or(crate::device::DEFAULT_MTU, |i| i.mtu);
+    match config.and_then(|c| c.tcp_mss_clamp) {
+        Some(mss) => mtu.min(mss + TCP_FRAME_OVERHEAD),
+        None => mtu,
+    }
+}
+
+/// RX and TX buffer counts for device `name`: its own entry in `interfaces`, then the
+/// global settings, then the built-in defaults.
+pub fn ring_buffers(name: &str, config: Option<&NetworkConfig>) -> (usize, usize) {
+    let Some(config) = config else {
+        return (crate::device::DEFAULT_RX_BUFFERS, crate::device::DEFAULT_TX_BUFFERS);
+    };
+    let iface = config.interfaces.iter().find(|i| i.name == name);
+    (
+        iface.and_then(|i| i.rx_buffers).unwrap_or(config.rx_buffers),
+        iface.and_then(|i| i.tx_buffers).unwrap_or(config.tx_buffers),
+    )
+}
+
+/// Apply the configured reassembly timeout, or smoltcp's default when there is none.
+pub fn configure_reassembly(iface: &mut Interface, config: Option<&NetworkConfig>) {
+    if let Some(ms) = config.and_then(|c| c.reassembly_timeout_ms) {
+        iface.set_reassembly_timeout(smoltcp::time::Duration::from_millis(ms));
+    }
+}
+
+/// Apply the configured neighbor lifetime, or smoltcp's default when there is none.
+pub fn configure_neighbor_ttl(neighbors: &mut NeighborTable, config: Option<&NetworkConfig>) {
+    let ttl = config.and_then(|c| c.neighbor_ttl_ms).map(smoltcp::time::Duration::from_millis);
+    neighbors.set_lifetime(ttl);
+}
+
+/// Drop everything smoltcp has cached for `ctx`, so the next packet to each neighbor
+/// goes out behind a fresh ARP request. smoltcp has no call for this, but any update of
+/// the address list flushes its neighbor cache, so an empty one does it.
+pub fn flush_neighbor_cache(ctx: &mut InterfaceContext, now: smoltcp::time::Instant) {
+    ctx.iface.update_ip_addrs(|_| {});
+    if let DeviceVariant::Net(dev) = &mut ctx.device {
+        dev.neighbors.flush(now);
+    }
+    ctx.dirty = true;
+}
+
+/// Install a default route, logging instead of failing when the table is full.
+fn add_default_route(iface: &mut Interface, name: &str, via: Ipv4Address) -> Option<Ipv4Address> {
+    if iface.routes_mut().add_default_ipv4_route(via).is_err() {
+        warn!("Routing table of {} is full, dropping default route via {}", name, via);
+        return None;
+    }
+    Some(via)
+}
+
+/// Assign addresses and routes from `config` to a freshly created or cleared interface,
+/// falling back to `default_interface` (the QEMU user-network defaults unless the config
+/// says otherwise) when it has no addresses configured. Returns the gateway of the
+/// default route it installed, if any.
+pub fn configure_interface(
+    iface: &mut Interface,
+    name: &str,
+    config: Option<&NetworkConfig>,
+) -> Option<Ipv4Address> {
+    let mut configured = false;
+    let mut gateway = None;
+    if let Some(config) = config {
+        if let Some(iface_config) = config.interfaces.iter().find(|i| i.name == name) {
+            let addresses = iface_config.addresses();
+            iface.update_ip_addrs(|addrs| {
+                for (addr, mask) in &addresses {
+                    log!("Configuring interface {} with IP {}/{}", name, addr, mask);
+                    if addrs.push(IpCidr::new(IpAddress::Ipv4(*addr), *mask)).is_err() {
+                        warn!("Too many addresses on {}, dropping {}", name, addr);
                     }
-                    configured = true;
                 }
-            }
-
-            // Apply global routes
-            for route in &config.routes {
-                if let (Ok(dest), Ok(via)) =
-                    (route.dest.parse::<Ipv4Address>(), route.via.parse::<Ipv4Address>())
-                {
-                    if dest.is_unspecified() && route.mask == 0 {
-                        log!("Adding default route via {}", via);
-                        iface.routes_mut().add_default_ipv4_route(via).unwrap();
+            });
+            if !addresses.is_empty() {
+                if let Some(gw) = &iface_config.gateway {
+                    match gw.parse::<Ipv4Address>() {
+                        Ok(gw_addr) => {
+                            log!("Setting default gateway for {} to {}", name, gw_addr);
+                            gateway = add_default_route(iface, name, gw_addr);
+                        }
+                        Err(_) => warn!("Ignoring invalid gateway {} for {}", gw, name),
                     }
                 }
+                configured = true;
             }
         }
 
-        if !configured {
-            // Default fallback
-            iface.update_ip_addrs(|addrs| {
-                addrs.push(IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24)).unwrap();
+        // Apply global routes
+        for route in &config.routes {
+            let Some((dest, mask, via)) = route.parse() else {
+                continue;
+            };
+            if dest.is_unspecified() && mask == 0 {
+                log!("Adding default route via {}", via);
+                gateway = add_default_route(iface, name, via);
+                continue;
+            }
+            // Only the interface that can reach the gateway carries the route
+            if !iface.ip_addrs().iter().any(|cidr| cidr.contains_addr(&IpAddress::Ipv4(via))) {
+                continue;
+            }
+            log!("Adding route {}/{} via {} on {}", dest, mask, via, name);
+            let added = iface.routes_mut().update(|routes| {
+                routes
+                    .push(Route {
+                        cidr: IpCidr::new(IpAddress::Ipv4(dest), mask),
+                        via_router: IpAddress::Ipv4(via),
+                        preferred_until: None,
+                        expires_at: None,
+                    })
+                    .is_ok()
             });
-            iface.routes_mut().add_default_ipv4_route(Ipv4Address::new(10, 0, 2, 2)).unwrap();
+            if !added {
+                warn!("Routing table of {} is full, dropping {}/{}", name, dest, mask);
+            }
         }
+    }
 
-        self.interfaces.push(InterfaceContext { device, iface });
-        self.probed_hardware.insert(hw_id);
-
-        Ok(())
+    if !configured {
+        let builtin = DefaultInterfaceConfig::default();
+        let fallback = config.map_or(&builtin, |c| &c.default_interface);
+        let addresses = fallback.addresses(name);
+        iface.update_ip_addrs(|addrs| {
+            for (addr, mask) in &addresses {
+                log!("Configuring interface {} with fallback IP {}/{}", name, addr, mask);
+                if addrs.push(IpCidr::new(IpAddress::Ipv4(*addr), *mask)).is_err() {
+                    warn!("Failed to assign fallback address {} to {}", addr, name);
+                }
+            }
+        });
+        if let Some(gw) = &fallback.gateway {
+            match gw.parse::<Ipv4Address>() {
+                Ok(gw_addr) => gateway = add_default_route(iface, name, gw_addr),
+                Err(_) => warn!("Ignoring invalid fallback gateway {} for {}", gw, name),
+            }
+        }
     }
+    gateway
 }
@@ -0,0 +1,164 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, Ipv4Address,
+};
+
+// smoltcp forgets dynamic neighbors after a minute
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+// Static entries are re-announced to smoltcp well before they would expire
+const STATIC_REFRESH: Duration = Duration::from_secs(30);
+
+pub struct Neighbor {
+    pub mac: EthernetAddress,
+    // Expiry for learned entries, next refresh for static ones
+    pub expires_at: Instant,
+    // Local address static entries are announced to; `None` for learned entries
+    pub local_ip: Option<Ipv4Address>,
+}
+
+impl Neighbor {
+    pub fn is_static(&self) -> bool {
+        self.local_ip.is_some()
+    }
+}
+
+/// Mirror of the smoltcp neighbor cache, which is not reachable through the
+/// `Interface` API. Learned entries come from snooping ARP on the RX path;
+/// static entries are fed to smoltcp as synthesized ARP replies.
+pub struct NeighborTable {
+    entries: BTreeMap<Ipv4Address, Neighbor>,
+    // How long learned entries live; never longer than smoltcp keeps them
+    lifetime: Duration,
+}
+
+impl Default for NeighborTable {
+    fn default() -> Self {
+        Self { entries: BTreeMap::new(), lifetime: ENTRY_LIFETIME }
+    }
+}
+
+impl NeighborTable {
+    /// Age learned entries out after `lifetime`, or smoltcp's minute when `None`.
+    /// Longer lifetimes are capped, since smoltcp would forget the entry anyway.
+    pub fn set_lifetime(&mut self, lifetime: Option<Duration>) {
+        self.lifetime = lifetime.map_or(ENTRY_LIFETIME, |l| l.min(ENTRY_LIFETIME));
+    }
+
+    /// Learn from an ARP frame seen on the wire, the same way smoltcp does.
+    pub fn snoop(&mut self, frame: &[u8], now: Instant) {
+        let Ok(eth) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        if eth.ethertype() != EthernetProtocol::Arp {
+            return;
+        }
+        let Ok(arp) = ArpPacket::new_checked(eth.payload()) else {
+            return;
+        };
+        if let Ok(ArpRepr::EthernetIpv4 { source_hardware_addr, source_protocol_addr, .. }) =
+            ArpRepr::parse(&arp)
+        {
+            if self.entries.get(&source_protocol_addr).is_some_and(Neighbor::is_static) {
+                return;
+            }
+            self.entries.insert(
+                source_protocol_addr,
+                Neighbor {
+                    mac: source_hardware_addr,
+                    expires_at: now + self.lifetime,
+                    local_ip: None,
+                },
+            );
+        }
+    }
+
+    pub fn add_static(
+        &mut self,
+        ip: Ipv4Address,
+        mac: EthernetAddress,
+        local_ip: Ipv4Address,
+        now: Instant,
+    ) {
+        // Due immediately so the next receive() announces it
+        self.entries.insert(ip, Neighbor { mac, expires_at: now, local_ip: Some(local_ip) });
+    }
+
+    /// Next synthesized ARP reply for a static entry that is due for a refresh.
+    pub fn next_announcement(
+        &mut self,
+        local_mac: EthernetAddress,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let (ip, neighbor) =
+            self.entries.iter_mut().find(|(_, n)| n.is_static() && n.expires_at <= now)?;
+        neighbor.expires_at = now + STATIC_REFRESH;
+        Some(arp_reply(neighbor.mac, *ip, local_mac, neighbor.local_ip?))
+    }
+
+    /// Live entries; learned ones past their lifetime are left for `expire` to drop.
+    pub fn entries(&self, now: Instant) -> impl Iterator<Item = (&Ipv4Address, &Neighbor)> {
+        self.entries.iter().filter(move |(_, n)| n.is_static() || n.expires_at > now)
+    }
+
+    /// Drop learned entries past their lifetime. Returns `true` if smoltcp still holds
+    /// one of them, i.e. the lifetime is shorter than its own and its cache needs a flush.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|_, n| n.is_static() || n.expires_at > now);
+        self.entries.len() < before && self.lifetime < ENTRY_LIFETIME
+    }
+
+    /// Forget every learned entry, after smoltcp's cache was flushed. Static entries
+    /// stay and are due for announcement again right away.
+    pub fn flush(&mut self, now: Instant) {
+        self.entries.retain(|_, n| n.is_static());
+        for neighbor in self.entries.values_mut() {
+            neighbor.expires_at = now;
+        }
+    }
+}
+
+/// Broadcast ARP request for our own `ip`, telling peers it now lives at `mac`.
+pub fn gratuitous_arp(mac: EthernetAddress, ip: Ipv4Address) -> Vec<u8> {
+    let arp = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr: mac,
+        source_protocol_addr: ip,
+        target_hardware_addr: EthernetAddress([0; 6]),
+        target_protocol_addr: ip,
+    };
+    let eth = EthernetRepr {
+        src_addr: mac,
+        dst_addr: EthernetAddress::BROADCAST,
+        ethertype: EthernetProtocol::Arp,
+    };
+    let mut buf = alloc::vec![0u8; eth.buffer_len() + arp.buffer_len()];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    eth.emit(&mut frame);
+    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    buf
+}
+
+fn arp_reply(
+    mac: EthernetAddress,
+    ip: Ipv4Address,
+    local_mac: EthernetAddress,
+    local_ip: Ipv4Address,
+) -> Vec<u8> {
+    let arp = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: mac,
+        source_protocol_addr: ip,
+        target_hardware_addr: local_mac,
+        target_protocol_addr: local_ip,
+    };
+    let eth = EthernetRepr { src_addr: mac, dst_addr: local_mac, ethertype: EthernetProtocol::Arp };
+    let mut buf = alloc::vec![0u8; eth.buffer_len() + arp.buffer_len()];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    eth.emit(&mut frame);
+    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    buf
+}
@@ -1,14 +1,264 @@
 use super::GopherServer;
-use glenda::cap::Page;
+use super::config::{default_max_backlog, default_socket_buf};
+use super::ops;
+use super::sockaddr;
+use super::stats::{SocketStateReport, SocketStats};
+use super::uring::{PendingKind, PendingOp, Region, RingMapping, UringWindow};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use glenda::cap::{Endpoint, Page};
 use glenda::error::Error;
-use glenda::interface::VSpaceService;
+use glenda::interface::{CSpaceService, VSpaceService};
 use glenda::interface::{NetworkService, SocketService};
 use glenda::io::uring::{IOURING_OP_READ, IOURING_OP_WRITE};
 use glenda::ipc::Badge;
 use glenda::protocol;
 use glenda::utils::align::align_up;
 use smoltcp::iface::SocketHandle;
-use smoltcp::socket::tcp;
+use smoltcp::phy::Device;
+use smoltcp::socket::{Socket, tcp, udp};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, Ipv4Address};
+
+pub const EPHEMERAL_PORT_START: u16 = 49152;
+// Segment size assumed for a socket whose interface is not known yet (RFC 879)
+const DEFAULT_MSS: usize = 536;
+// How long the badge of a timed-out socket keeps answering Timeout before it is forgotten
+const TIMED_OUT_RETENTION_NS: u64 = 60_000_000_000;
+
+/// Socket ownership. `NET_ENDPOINT` only answers HELLO, which mints a copy of Gopher's
+/// endpoint with a fresh client id in the bits at and above `SOCKET_ID_BITS`. A badged
+/// cap cannot be badged again, so the client cannot change that id; the bits below name
+/// one of its sockets and come from SOCKET_ENDPOINT, which mints the client's badge plus
+/// a socket id the client owns. Calls that are not about a socket, SOCKET included, go
+/// through the HELLO copy with those bits zero. Each socket remembers the client that
+/// created it (an accepted connection belongs to the listener's owner), and a call
+/// naming a socket owned by someone else fails with `Error::PermissionDenied`, so
+/// learning another client's socket id is not enough to use it.
+pub const SOCKET_ID_BITS: u32 = 32;
+
+/// Badge bit of the copy registered as `NET_ENDPOINT`, which only serves HELLO.
+pub const BOOTSTRAP_BADGE: usize = 1 << (usize::BITS - 2);
+
+// Client ids stay below the bootstrap and admin bits
+const MAX_CLIENT_ID: usize = 1 << (usize::BITS - SOCKET_ID_BITS - 2);
+
+/// Badge bit of the management endpoint registered as `NET_ADMIN_ENDPOINT`. Admin ops
+/// (stats, reconfiguration, shutdown) are only served with it set, and socket ops only
+/// without it. Every other copy that leaves Gopher is minted by Gopher with this bit
+/// clear, and client ids stay below it, so a client holding just `NET_ENDPOINT` cannot
+/// reconfigure the stack.
+pub const ADMIN_BADGE: usize = 1 << (usize::BITS - 1);
+
+/// Resource id the management endpoint is registered under, next to `NET_ENDPOINT`.
+/// `glenda::protocol::resource` has no id for it, so Gopher defines one; privileged
+/// clients look the endpoint up with the same value.
+pub const NET_ADMIN_ENDPOINT: usize = 0x4E45_5441;
+
+/// Split an incoming badge into the calling client and the socket it names.
+pub fn split_badge(badge: Badge) -> (Badge, Badge) {
+    let mask = (1usize << SOCKET_ID_BITS) - 1;
+    (Badge::new(badge.bits() & !mask), Badge::new(badge.bits() & mask))
+}
+
+/// Bits of a socket id naming its slot; the rest of the `SOCKET_ID_BITS` hold the
+/// slot's generation.
+pub const SOCKET_INDEX_BITS: u32 = 16;
+const SOCKET_INDEX_MASK: usize = (1 << SOCKET_INDEX_BITS) - 1;
+const GENERATION_MASK: usize = (1 << (SOCKET_ID_BITS - SOCKET_INDEX_BITS)) - 1;
+
+/// Socket ids as slot index plus generation. Every time a slot is handed out its
+/// generation goes up, so a badge kept from a closed socket names an old generation
+/// and fails with `Error::BadHandle` instead of reaching the socket that got the slot
+/// next. Freed slots are reused oldest first. Generations run from 1 to 65535 and
+/// then start over, skipping zero so no id is the null badge; only a badge from 65535
+/// reuses of its slot ago could name a live socket again, and even then it has to
+/// pass the ownership check.
+pub struct SocketIds {
+    // Generation last handed out for each slot, and whether that socket is still open
+    slots: Vec<(usize, bool)>,
+    free: VecDeque<usize>,
+}
+
+impl SocketIds {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new(), free: VecDeque::new() }
+    }
+
+    /// Whether `alloc` has an id left to give.
+    pub fn has_room(&self) -> bool {
+        !self.free.is_empty() || self.slots.len() <= SOCKET_INDEX_MASK
+    }
+
+    pub fn alloc(&mut self) -> Option<Badge> {
+        let index = match self.free.pop_front() {
+            Some(index) => index,
+            None if self.slots.len() <= SOCKET_INDEX_MASK => {
+                self.slots.push((0, false));
+                self.slots.len() - 1
+            }
+            None => return None,
+        };
+        let (generation, live) = &mut self.slots[index];
+        *generation = ((*generation + 1) & GENERATION_MASK).max(1);
+        *live = true;
+        Some(Badge::new(*generation << SOCKET_INDEX_BITS | index))
+    }
+
+    /// Retire `badge` and queue its slot for reuse.
+    pub fn free(&mut self, badge: Badge) {
+        if self.is_live(badge) {
+            let index = split_id(badge).0;
+            self.slots[index].1 = false;
+            self.free.push_back(index);
+        }
+    }
+
+    fn is_live(&self, badge: Badge) -> bool {
+        let (index, generation) = split_id(badge);
+        self.slots.get(index) == Some(&(generation, true))
+    }
+
+    /// Whether `badge` names a slot we handed out, but not to a socket that is open.
+    pub fn is_stale(&self, badge: Badge) -> bool {
+        badge.bits() != 0 && split_id(badge).0 < self.slots.len() && !self.is_live(badge)
+    }
+}
+
+fn split_id(badge: Badge) -> (usize, usize) {
+    (badge.bits() & SOCKET_INDEX_MASK, (badge.bits() >> SOCKET_INDEX_BITS) & GENERATION_MASK)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+pub struct SocketEntry {
+    pub handle: SocketHandle,
+    pub kind: SocketKind,
+    pub stats: SocketStats,
+    pub shut_rd: bool,
+    pub shut_wr: bool,
+    pub local: Option<IpEndpoint>,
+    pub remote: Option<IpEndpoint>,
+    // Interface the socket is pinned to by bind, connect or SO_BINDTODEVICE
+    pub iface: Option<usize>,
+    // SO_BINDTODEVICE, which a later bind to the wildcard address keeps
+    pub bound_device: Option<usize>,
+    // SO_BROADCAST: UDP sends to a broadcast address are allowed
+    pub broadcast: bool,
+    pub listening: bool,
+    // Extra smoltcp sockets listening on the same endpoint as `handle`, so up to the
+    // LISTEN backlog of handshakes can complete before ACCEPT; further SYNs get a RST
+    pub backlog: Vec<SocketHandle>,
+    // Blocking sockets park SEND/RECV until ready instead of returning WouldBlock
+    pub nonblocking: bool,
+    // SO_KEEPALIVE and TCP_KEEPIDLE, kept so getsockopt can report them
+    pub keepalive: bool,
+    pub keepalive_idle_secs: u32,
+    // TCP_USER_TIMEOUT; starts out as the configured `tcp_timeout_ms`
+    pub timeout_ms: Option<u64>,
+    // Client endpoint signalled with readiness bits, and the bits it last saw
+    pub notify: Option<Endpoint>,
+    pub notified: u32,
+    // Badge of the client that created the socket, for per-client limits
+    pub owner: Badge,
+    // Set while a TCP connect is in flight, with the time it started
+    pub connecting: Option<Instant>,
+    // Outcome of a failed connect, reported once through SO_ERROR
+    pub so_error: Option<Error>,
+    // The connection got as far as ESTABLISHED, and whether the peer has sent its FIN
+    pub established: bool,
+    pub peer_fin: bool,
+    // A reset from the peer closed the connection, not our timeout
+    pub peer_reset: bool,
+    // SO_REUSEADDR: bind may share a port with connections that are not listening
+    pub reuse_addr: bool,
+    // IP_TTL for outgoing packets
+    pub ttl: u8,
+    // Multicast groups joined through IP_ADD_MEMBERSHIP, with the interface index
+    pub groups: Vec<(usize, Ipv4Address)>,
+    // SO_LINGER in seconds: zero resets on close, more makes CLOSE wait for the FIN
+    pub linger: Option<u32>,
+    // SO_SNDHIWAT: cap on bytes queued in the TX buffer, below its capacity
+    pub send_hiwat: Option<usize>,
+    // CONNECT_DATA payload, queued once the handshake reaches ESTABLISHED
+    pub initial_data: Vec<u8>,
+    // TCP_CORK, and data held back by it or MSG_MORE with when the oldest byte came in
+    // (mono ns). Counts against the TX buffer but is not in it yet
+    pub cork: bool,
+    pub corked: Vec<u8>,
+    pub corked_since: Option<u64>,
+}
+
+impl SocketEntry {
+    /// Keepalive interval to hand to smoltcp, `None` when keepalives are off.
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.keepalive.then(|| Duration::from_secs(self.keepalive_idle_secs as u64))
+    }
+
+    /// Why a TCP socket in CLOSED can no longer carry data. Never `Timeout`, which the
+    /// run loop would take for "no reply" and leave the caller blocked.
+    pub fn closed_error(&self) -> Error {
+        match self.so_error {
+            Some(Error::Timeout) => Error::ConnectionAborted,
+            Some(e) => e,
+            // Torn down without either side finishing the close handshake
+            None if self.established && !self.peer_fin && !self.shut_wr => Error::ConnectionReset,
+            None => Error::NotConnected,
+        }
+    }
+
+    /// Bytes a send may still queue in a TX buffer of `capacity` holding `queued` bytes.
+    pub fn send_room(&self, capacity: usize, queued: usize) -> usize {
+        self.send_hiwat.map_or(capacity, |hiwat| hiwat.min(capacity)).saturating_sub(queued)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn new(handle: SocketHandle, kind: SocketKind) -> Self {
+        Self {
+            handle,
+            kind,
+            stats: SocketStats::default(),
+            shut_rd: false,
+            shut_wr: false,
+            local: None,
+            remote: None,
+            iface: None,
+            bound_device: None,
+            broadcast: false,
+            listening: false,
+            backlog: Vec::new(),
+            nonblocking: true,
+            keepalive: false,
+            keepalive_idle_secs: ops::DEFAULT_KEEPIDLE_SECS,
+            timeout_ms: None,
+            notify: None,
+            notified: 0,
+            owner: Badge::null(),
+            connecting: None,
+            so_error: None,
+            established: false,
+            peer_fin: false,
+            peer_reset: false,
+            reuse_addr: false,
+            ttl: ops::DEFAULT_TTL,
+            groups: Vec::new(),
+            linger: None,
+            send_hiwat: None,
+            initial_data: Vec::new(),
+            cork: false,
+            corked: Vec::new(),
+            corked_since: None,
+        }
+    }
+}
 
 pub struct GopherSocket<'a, 'b> {
     pub server: &'a mut GopherServer<'b>,
@@ -16,73 +266,1483 @@ pub struct GopherSocket<'a, 'b> {
 }
 
 impl<'a, 'b> NetworkService for GopherServer<'a> {
-    fn socket(&mut self, domain: i32, socket_type: i32, _protocol: i32) -> Result<usize, Error> {
+    fn socket(&mut self, domain: i32, socket_type: i32, proto: i32) -> Result<usize, Error> {
+        self.open_socket(Badge::null(), domain, socket_type, proto)
+    }
+}
+
+impl<'a> GopherServer<'a> {
+    /// Create a socket on behalf of the client calling with `owner`, counting it against
+    /// that client's `max_sockets_per_client`.
+    pub fn open_socket(
+        &mut self,
+        owner: Badge,
+        domain: i32,
+        socket_type: i32,
+        _protocol: i32,
+    ) -> Result<usize, Error> {
         if domain != protocol::network::AF_INET {
             return Err(Error::InvalidArgs);
         }
+        self.check_socket_limit(owner)?;
 
-        let handle = match socket_type {
-            protocol::network::SOCK_STREAM => {
-                let rx_buffer = tcp::SocketBuffer::new(alloc::vec![0; 4096]);
-                let tx_buffer = tcp::SocketBuffer::new(alloc::vec![0; 4096]);
-                let socket = tcp::Socket::new(rx_buffer, tx_buffer);
-                self.sockets.add(socket)
+        let (handle, kind) = match socket_type {
+            protocol::network::SOCK_STREAM => (self.new_tcp_socket(), SocketKind::Tcp),
+            protocol::network::SOCK_DGRAM => {
+                let (rx_size, tx_size) = self.socket_buffer_sizes();
+                let rx_buffer = udp::PacketBuffer::new(
+                    alloc::vec![udp::PacketMetadata::EMPTY; 16],
+                    alloc::vec![0; rx_size],
+                );
+                let tx_buffer = udp::PacketBuffer::new(
+                    alloc::vec![udp::PacketMetadata::EMPTY; 16],
+                    alloc::vec![0; tx_size],
+                );
+                let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+                socket.set_hop_limit(Some(ops::DEFAULT_TTL));
+                (self.sockets.add(socket), SocketKind::Udp)
             }
             _ => return Err(Error::NotSupported),
         };
 
-        let id = unsafe { core::mem::transmute_copy::<SocketHandle, usize>(&handle) };
-        let badge = Badge::new(id);
-        self.socket_map.insert(badge, handle);
-
+        let mut entry = SocketEntry::new(handle, kind);
+        entry.owner = owner;
+        if kind == SocketKind::Tcp {
+            entry.timeout_ms = self.default_tcp_timeout();
+        }
+        let badge = self.register_socket(entry)?;
         Ok(badge.bits())
     }
+
+    /// Refuse a new socket once the global or the owner's limit from the config is hit.
+    pub fn check_socket_limit(&self, owner: Badge) -> Result<(), Error> {
+        if !self.socket_ids.has_room() {
+            warn!("Every socket id is in use");
+            return Err(Error::Exhausted);
+        }
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        if config.max_sockets.is_some_and(|max| self.socket_map.len() >= max) {
+            warn!("Socket limit of {} reached", self.socket_map.len());
+            return Err(Error::OutOfMemory);
+        }
+        if let Some(max) = config.max_sockets_per_client {
+            let owned = self.socket_map.values().filter(|entry| entry.owner == owner).count();
+            if owned >= max {
+                warn!("Client {} reached its limit of {} sockets", owner.bits(), max);
+                return Err(Error::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new_tcp_socket(&mut self) -> SocketHandle {
+        let (rx_size, tx_size) = self.socket_buffer_sizes();
+        let rx_size = self.config.as_ref().and_then(|c| c.tcp_rx_window).unwrap_or(rx_size);
+        let rx_buffer = tcp::SocketBuffer::new(alloc::vec![0; rx_size]);
+        let tx_buffer = tcp::SocketBuffer::new(alloc::vec![0; tx_size]);
+        let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+        socket.set_hop_limit(Some(ops::DEFAULT_TTL));
+        socket.set_timeout(self.default_tcp_timeout().map(Duration::from_millis));
+        if let Some(cc) = self.default_congestion_control() {
+            socket.set_congestion_control(cc);
+        }
+        self.sockets.add(socket)
+    }
+
+    /// A fresh smoltcp socket listening on the endpoint of the listener `badge`, with
+    /// its keepalive, timeout, TTL and congestion control.
+    fn new_listener(&mut self, badge: Badge) -> Result<SocketHandle, Error> {
+        let entry = self.socket_map.get(&badge).ok_or(Error::NotFound)?;
+        let local = entry.local.ok_or(Error::InvalidArgs)?;
+        let (keep_alive, timeout, ttl) = (entry.keep_alive(), entry.timeout(), entry.ttl);
+        let cc = self.sockets.get::<tcp::Socket>(entry.handle).congestion_control();
+        let handle = self.new_tcp_socket();
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        socket.set_keep_alive(keep_alive);
+        socket.set_congestion_control(cc);
+        socket.set_timeout(timeout);
+        socket.set_hop_limit(Some(ttl));
+        if let Err(e) = socket.listen(sockaddr::listen_endpoint(local)) {
+            self.sockets.remove(handle);
+            return Err(match e {
+                tcp::ListenError::InvalidState => Error::InvalidArgs,
+                tcp::ListenError::Unaddressable => Error::AddrNotAvailable,
+            });
+        }
+        Ok(handle)
+    }
+
+    /// A socket of the listener's pool holding a connection ready for ACCEPT.
+    pub fn pending_connection(&self, entry: &SocketEntry) -> Option<SocketHandle> {
+        core::iter::once(entry.handle).chain(entry.backlog.iter().copied()).find(|handle| {
+            let state = self.sockets.get::<tcp::Socket>(*handle).state();
+            !matches!(state, tcp::State::Listen | tcp::State::SynReceived)
+        })
+    }
+
+    /// Most listening sockets a LISTEN may arm.
+    fn max_listen_backlog(&self) -> usize {
+        self.config.as_ref().map_or_else(default_max_backlog, |c| c.max_listen_backlog)
+    }
+
+    /// RX and TX buffer sizes for a new socket, from the config or 4096 bytes each.
+    fn socket_buffer_sizes(&self) -> (usize, usize) {
+        match &self.config {
+            Some(c) => (c.default_rx_buf, c.default_tx_buf),
+            None => (default_socket_buf(), default_socket_buf()),
+        }
+    }
+
+    pub fn default_tcp_timeout(&self) -> Option<u64> {
+        self.config.as_ref().and_then(|c| c.tcp_timeout_ms)
+    }
+
+    /// Payload of a full TCP segment on interface `iface`, which is what corked data
+    /// is released in; the RFC 879 default when the socket has no interface yet.
+    fn full_segment(&self, iface: Option<usize>) -> usize {
+        let Some(ctx) = iface.and_then(|idx| self.interfaces.get(idx)) else {
+            return DEFAULT_MSS;
+        };
+        // Loopback carries bare IP packets, without the Ethernet header
+        let overhead = if ctx.device.is_loopback() { 40 } else { super::TCP_FRAME_OVERHEAD };
+        ctx.device.capabilities().max_transmission_unit.saturating_sub(overhead).max(1)
+    }
+
+    /// Send what TCP_CORK or MSG_MORE has held back for longer than `CORK_TIMEOUT_MS`.
+    /// Runs before the interfaces are polled, so the data goes out in the same pass.
+    pub fn release_stale_corks(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        for entry in self.socket_map.values_mut() {
+            if entry
+                .corked_since
+                .is_some_and(|since| now.saturating_sub(since) >= ops::CORK_TIMEOUT_MS * 1_000_000)
+            {
+                let socket = self.sockets.get_mut::<tcp::Socket>(entry.handle);
+                release_corked(entry, socket, None);
+            }
+        }
+    }
+
+    fn default_congestion_control(&self) -> Option<tcp::CongestionControl> {
+        let name = self.config.as_ref()?.tcp_congestion_control.as_deref()?;
+        let cc = congestion_control(name.as_bytes());
+        if cc.is_none() {
+            warn!("Ignoring unknown congestion control {}", name);
+        }
+        cc
+    }
+
+    /// Follow every TCP connection through its states. In-flight connects that fell back
+    /// to CLOSED record why in `so_error`: running out the socket timeout aborts the
+    /// connect, anything sooner was the peer refusing with a reset. The run loop takes
+    /// `Err(Timeout)` for "no reply", so a timed-out connect is `ConnectionAborted` to
+    /// keep SEND, RECV and CONNECT answering. Seeing the peer's FIN is remembered so a
+    /// later CLOSED can be told apart from a reset, and a reset that the tap saw
+    /// arrive so it is not taken for a timeout.
+    pub fn track_tcp_state(&mut self) {
+        let now = self.get_time();
+        let resets = self.segment_log.take_resets();
+        for entry in self.socket_map.values_mut() {
+            if entry.kind != SocketKind::Tcp || entry.listening {
+                continue;
+            }
+            let socket = self.sockets.get_mut::<tcp::Socket>(entry.handle);
+            match socket.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => {}
+                tcp::State::Closed => {
+                    if entry.local.zip(entry.remote).is_some_and(|flow| resets.contains(&flow)) {
+                        entry.peer_reset = true;
+                    }
+                    entry.initial_data.clear();
+                    entry.corked.clear();
+                    entry.corked_since = None;
+                    if let Some(started) = entry.connecting.take() {
+                        let timed_out =
+                            entry.timeout().is_some_and(|timeout| now - started >= timeout);
+                        entry.so_error = Some(if timed_out {
+                            Error::ConnectionAborted
+                        } else {
+                            Error::ConnectionRefused
+                        });
+                    }
+                }
+                _ => {
+                    if entry.connecting.take().is_some() {
+                        entry.established = true;
+                    }
+                    queue_initial_data(entry, socket);
+                    if !socket.may_recv() {
+                        entry.peer_fin = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Free every TCP connection smoltcp dropped on its timeout. The badge is remembered
+    /// with its owner for `TIMED_OUT_RETENTION_NS` so the owner's next call about it fails
+    /// with `Error::Timeout` instead of `BadHandle`.
+    pub fn reap_timed_out(&mut self) {
+        let now_ns = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        self.timed_out.retain(|_, at| now_ns.saturating_sub(*at) < TIMED_OUT_RETENTION_NS);
+        let expired: Vec<Badge> = self
+            .socket_map
+            .iter()
+            .filter(|(_, entry)| {
+                // Closed without our FIN or the peer's reset, so the timer fired
+                entry.kind == SocketKind::Tcp
+                    && entry.timeout_ms.is_some()
+                    && entry.remote.is_some()
+                    && !entry.listening
+                    && !entry.shut_wr
+                    && !entry.peer_reset
+                    // Failed connects stay around so SO_ERROR can report them
+                    && entry.connecting.is_none()
+                    && entry.so_error.is_none()
+                    && self.sockets.get::<tcp::Socket>(entry.handle).state() == tcp::State::Closed
+            })
+            .map(|(badge, _)| *badge)
+            .collect();
+        for badge in expired {
+            log!("Socket for badge {} timed out", badge.bits());
+            self.fail_blocked(badge, Error::Timeout);
+            if let Some(entry) = self.unregister_socket(badge) {
+                self.sockets.remove(entry.handle);
+                self.timed_out.insert((entry.owner, badge), now_ns);
+            }
+            self.unreachable.remove(&badge);
+        }
+    }
+
+    /// Free TCP connections that reached CLOSED and that the client can learn nothing
+    /// more from: nothing left to read, no parked call or io_uring op, and any notify
+    /// endpoint already told about the hangup. The owner's next call about the badge
+    /// gets the error the socket would have reported. smoltcp sockets the client already closed are dropped
+    /// once they are done as well, which releases their port.
+    pub fn reap_closed(&mut self) {
+        let closed: Vec<Badge> = self
+            .socket_map
+            .iter()
+            .filter(|(badge, entry)| {
+                if entry.kind != SocketKind::Tcp
+                    || entry.remote.is_none()
+                    || entry.listening
+                    || entry.connecting.is_some()
+                    || entry.so_error.is_some()
+                    || (entry.notify.is_some() && entry.notified & ops::POLLHUP == 0)
+                    || self.blocked.contains_key(badge)
+                    || self.pending_uring.contains_key(badge)
+                {
+                    return false;
+                }
+                let socket = self.sockets.get::<tcp::Socket>(entry.handle);
+                socket.state() == tcp::State::Closed && socket.recv_queue() == 0
+            })
+            .map(|(badge, _)| *badge)
+            .collect();
+        for badge in closed {
+            log!("Reaping closed socket for badge {}", badge.bits());
+            if let Some(entry) = self.unregister_socket(badge) {
+                self.sockets.remove(entry.handle);
+                self.reaped.insert((entry.owner, badge), entry.closed_error());
+            }
+            self.unreachable.remove(&badge);
+        }
+
+        let orphans: Vec<SocketHandle> = self
+            .sockets
+            .iter()
+            .filter(|(handle, socket)| {
+                !self.handle_map.contains_key(handle)
+                    && match socket {
+                        Socket::Tcp(socket) => socket.state() == tcp::State::Closed,
+                        Socket::Udp(socket) => !socket.is_open(),
+                        // ICMP sockets belong to pings in flight, the DNS socket to
+                        // CONNECT_HOST
+                        _ => false,
+                    }
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in orphans {
+            if let Socket::Tcp(socket) = self.sockets.remove(handle)
+                && let Some(local) = socket.local_endpoint()
+            {
+                remove_port(&mut self.closing_ports, local.port, &handle);
+            }
+        }
+    }
+
+    /// Reject a call from `caller` on a socket another client owns.
+    pub fn check_owner(&self, caller: Badge, badge: Badge) -> Result<(), Error> {
+        match self.socket_map.get(&badge) {
+            Some(entry) if entry.owner != caller => {
+                warn!("Client {} denied access to socket {}", caller.bits(), badge.bits());
+                Err(Error::PermissionDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Badge of a new client for HELLO.
+    pub fn new_client(&mut self) -> Result<Badge, Error> {
+        if self.next_client_id >= MAX_CLIENT_ID {
+            return Err(Error::Exhausted);
+        }
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+        Ok(Badge::new(id << SOCKET_ID_BITS))
+    }
+
+    /// Give `entry` a socket id from `socket_ids`. Callers check `check_socket_limit`
+    /// first, which makes sure there is one.
+    pub fn register_socket(&mut self, entry: SocketEntry) -> Result<Badge, Error> {
+        let badge = self.socket_ids.alloc().ok_or(Error::Exhausted)?;
+        self.handle_map.insert(entry.handle, badge);
+        self.socket_map.insert(badge, entry);
+        Ok(badge)
+    }
+
+    pub fn unregister_socket(&mut self, badge: Badge) -> Option<SocketEntry> {
+        let entry = self.socket_map.remove(&badge)?;
+        self.socket_ids.free(badge);
+        self.handle_map.remove(&entry.handle);
+        if let Some(local) = entry.local {
+            remove_port(&mut self.bound_ports, local.port, &badge);
+        }
+        self.release_ring(badge);
+        self.release_recv_shm(badge);
+        // Connections nobody accepted are reset; the orphan sweep frees the sockets
+        for handle in &entry.backlog {
+            self.handle_map.remove(handle);
+            self.sockets.get_mut::<tcp::Socket>(*handle).abort();
+        }
+        for &(idx, group) in &entry.groups {
+            self.release_group(idx, group);
+        }
+        if let Some(ep) = &entry.notify {
+            self.release_slot(ep.cap());
+        }
+        Some(entry)
+    }
+
+    /// Handle and linger time of a TCP connection whose CLOSE has to wait for the FIN.
+    pub fn linger_on_close(&self, badge: Badge) -> Option<(SocketHandle, u32)> {
+        let entry = self.socket_map.get(&badge)?;
+        if entry.kind != SocketKind::Tcp || entry.listening {
+            return None;
+        }
+        entry.linger.filter(|secs| *secs > 0).map(|secs| (entry.handle, secs))
+    }
+
+    /// Record the local endpoint of `badge`, keeping `bound_ports` in step.
+    pub fn set_local(&mut self, badge: Badge, local: Option<IpEndpoint>) {
+        let Some(entry) = self.socket_map.get_mut(&badge) else {
+            return;
+        };
+        if let Some(old) = core::mem::replace(&mut entry.local, local) {
+            remove_port(&mut self.bound_ports, old.port, &badge);
+        }
+        if let Some(local) = local {
+            self.bound_ports.entry(local.port).or_default().insert(badge);
+        }
+    }
+
+    /// Whether binding `local` for `badge` would clash with another socket. A listener
+    /// always holds its port; connections (including ones closed by their client but
+    /// still in TIME-WAIT) hold it only against sockets without SO_REUSEADDR. Only the
+    /// sockets `bound_ports` and `closing_ports` list for the port are looked at.
+    pub fn port_in_use(
+        &self,
+        badge: Badge,
+        kind: SocketKind,
+        local: IpEndpoint,
+        reuse: bool,
+    ) -> bool {
+        let overlaps = |addr: Option<IpAddress>| {
+            addr.is_none_or(|addr| {
+                addr.is_unspecified() || local.addr.is_unspecified() || addr == local.addr
+            })
+        };
+        let live = self.bound_ports.get(&local.port).into_iter().flatten().any(|other| {
+            let Some(entry) = self.socket_map.get(other) else {
+                return false;
+            };
+            let Some(bound) = entry.local else {
+                return false;
+            };
+            if *other == badge
+                || entry.kind != kind
+                || bound.port != local.port
+                || !overlaps(Some(bound.addr))
+            {
+                return false;
+            }
+            entry.listening || !reuse
+        });
+        if live || kind != SocketKind::Tcp {
+            return live;
+        }
+        // Sockets whose badge is gone but which smoltcp is still closing
+        self.closing_ports.get(&local.port).into_iter().flatten().any(|handle| {
+            let socket = self.sockets.get::<tcp::Socket>(*handle);
+            let ep = socket.listen_endpoint();
+            socket.state() != tcp::State::Closed
+                && ep.port == local.port
+                && overlaps(ep.addr)
+                && (socket.state() == tcp::State::Listen || !reuse)
+        })
+    }
+
+    /// Whether any socket still holds `group` on interface `idx`.
+    pub fn group_in_use(&self, idx: usize, group: Ipv4Address) -> bool {
+        self.socket_map.values().any(|entry| entry.groups.contains(&(idx, group)))
+    }
+
+    /// Leave `group` on interface `idx` once no socket holds it anymore.
+    pub fn release_group(&mut self, idx: usize, group: Ipv4Address) {
+        if self.group_in_use(idx, group) {
+            return;
+        }
+        if let Some(ctx) = self.interfaces.get_mut(idx) {
+            let _ = ctx.iface.leave_multicast_group(group);
+            ctx.dirty = true;
+            log!("Left multicast group {} on {}", group, ctx.name);
+        }
+    }
+
+    /// Index of the interface owning `addr`, if any.
+    pub fn interface_for_addr(&self, addr: IpAddress) -> Option<usize> {
+        self.interfaces.iter().position(|ctx| ctx.iface.has_ip_addr(addr))
+    }
+
+    /// Pick the interface to reach `remote`: the attached subnet with the longest
+    /// prefix, then the longest-prefix static route (lowest metric on ties), then the
+    /// first up interface with a default gateway. `None` means nothing can reach it.
+    pub fn route_interface(&self, remote: IpAddress) -> Option<usize> {
+        // 127/8 never leaves the host, so without `lo` it has nowhere to go
+        if remote.is_loopback() {
+            return self.attached_interface(remote);
+        }
+        self.attached_interface(remote)
+            .or_else(|| {
+                let config = self.config.as_ref()?;
+                let (_, via) = config
+                    .routes
+                    .iter()
+                    .filter_map(|route| {
+                        let (dest, mask, via) = route.parse()?;
+                        let cidr = IpCidr::new(IpAddress::Ipv4(dest), mask);
+                        cidr.contains_addr(&remote).then_some(((mask, Reverse(route.metric)), via))
+                    })
+                    .max_by_key(|(rank, _)| *rank)?;
+                self.attached_interface(IpAddress::Ipv4(via))
+            })
+            .or_else(|| {
+                self.interfaces
+                    .iter()
+                    .position(|ctx| ctx.up && !ctx.device.is_loopback() && ctx.gateway.is_some())
+            })
+    }
+
+    /// Source address for traffic to `remote` leaving through interface `idx`: the
+    /// address on the subnet of `remote`, or of the next hop when it is routed, else
+    /// the interface's first address.
+    pub fn source_address(&self, idx: usize, remote: IpAddress) -> Option<Ipv4Address> {
+        let ctx = self.interfaces.get(idx)?;
+        let hop = match self.attached_interface(remote) {
+            Some(attached) if attached == idx => remote,
+            _ => ctx.gateway.map_or(remote, IpAddress::Ipv4),
+        };
+        let cidrs = ctx.iface.ip_addrs();
+        let cidr = cidrs.iter().find(|cidr| cidr.contains_addr(&hop)).or(cidrs.first())?;
+        let IpAddress::Ipv4(addr) = cidr.address();
+        Some(addr)
+    }
+
+    /// Up interface with an address on the most specific subnet containing `addr`.
+    fn attached_interface(&self, addr: IpAddress) -> Option<usize> {
+        self.interfaces
+            .iter()
+            .enumerate()
+            .filter(|(_, ctx)| ctx.up)
+            .flat_map(|(idx, ctx)| {
+                ctx.iface
+                    .ip_addrs()
+                    .iter()
+                    .filter(|cidr| cidr.contains_addr(&addr))
+                    .map(move |cidr| (cidr.prefix_len(), Reverse(idx)))
+            })
+            .max()
+            .map(|(_, Reverse(idx))| idx)
+    }
+
+    /// Map a client frame once so rings of any of `client`'s sockets can live in it,
+    /// returning the region id to pass to SETUP_IOURING.
+    pub fn register_region(
+        &mut self,
+        client: Badge,
+        client_vaddr: usize,
+        size: usize,
+        frame: Page,
+    ) -> Result<usize, Error> {
+        if size == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let size_aligned = align_up(size, 4096);
+        let (addr_server, range) = self.alloc_ring_va(size_aligned);
+        if let Err(e) = self.vspace.map_page(
+            frame,
+            addr_server,
+            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+            size_aligned / 4096,
+            self.res_client,
+            self.cspace,
+        ) {
+            self.ring_va.free(addr_server, range);
+            return Err(e);
+        }
+        let region = Region {
+            window: UringWindow { client: client_vaddr, server: addr_server, size },
+            mapping: RingMapping {
+                frame,
+                va: addr_server,
+                size: range,
+                pages: size_aligned / 4096,
+            },
+            rings: BTreeMap::new(),
+        };
+        let regions = self.regions.entry(client).or_default();
+        // Reuse the id of an unregistered region before growing the list
+        match regions.iter().position(Option::is_none) {
+            Some(id) => {
+                regions[id] = Some(region);
+                Ok(id)
+            }
+            None => {
+                regions.push(Some(region));
+                Ok(regions.len() - 1)
+            }
+        }
+    }
+
+    pub fn alloc_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+        port
+    }
+}
+
+impl<'a, 'b> GopherSocket<'a, 'b> {
+    /// Bind an unbound UDP socket to an ephemeral port so it can send and receive.
+    fn ensure_udp_bound(&mut self) -> Result<(), Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.local.is_some() {
+            return Ok(());
+        }
+        let port = self.server.alloc_port();
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        let socket = self.server.sockets.get_mut::<udp::Socket>(entry.handle);
+        socket.bind(port).map_err(|_| Error::InvalidArgs)?;
+        let local = IpEndpoint::new(smoltcp::wire::IpAddress::v4(0, 0, 0, 0), port);
+        self.server.set_local(self.badge, Some(local));
+        Ok(())
+    }
+
+    /// Send one datagram to `remote` on a UDP socket.
+    pub fn send_to(&mut self, data: &[u8], remote: IpEndpoint) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Udp {
+            return Err(Error::InvalidArgs);
+        }
+        if remote.port == 0 || remote.addr.is_unspecified() {
+            return Err(Error::InvalidArgs);
+        }
+        self.ensure_udp_bound()?;
+        let egress = self.broadcast_egress(remote.addr)?;
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        let source = match egress {
+            Some((_, local)) => local,
+            // Unicast from the wildcard address leaves from the address facing `remote`
+            None if entry.local.is_none_or(|local| local.addr.is_unspecified()) => entry
+                .iface
+                .or_else(|| self.server.route_interface(remote.addr))
+                .and_then(|idx| self.server.source_address(idx, remote.addr)),
+            None => None,
+        };
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        let socket = self.server.sockets.get_mut::<udp::Socket>(entry.handle);
+        // Datagrams go out whole, so one that does not fit under the watermark waits,
+        // and one that would not fit in an empty buffer is refused
+        if data.len() > entry.send_room(socket.payload_send_capacity(), 0) {
+            return Err(Error::MessageTooLong);
+        }
+        if entry.send_room(socket.payload_send_capacity(), socket.send_queue()) < data.len() {
+            return Err(Error::WouldBlock);
+        }
+        let mut meta = udp::UdpMetadata::from(remote);
+        meta.local_address = source.map(IpAddress::Ipv4);
+        socket.send_slice(data, meta).map_err(|e| match e {
+            udp::SendError::BufferFull => Error::WouldBlock,
+            udp::SendError::Unaddressable => Error::InvalidArgs,
+        })?;
+        entry.stats.bytes_sent += data.len() as u64;
+        if let Some((idx, _)) = egress {
+            // Every interface can route a broadcast, so send it before another one
+            // polls and takes the datagram
+            let now = self.server.get_time();
+            self.server.poll_interface(idx, now);
+        }
+        Ok(data.len())
+    }
+
+    /// Interface and source address for a datagram to `remote` if it is a broadcast:
+    /// the socket's own interface, else the one `remote` is the subnet broadcast of,
+    /// else the only non-loopback interface that is up. `None` for other destinations.
+    fn broadcast_egress(
+        &self,
+        remote: IpAddress,
+    ) -> Result<Option<(usize, Option<Ipv4Address>)>, Error> {
+        let IpAddress::Ipv4(addr) = remote;
+        let interfaces = &self.server.interfaces;
+        let subnet =
+            interfaces.iter().position(|ctx| {
+                ctx.up
+                    && ctx.iface.ip_addrs().iter().any(
+                        |cidr| matches!(cidr, IpCidr::Ipv4(v4) if v4.broadcast() == Some(addr)),
+                    )
+            });
+        if !addr.is_broadcast() && subnet.is_none() {
+            return Ok(None);
+        }
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if !entry.broadcast {
+            return Err(Error::PermissionDenied);
+        }
+        let idx = entry
+            .iface
+            .or(subnet)
+            .or_else(|| {
+                let mut up = interfaces
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, ctx)| ctx.up && !ctx.device.is_loopback());
+                match (up.next(), up.next()) {
+                    (Some((idx, _)), None) => Some(idx),
+                    // Ambiguous on a multi-homed host without SO_BINDTODEVICE
+                    _ => None,
+                }
+            })
+            .filter(|idx| interfaces[*idx].up)
+            .ok_or(Error::NetworkUnreachable)?;
+        Ok(Some((idx, interfaces[idx].iface.ipv4_addr())))
+    }
+
+    /// SO_BINDTODEVICE: pin the socket to the interface named in `optval`, or unpin it
+    /// when the name is empty.
+    fn bind_to_device(&mut self, optval: &[u8]) -> Result<(), Error> {
+        let name = optval.split(|b| *b == 0).next().unwrap_or(optval);
+        let idx = if name.is_empty() {
+            None
+        } else {
+            let idx = self.server.interfaces.iter().position(|ctx| ctx.name.as_bytes() == name);
+            Some(idx.ok_or(Error::NotFound)?)
+        };
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.listening || entry.established || entry.connecting.is_some() {
+            return Err(Error::InvalidState);
+        }
+        let by_addr = entry
+            .local
+            .filter(|local| !local.addr.is_unspecified())
+            .and_then(|local| self.server.interface_for_addr(local.addr));
+        if idx.is_some() && by_addr.is_some() && idx != by_addr {
+            return Err(Error::AddrNotAvailable);
+        }
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        entry.bound_device = idx;
+        entry.iface = idx.or(by_addr);
+        Ok(())
+    }
+
+    /// CONNECT_DATA: start a TCP connect with `data` waiting to go out as soon as the
+    /// handshake completes, so the client needs no SEND round trip after it.
+    pub fn connect_with_data(&mut self, address: &[u8], data: &[u8]) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Tcp {
+            return Err(Error::InvalidArgs);
+        }
+        let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+        if data.len() > entry.send_room(socket.send_capacity(), 0) {
+            return Err(Error::MessageTooLong);
+        }
+        self.connect(address)?;
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        entry.initial_data = data.to_vec();
+        Ok(data.len())
+    }
+
+    /// Receive one datagram from a UDP socket along with its sender.
+    pub fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        flags: i32,
+    ) -> Result<(usize, udp::UdpMetadata), Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Udp {
+            return Err(Error::InvalidArgs);
+        }
+        let peek = flags & ops::MSG_PEEK != 0;
+        let socket = self.server.sockets.get_mut::<udp::Socket>(entry.handle);
+        loop {
+            let res = if peek {
+                socket.peek_slice(buffer).map(|(len, meta)| (len, *meta))
+            } else {
+                socket.recv_slice(buffer)
+            };
+            let (len, meta) = res.map_err(|e| match e {
+                udp::RecvError::Exhausted => Error::WouldBlock,
+                udp::RecvError::Truncated => Error::Generic,
+            })?;
+            // Connected sockets only see datagrams from their peer
+            if entry.remote.is_some_and(|r| r != meta.endpoint) {
+                if peek {
+                    let _ = socket.recv();
+                }
+                continue;
+            }
+            if !peek {
+                entry.stats.bytes_received += len as u64;
+            }
+            return Ok((len, meta));
+        }
+    }
+
+    /// RECVMMSG: drain up to `max` queued datagrams (all that fit when zero) into `out`,
+    /// each as a native-endian `u32` length, the sender's sockaddr, then the data.
+    /// Returns how many were taken and the bytes written; a first datagram too large for
+    /// `out` fails with `MessageTooLong` and stays queued.
+    pub fn recv_batch(&mut self, out: &mut [u8], max: usize) -> Result<(usize, usize), Error> {
+        const HEAD: usize = 4 + sockaddr::SOCKADDR_IN_LEN;
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Udp {
+            return Err(Error::InvalidArgs);
+        }
+        let socket = self.server.sockets.get_mut::<udp::Socket>(entry.handle);
+        let (mut count, mut used) = (0, 0);
+        while max == 0 || count < max {
+            let (len, endpoint) = match socket.peek() {
+                Ok((data, meta)) => (data.len(), meta.endpoint),
+                Err(_) => break,
+            };
+            // Connected sockets only see datagrams from their peer
+            if entry.remote.is_some_and(|r| r != endpoint) {
+                let _ = socket.recv();
+                continue;
+            }
+            let Some(record) = out.get_mut(used..used + HEAD + len) else {
+                break;
+            };
+            let (head, data) = record.split_at_mut(HEAD);
+            let (data_len, meta) = socket.recv_slice(data).map_err(|_| Error::Generic)?;
+            head[..4].copy_from_slice(&(data_len as u32).to_ne_bytes());
+            sockaddr::write(meta.endpoint, &mut head[4..])?;
+            entry.stats.bytes_received += data_len as u64;
+            used += HEAD + data_len;
+            count += 1;
+        }
+        match count {
+            0 if socket.can_recv() => Err(Error::MessageTooLong),
+            0 => Err(Error::WouldBlock),
+            _ => Ok((count, used)),
+        }
+    }
+
+    /// Where a datagram received with `meta` arrived: the address it was sent to, with
+    /// the socket's port, and the interface holding it. smoltcp does not record which
+    /// interface took a packet in, so a subnet broadcast maps to that subnet and other
+    /// broadcasts and multicast to the interface the socket is pinned to, if any.
+    pub fn arrival(&self, meta: &udp::UdpMetadata) -> Result<(IpEndpoint, Option<usize>), Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        let port = entry.local.map_or(0, |local| local.port);
+        let Some(dst) = meta.local_address else {
+            return Ok((IpEndpoint::new(IpAddress::v4(0, 0, 0, 0), port), entry.iface));
+        };
+        let IpAddress::Ipv4(addr) = dst;
+        let idx = self.server.interface_for_addr(dst).or_else(|| {
+            self.server.interfaces.iter().position(|ctx| {
+                ctx.iface
+                    .ip_addrs()
+                    .iter()
+                    .any(|cidr| matches!(cidr, IpCidr::Ipv4(v4) if v4.broadcast() == Some(addr)))
+            })
+        });
+        Ok((IpEndpoint::new(dst, port), idx.or(entry.iface)))
+    }
+
+    /// Current readiness of the socket as a mask of `ops::POLL*` bits.
+    pub fn readiness(&self) -> Result<u32, Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if self.server.unreachable.contains(&self.badge) {
+            return Ok(ops::POLLERR | ops::POLLHUP);
+        }
+        let mut ready = 0;
+        match entry.kind {
+            SocketKind::Tcp => {
+                if entry.listening {
+                    if self.server.pending_connection(entry).is_some() {
+                        ready |= ops::POLLIN;
+                    }
+                    return Ok(ready);
+                }
+                let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+                // EOF counts as readable so the client sees the zero-length read
+                if socket.can_recv() || (socket.is_open() && !socket.may_recv()) {
+                    ready |= ops::POLLIN;
+                }
+                if socket.can_send()
+                    && entry.send_room(socket.send_capacity(), socket.send_queue()) > 0
+                {
+                    ready |= ops::POLLOUT;
+                }
+                if entry.remote.is_some() && socket.state() == tcp::State::Closed {
+                    ready |= ops::POLLHUP;
+                }
+            }
+            SocketKind::Udp => {
+                let socket = self.server.sockets.get::<udp::Socket>(entry.handle);
+                if socket.can_recv() {
+                    ready |= ops::POLLIN;
+                }
+                if socket.can_send() {
+                    ready |= ops::POLLOUT;
+                }
+            }
+        }
+        Ok(ready)
+    }
+
+    /// Connection state, endpoints and queue lengths for SOCK_STATE.
+    pub fn state_report(&self) -> Result<SocketStateReport, Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        let fmt = |ep: Option<IpEndpoint>| ep.map(|ep| alloc::format!("{}", ep));
+        let report = match entry.kind {
+            SocketKind::Tcp => {
+                let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+                SocketStateReport {
+                    state: Some(alloc::format!("{}", socket.state())),
+                    local: fmt(socket.local_endpoint().or(entry.local)),
+                    remote: fmt(socket.remote_endpoint().or(entry.remote)),
+                    send_queue: socket.send_queue(),
+                    recv_queue: socket.recv_queue(),
+                    tcp: Some(self.server.sender_stats(socket)),
+                }
+            }
+            SocketKind::Udp => {
+                let socket = self.server.sockets.get::<udp::Socket>(entry.handle);
+                SocketStateReport {
+                    state: None,
+                    local: fmt(entry.local),
+                    remote: fmt(entry.remote),
+                    send_queue: socket.send_queue(),
+                    recv_queue: socket.recv_queue(),
+                    tcp: None,
+                }
+            }
+        };
+        Ok(report)
+    }
+
+    /// Map a client frame of `size` bytes as the receive buffer for `recv_shm`.
+    pub fn setup_recv_shm(&mut self, size: usize, frame: Page) -> Result<(), Error> {
+        self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if size == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        // A second SETUP_RECV_SHM replaces the buffer, so the old one has to go first
+        self.server.release_recv_shm(self.badge);
+        let size_aligned = align_up(size, 4096);
+        let (addr_server, range) = self.server.alloc_ring_va(size_aligned);
+        if let Err(e) = self.server.vspace.map_page(
+            frame,
+            addr_server,
+            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+            size_aligned / 4096,
+            self.server.res_client,
+            self.server.cspace,
+        ) {
+            self.server.ring_va.free(addr_server, range);
+            return Err(e);
+        }
+        let mapping =
+            RingMapping { frame, va: addr_server, size: range, pages: size_aligned / 4096 };
+        self.server.recv_shm.insert(self.badge, (mapping, size));
+        Ok(())
+    }
+
+    /// Receive into `[offset, offset + len)` of the socket's shared buffer, so bulk data
+    /// skips the UTCB and its size limit.
+    pub fn recv_shm(&mut self, offset: usize, len: usize) -> Result<usize, Error> {
+        let (addr, size) = self
+            .server
+            .recv_shm
+            .get(&self.badge)
+            .map(|(map, size)| (map.va, *size))
+            .ok_or(Error::NotInitialized)?;
+        let end = offset.checked_add(len).filter(|end| *end <= size).ok_or(Error::InvalidArgs)?;
+        let buf =
+            unsafe { core::slice::from_raw_parts_mut((addr + offset) as *mut u8, end - offset) };
+        self.recv(buf, 0)
+    }
+
+    /// Set up the socket's ring at `client_vaddr` inside a region `client` registered
+    /// earlier, reusing that mapping instead of mapping a frame of its own. The ring may
+    /// not overlap that of another socket already placed there.
+    pub fn setup_iouring_in_region(
+        &mut self,
+        client: Badge,
+        region: usize,
+        client_vaddr: usize,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        let found = self.server.region_mut(client, region).ok_or(Error::NotFound)?;
+        let addr_server = found.window.translate(client_vaddr, size).ok_or(Error::InvalidArgs)?;
+        if found.overlaps(self.badge, client_vaddr, size) {
+            return Err(Error::AddrInUse);
+        }
+        self.attach_ring(UringWindow { client: client_vaddr, server: addr_server, size });
+        if let Some(found) = self.server.region_mut(client, region) {
+            found.rings.insert(self.badge, (client_vaddr, size));
+        }
+        Ok(())
+    }
+
+    fn attach_ring(&mut self, window: UringWindow) {
+        // A second SETUP_IOURING replaces the ring, so the old one has to go first
+        self.server.release_ring(self.badge);
+        let ring = unsafe {
+            glenda::io::uring::IoUringBuffer::attach(window.server as *mut u8, window.size)
+        };
+        let uring_server = glenda::io::uring::IoUringServer::new(ring);
+        self.server.uring_servers.insert(self.badge, uring_server);
+        self.server.uring_windows.insert(self.badge, window);
+    }
+
+    /// Outcome of a previously started TCP connect.
+    pub fn connect_result(&mut self) -> Result<(), Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Tcp {
+            return Err(Error::InvalidArgs);
+        }
+        if let Some(e) = entry.so_error {
+            return Err(e);
+        }
+        let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+        match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => Err(Error::WouldBlock),
+            tcp::State::Closed => Err(entry.closed_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// SO_ERROR: the pending connect failure as an error code (0 if none), cleared on read.
+    pub fn take_error(&mut self, optval: &mut [u8]) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        let code = entry.so_error.map_or(0, |e| e as i32);
+        let len = write_int(optval, code)?;
+        entry.so_error = None;
+        Ok(len)
+    }
+
+    /// Send `segments` (server addresses) in order. A stream stops at the first short
+    /// write and reports what went out; a datagram socket sends them as one datagram.
+    pub fn send_vectored(&mut self, segments: &[(usize, usize)]) -> Result<usize, Error> {
+        let slices = segments
+            .iter()
+            .map(|&(addr, len)| unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+        let kind = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?.kind;
+        if kind == SocketKind::Udp {
+            let datagram: Vec<u8> = slices.flatten().copied().collect();
+            return self.send(&datagram, 0);
+        }
+        let mut total = 0;
+        for slice in slices {
+            match self.send(slice, 0) {
+                Ok(len) => {
+                    total += len;
+                    if len < slice.len() {
+                        break;
+                    }
+                }
+                Err(e) if total == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(total)
+    }
+
+    /// IP_ADD_MEMBERSHIP: `optval` is an `ip_mreq`, the group then the local interface
+    /// address, both in network order. `INADDR_ANY` picks the route to the group.
+    fn join_group(&mut self, optval: &[u8]) -> Result<(), Error> {
+        let (group, idx) = self.parse_mreq(optval)?;
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.groups.contains(&(idx, group)) {
+            return Ok(());
+        }
+        if !self.server.group_in_use(idx, group) {
+            let ctx = &mut self.server.interfaces[idx];
+            ctx.iface.join_multicast_group(group).map_err(|_| Error::OutOfMemory)?;
+            ctx.dirty = true;
+            log!("Joined multicast group {} on {}", group, ctx.name);
+        }
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        entry.groups.push((idx, group));
+        Ok(())
+    }
+
+    fn leave_group(&mut self, optval: &[u8]) -> Result<(), Error> {
+        let (group, idx) = self.parse_mreq(optval)?;
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        let pos = entry.groups.iter().position(|g| *g == (idx, group)).ok_or(Error::NotFound)?;
+        entry.groups.remove(pos);
+        self.server.release_group(idx, group);
+        Ok(())
+    }
+
+    fn parse_mreq(&self, optval: &[u8]) -> Result<(Ipv4Address, usize), Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Udp {
+            return Err(Error::NotSupported);
+        }
+        let mreq = optval.get(..8).ok_or(Error::InvalidArgs)?;
+        let group = Ipv4Address::new(mreq[0], mreq[1], mreq[2], mreq[3]);
+        let local = Ipv4Address::new(mreq[4], mreq[5], mreq[6], mreq[7]);
+        if !group.is_multicast() {
+            return Err(Error::InvalidArgs);
+        }
+        let idx = if local.is_unspecified() {
+            self.server.route_interface(IpAddress::Ipv4(group)).ok_or(Error::NetworkUnreachable)?
+        } else {
+            self.server.interface_for_addr(IpAddress::Ipv4(local)).ok_or(Error::AddrNotAvailable)?
+        };
+        Ok((group, idx))
+    }
+
+    /// Half-close the connection. SHUT_WR sends a FIN but keeps the receive side open.
+    pub fn shutdown(&mut self, how: i32) -> Result<(), Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        let (rd, wr) = match how {
+            ops::SHUT_RD => (true, false),
+            ops::SHUT_WR => (false, true),
+            ops::SHUT_RDWR => (true, true),
+            _ => return Err(Error::InvalidArgs),
+        };
+        if entry.kind != SocketKind::Tcp {
+            return Err(Error::NotSupported);
+        }
+        let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+        if wr && !entry.shut_wr {
+            release_corked(entry, socket, None);
+            socket.close();
+            entry.shut_wr = true;
+        }
+        if rd {
+            entry.shut_rd = true;
+        }
+        Ok(())
+    }
+}
+
+/// Socket options carry a native-endian `i32`, as with POSIX `setsockopt`.
+fn read_int(optval: &[u8]) -> Result<i32, Error> {
+    let bytes = optval.get(..4).ok_or(Error::InvalidArgs)?;
+    Ok(i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn write_int(optval: &mut [u8], value: i32) -> Result<usize, Error> {
+    let out = optval.get_mut(..4).ok_or(Error::InvalidArgs)?;
+    out.copy_from_slice(&value.to_ne_bytes());
+    Ok(4)
+}
+
+/// Hand a CONNECT_DATA payload to smoltcp once the connection can carry it. It was
+/// checked against the TX buffer on connect, so it fits in one go.
+fn queue_initial_data(entry: &mut SocketEntry, socket: &mut tcp::Socket) {
+    if entry.initial_data.is_empty() || !socket.may_send() {
+        return;
+    }
+    if let Ok(len) = socket.send_slice(&entry.initial_data) {
+        entry.stats.bytes_sent += len as u64;
+        entry.initial_data.drain(..len);
+    }
+}
+
+/// Move corked data into smoltcp: all of it, or only whole segments of `segment` bytes
+/// while the socket stays corked. Room was reserved when it was corked, so it fits.
+fn release_corked(entry: &mut SocketEntry, socket: &mut tcp::Socket, segment: Option<usize>) {
+    let len = match segment {
+        Some(segment) => entry.corked.len() / segment.max(1) * segment.max(1),
+        None => entry.corked.len(),
+    };
+    if len == 0 || !socket.may_send() {
+        return;
+    }
+    if let Ok(sent) = socket.send_slice(&entry.corked[..len]) {
+        entry.stats.bytes_sent += sent as u64;
+        entry.corked.drain(..sent);
+    }
+    if entry.corked.is_empty() {
+        entry.corked_since = None;
+    }
+}
+
+/// The algorithm TCP_CONGESTION names, with or without a trailing NUL.
+fn congestion_control(name: &[u8]) -> Option<tcp::CongestionControl> {
+    match name.split(|b| *b == 0).next().unwrap_or(name) {
+        b"none" => Some(tcp::CongestionControl::None),
+        b"reno" => Some(tcp::CongestionControl::Reno),
+        b"cubic" => Some(tcp::CongestionControl::Cubic),
+        _ => None,
+    }
+}
+
+fn congestion_control_name(cc: tcp::CongestionControl) -> &'static str {
+    match cc {
+        tcp::CongestionControl::None => "none",
+        tcp::CongestionControl::Reno => "reno",
+        tcp::CongestionControl::Cubic => "cubic",
+    }
 }
 
 impl<'a, 'b> SocketService for GopherSocket<'a, 'b> {
-    fn bind(&mut self, _address: &[u8]) -> Result<(), Error> {
-        let _handle = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
-        // For now, smoltcp handles this differently or it's a stub
+    /// Bind to `address`. For UDP this also binds the smoltcp socket, so datagrams to
+    /// the port are received from then on; `0.0.0.0` receives on every interface and
+    /// port 0 picks a free ephemeral port.
+    fn bind(&mut self, address: &[u8]) -> Result<(), Error> {
+        let mut local = sockaddr::parse(address)?;
+        let iface = if local.addr.is_unspecified() {
+            None
+        } else {
+            Some(self.server.interface_for_addr(local.addr).ok_or(Error::AddrNotAvailable)?)
+        };
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if iface.is_some() && entry.bound_device.is_some() && iface != entry.bound_device {
+            return Err(Error::AddrNotAvailable);
+        }
+        if local.port != 0
+            && self.server.port_in_use(self.badge, entry.kind, local, entry.reuse_addr)
+        {
+            return Err(Error::AddrInUse);
+        }
+        if entry.kind == SocketKind::Udp {
+            // A UDP socket is bound once, explicitly or by its first send
+            if entry.local.is_some() {
+                return Err(Error::InvalidArgs);
+            }
+            let (kind, reuse) = (entry.kind, entry.reuse_addr);
+            if local.port == 0 {
+                let mut tries = u16::MAX - EPHEMERAL_PORT_START;
+                loop {
+                    let candidate = IpEndpoint::new(local.addr, self.server.alloc_port());
+                    if !self.server.port_in_use(self.badge, kind, candidate, reuse) {
+                        local = candidate;
+                        break;
+                    }
+                    tries = tries.checked_sub(1).ok_or(Error::AddrInUse)?;
+                }
+            }
+        }
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind == SocketKind::Udp {
+            let socket = self.server.sockets.get_mut::<udp::Socket>(entry.handle);
+            socket.bind(sockaddr::listen_endpoint(local)).map_err(|e| match e {
+                udp::BindError::InvalidState => Error::InvalidArgs,
+                udp::BindError::Unaddressable => Error::AddrNotAvailable,
+            })?;
+        }
+        entry.iface = iface.or(entry.bound_device);
+        self.server.set_local(self.badge, Some(local));
         Ok(())
     }
 
-    fn listen(&mut self, _backlog: i32) -> Result<(), Error> {
-        let _handle = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
-        // Implementation logic ...
+    /// Listen on the bound port with one smoltcp socket per backlog slot, capped by
+    /// `max_listen_backlog`. Each slot holds one connection until it is accepted.
+    fn listen(&mut self, backlog: i32) -> Result<(), Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Tcp {
+            return Err(Error::NotSupported);
+        }
+        let local = entry.local.filter(|local| local.port != 0).ok_or(Error::InvalidArgs)?;
+
+        // The backlog is built before anything changes, so a failure leaves the socket
+        // as it was and a later LISTEN can try again
+        let slots =
+            usize::try_from(backlog).unwrap_or(0).clamp(1, self.server.max_listen_backlog());
+        let mut extra = Vec::with_capacity(slots - 1);
+        let mut res = Ok(());
+        for _ in 1..slots {
+            match self.server.new_listener(self.badge) {
+                Ok(handle) => extra.push(handle),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if res.is_ok() {
+            let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+            res = socket.listen(sockaddr::listen_endpoint(local)).map_err(|e| match e {
+                tcp::ListenError::InvalidState => Error::InvalidArgs,
+                tcp::ListenError::Unaddressable => Error::AddrNotAvailable,
+            });
+        }
+        if let Err(e) = res {
+            for handle in extra {
+                self.server.sockets.remove(handle);
+            }
+            return Err(e);
+        }
+        entry.listening = true;
+        for handle in extra {
+            self.server.handle_map.insert(handle, self.badge);
+            entry.backlog.push(handle);
+        }
         Ok(())
     }
 
+    /// Hand an established connection to a new badge and re-arm its slot of the
+    /// listener with a fresh smoltcp socket on the same endpoint.
     fn accept(&mut self) -> Result<usize, Error> {
-        log!("Accept stub called");
-        Err(Error::NotSupported)
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        if !entry.listening {
+            return Err(Error::InvalidArgs);
+        }
+        let owner = entry.owner;
+        let (keepalive, keepalive_idle_secs) = (entry.keepalive, entry.keepalive_idle_secs);
+        let timeout_ms = entry.timeout_ms;
+        let ttl = entry.ttl;
+        let conn_handle = self.server.pending_connection(entry).ok_or(Error::WouldBlock)?;
+        let conn = self.server.sockets.get::<tcp::Socket>(conn_handle);
+        let (conn_local, conn_remote) = (conn.local_endpoint(), conn.remote_endpoint());
+        // The connection stays queued on the listener until the client has room for it
+        self.server.check_socket_limit(owner)?;
+
+        let listener = self.server.new_listener(self.badge).map_err(|_| Error::Generic)?;
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        match entry.backlog.iter_mut().find(|handle| **handle == conn_handle) {
+            Some(slot) => *slot = listener,
+            None => entry.handle = listener,
+        }
+        self.server.handle_map.remove(&conn_handle);
+        self.server.handle_map.insert(listener, self.badge);
+
+        let mut conn_entry = SocketEntry::new(conn_handle, SocketKind::Tcp);
+        conn_entry.remote = conn_remote;
+        conn_entry.keepalive = keepalive;
+        conn_entry.keepalive_idle_secs = keepalive_idle_secs;
+        conn_entry.timeout_ms = timeout_ms;
+        conn_entry.owner = owner;
+        conn_entry.ttl = ttl;
+        conn_entry.established = true;
+        conn_entry.iface = conn_local.and_then(|l| self.server.interface_for_addr(l.addr));
+        let badge = self.server.register_socket(conn_entry)?;
+        self.server.set_local(badge, conn_local);
+        Ok(badge.bits())
     }
 
-    fn connect(&mut self, _address: &[u8]) -> Result<(), Error> {
-        log!("Connect stub called");
-        Err(Error::NotSupported)
+    fn connect(&mut self, address: &[u8]) -> Result<(), Error> {
+        let remote = sockaddr::parse(address)?;
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        // Connecting UDP to a broadcast address needs SO_BROADCAST, as sending to it does
+        let broadcast = match entry.kind {
+            SocketKind::Udp => self.broadcast_egress(remote.addr)?,
+            SocketKind::Tcp => None,
+        };
+        let idx = match (entry.iface, broadcast) {
+            (Some(idx), _) | (None, Some((idx, _))) => idx,
+            (None, None) => {
+                self.server.route_interface(remote.addr).ok_or(Error::NetworkUnreachable)?
+            }
+        };
+        match entry.kind {
+            SocketKind::Udp => {
+                self.ensure_udp_bound()?;
+                let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+                entry.remote = Some(remote);
+                entry.iface = Some(idx);
+                Ok(())
+            }
+            SocketKind::Tcp => {
+                // A socket not bound to an address sends from the one facing `remote`
+                let source = self.server.source_address(idx, remote.addr).map(IpAddress::Ipv4);
+                let addr = match entry.local {
+                    Some(local) if !local.addr.is_unspecified() => Some(local.addr),
+                    _ => source,
+                };
+                let port = match entry.local {
+                    Some(local) if local.port != 0 => local.port,
+                    _ => self.server.alloc_port(),
+                };
+                let local = IpListenEndpoint { addr, port };
+                let server = &mut *self.server;
+                let entry = server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+                let ctx = &mut server.interfaces[idx];
+                let socket = server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                socket.connect(ctx.iface.context(), remote, local).map_err(|e| match e {
+                    tcp::ConnectError::InvalidState => Error::InvalidArgs,
+                    tcp::ConnectError::Unaddressable => Error::AddrNotAvailable,
+                })?;
+                let local = socket.local_endpoint();
+                entry.remote = Some(remote);
+                entry.iface = Some(idx);
+                entry.connecting = Some(server.get_time());
+                entry.so_error = None;
+                ctx.dirty = true;
+                server.set_local(self.badge, local);
+                Ok(())
+            }
+        }
     }
 
-    fn send(&mut self, data: &[u8], _flags: i32) -> Result<usize, Error> {
-        let handle = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
-        let socket = self.server.sockets.get_mut::<tcp::Socket>(*handle);
+    fn send(&mut self, data: &[u8], flags: i32) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if self.server.unreachable.contains(&self.badge) {
+            return Err(Error::NetworkUnreachable);
+        }
+        if entry.shut_wr {
+            return Err(Error::BrokenPipe);
+        }
+        if entry.kind == SocketKind::Udp {
+            let remote = entry.remote.ok_or(Error::NotConnected)?;
+            return self.send_to(data, remote);
+        }
+        let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+        // Established by a poll that has not run `track_tcp_state` yet
+        queue_initial_data(entry, socket);
+        if !entry.initial_data.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+        let hold = entry.cork || flags & ops::MSG_MORE != 0;
+        if !hold {
+            // Held data goes first, and this call completes the segment it was waiting on
+            release_corked(entry, socket, None);
+            if !entry.corked.is_empty() {
+                return Err(Error::WouldBlock);
+            }
+        }
         if !socket.can_send() {
+            return match socket.state() {
+                tcp::State::Closed => Err(entry.closed_error()),
+                _ if !socket.may_send() => Err(Error::BrokenPipe),
+                _ => Err(Error::WouldBlock),
+            };
+        }
+        let room =
+            entry.send_room(socket.send_capacity(), socket.send_queue() + entry.corked.len());
+        if flags & ops::MSG_ATOMIC != 0 {
+            // All or nothing: a write larger than the buffer could ever hold fails outright
+            if data.len() > entry.send_room(socket.send_capacity(), 0) {
+                return Err(Error::MessageTooLong);
+            }
+            if room < data.len() {
+                return Err(Error::WouldBlock);
+            }
+        }
+        if room == 0 {
             return Err(Error::WouldBlock);
         }
-        socket.send_slice(data).map_err(|_| Error::Generic)
+        // May queue only part of `data`; the caller sends the rest once there is room
+        let data = &data[..data.len().min(room)];
+        if hold {
+            let now = self.server.time_client.mono_now(Badge::null()).unwrap_or(0);
+            entry.corked.extend_from_slice(data);
+            entry.corked_since.get_or_insert(now);
+            let iface = entry.iface;
+            let segment = self.server.full_segment(iface);
+            let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+            let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+            release_corked(entry, socket, Some(segment));
+            if let Some(since) = entry.corked_since {
+                self.server.arm_timer(since + ops::CORK_TIMEOUT_MS * 1_000_000);
+            }
+            return Ok(data.len());
+        }
+        let len = socket.send_slice(data).map_err(|_| Error::NotConnected)?;
+        entry.stats.bytes_sent += len as u64;
+        Ok(len)
     }
 
-    fn recv(&mut self, buffer: &mut [u8], _flags: i32) -> Result<usize, Error> {
-        let handle = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
-        let socket = self.server.sockets.get_mut::<tcp::Socket>(*handle);
+    fn recv(&mut self, buffer: &mut [u8], flags: i32) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        if self.server.unreachable.contains(&self.badge) {
+            return Err(Error::NetworkUnreachable);
+        }
+        if entry.shut_rd {
+            // Reads after SHUT_RD see EOF
+            return Ok(0);
+        }
+        if entry.kind == SocketKind::Udp {
+            return self.recv_from(buffer, flags).map(|(len, _)| len);
+        }
+        let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
         if !socket.can_recv() {
+            return match socket.state() {
+                // Still handshaking, or no data yet on an open receive side
+                tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => {
+                    Err(Error::WouldBlock)
+                }
+                _ if socket.may_recv() => Err(Error::WouldBlock),
+                tcp::State::Closed if !entry.peer_fin => Err(entry.closed_error()),
+                // The peer sent FIN and everything before it has been read: clean EOF
+                _ => Ok(0),
+            };
+        }
+        // MSG_WAITALL holds out for a full buffer, or as much as the RX buffer can take,
+        // for as long as more data can still arrive
+        let wanted = buffer.len().min(socket.recv_capacity());
+        if flags & ops::MSG_WAITALL != 0 && socket.may_recv() && socket.recv_queue() < wanted {
             return Err(Error::WouldBlock);
         }
-        socket.recv_slice(buffer).map_err(|_| Error::Generic)
+        let peek = flags & ops::MSG_PEEK != 0;
+        let res = if peek { socket.peek_slice(buffer) } else { socket.recv_slice(buffer) };
+        let len = res.map_err(|e| match e {
+            tcp::RecvError::Finished => Error::NotConnected,
+            tcp::RecvError::InvalidState => entry.closed_error(),
+        })?;
+        if !peek {
+            entry.stats.bytes_received += len as u64;
+        }
+        Ok(len)
     }
 
     fn close(&mut self) -> Result<(), Error> {
         log!("Close socket for badge {}", self.badge.bits());
-        self.server.socket_map.remove(&self.badge);
+        self.server.fail_blocked(self.badge, Error::NotConnected);
+        if let Some(mut entry) = self.server.unregister_socket(self.badge) {
+            // TCP goes through FIN and TIME-WAIT, a listener stops right away
+            match entry.kind {
+                // SO_LINGER with a zero timeout drops queued data and resets the peer
+                SocketKind::Tcp if entry.linger == Some(0) => {
+                    self.server.sockets.get_mut::<tcp::Socket>(entry.handle).abort()
+                }
+                SocketKind::Tcp => {
+                    let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                    release_corked(&mut entry, socket, None);
+                    socket.close();
+                    // Holds its port until it is done; `reap_closed` drops it then
+                    if let Some(local) = entry.local {
+                        let closing = self.server.closing_ports.entry(local.port).or_default();
+                        closing.insert(entry.handle);
+                    }
+                }
+                SocketKind::Udp => self.server.sockets.get_mut::<udp::Socket>(entry.handle).close(),
+            }
+        }
+        self.server.unreachable.remove(&self.badge);
         Ok(())
     }
 
@@ -94,43 +1754,217 @@ impl<'a, 'b> SocketService for GopherSocket<'a, 'b> {
         Err(Error::NotSupported)
     }
 
-    fn setsockopt(&mut self, _level: i32, _optname: i32, _optval: &[u8]) -> Result<(), Error> {
-        Err(Error::NotSupported)
+    fn setsockopt(&mut self, level: i32, optname: i32, optval: &[u8]) -> Result<(), Error> {
+        match (level, optname) {
+            (ops::IPPROTO_IP, ops::IP_ADD_MEMBERSHIP) => return self.join_group(optval),
+            (ops::IPPROTO_IP, ops::IP_DROP_MEMBERSHIP) => return self.leave_group(optval),
+            (ops::SOL_SOCKET, ops::SO_BINDTODEVICE) => return self.bind_to_device(optval),
+            _ => {}
+        }
+        let entry = self.server.socket_map.get_mut(&self.badge).ok_or(Error::NotFound)?;
+        match (level, optname) {
+            (ops::SOL_SOCKET, ops::SO_NONBLOCK) => {
+                entry.nonblocking = read_int(optval)? != 0;
+                Ok(())
+            }
+            (ops::SOL_SOCKET, ops::SO_REUSEADDR) => {
+                entry.reuse_addr = read_int(optval)? != 0;
+                Ok(())
+            }
+            (ops::SOL_SOCKET, ops::SO_BROADCAST) if entry.kind == SocketKind::Udp => {
+                entry.broadcast = read_int(optval)? != 0;
+                Ok(())
+            }
+            (ops::SOL_SOCKET, ops::SO_SNDHIWAT) => {
+                let bytes = read_int(optval)?;
+                if bytes < 0 {
+                    return Err(Error::InvalidArgs);
+                }
+                entry.send_hiwat = (bytes > 0).then_some(bytes as usize);
+                Ok(())
+            }
+            (ops::SOL_SOCKET, ops::SO_LINGER) if entry.kind == SocketKind::Tcp => {
+                let onoff = read_int(optval)?;
+                let secs = read_int(optval.get(4..).ok_or(Error::InvalidArgs)?)?;
+                if secs < 0 {
+                    return Err(Error::InvalidArgs);
+                }
+                entry.linger = (onoff != 0).then_some(secs as u32);
+                Ok(())
+            }
+            (ops::IPPROTO_IP, ops::IP_TTL) => {
+                // Zero is reserved by smoltcp, and TTLs only go up to 255
+                let ttl = u8::try_from(read_int(optval)?)
+                    .ok()
+                    .filter(|ttl| *ttl != 0)
+                    .ok_or(Error::InvalidArgs)?;
+                entry.ttl = ttl;
+                match entry.kind {
+                    SocketKind::Tcp => self
+                        .server
+                        .sockets
+                        .get_mut::<tcp::Socket>(entry.handle)
+                        .set_hop_limit(Some(ttl)),
+                    SocketKind::Udp => self
+                        .server
+                        .sockets
+                        .get_mut::<udp::Socket>(entry.handle)
+                        .set_hop_limit(Some(ttl)),
+                }
+                Ok(())
+            }
+            (ops::SOL_SOCKET, ops::SO_KEEPALIVE) if entry.kind == SocketKind::Tcp => {
+                entry.keepalive = read_int(optval)? != 0;
+                let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                socket.set_keep_alive(entry.keep_alive());
+                Ok(())
+            }
+            (ops::IPPROTO_TCP, ops::TCP_KEEPIDLE) if entry.kind == SocketKind::Tcp => {
+                let secs = read_int(optval)?;
+                if secs <= 0 {
+                    return Err(Error::InvalidArgs);
+                }
+                entry.keepalive_idle_secs = secs as u32;
+                let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                socket.set_keep_alive(entry.keep_alive());
+                Ok(())
+            }
+            (ops::IPPROTO_TCP, ops::TCP_USER_TIMEOUT) if entry.kind == SocketKind::Tcp => {
+                let ms = read_int(optval)?;
+                if ms < 0 {
+                    return Err(Error::InvalidArgs);
+                }
+                entry.timeout_ms = (ms > 0).then_some(ms as u64);
+                let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                socket.set_timeout(entry.timeout());
+                Ok(())
+            }
+            (ops::IPPROTO_TCP, ops::TCP_NODELAY) if entry.kind == SocketKind::Tcp => {
+                let nodelay = read_int(optval)? != 0;
+                let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                socket.set_nagle_enabled(!nodelay);
+                Ok(())
+            }
+            (ops::IPPROTO_TCP, ops::TCP_CORK) if entry.kind == SocketKind::Tcp => {
+                entry.cork = read_int(optval)? != 0;
+                if !entry.cork {
+                    let socket = self.server.sockets.get_mut::<tcp::Socket>(entry.handle);
+                    release_corked(entry, socket, None);
+                }
+                Ok(())
+            }
+            (ops::IPPROTO_TCP, ops::TCP_CONGESTION) if entry.kind == SocketKind::Tcp => {
+                let cc = congestion_control(optval).ok_or(Error::InvalidArgs)?;
+                for handle in core::iter::once(entry.handle).chain(entry.backlog.iter().copied()) {
+                    self.server.sockets.get_mut::<tcp::Socket>(handle).set_congestion_control(cc);
+                }
+                Ok(())
+            }
+            _ => Err(Error::NotSupported),
+        }
     }
 
-    fn getsockopt(&self, _level: i32, _optname: i32, _optval: &mut [u8]) -> Result<usize, Error> {
-        Err(Error::NotSupported)
+    fn getsockopt(&self, level: i32, optname: i32, optval: &mut [u8]) -> Result<usize, Error> {
+        let entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        match (level, optname) {
+            (ops::SOL_SOCKET, ops::SO_NONBLOCK) => write_int(optval, entry.nonblocking as i32),
+            (ops::SOL_SOCKET, ops::SO_REUSEADDR) => write_int(optval, entry.reuse_addr as i32),
+            (ops::IPPROTO_IP, ops::IP_TTL) => write_int(optval, entry.ttl as i32),
+            (ops::SOL_SOCKET, ops::SO_KEEPALIVE) => write_int(optval, entry.keepalive as i32),
+            (ops::SOL_SOCKET, ops::SO_BROADCAST) => write_int(optval, entry.broadcast as i32),
+            (ops::SOL_SOCKET, ops::SO_BINDTODEVICE) => {
+                let name = match entry.bound_device {
+                    Some(idx) => self.server.interfaces[idx].name.as_bytes(),
+                    None => &[],
+                };
+                let out = optval.get_mut(..name.len()).ok_or(Error::InvalidArgs)?;
+                out.copy_from_slice(name);
+                Ok(name.len())
+            }
+            (ops::SOL_SOCKET, ops::SO_SNDHIWAT) => {
+                write_int(optval, entry.send_hiwat.unwrap_or(0) as i32)
+            }
+            (ops::SOL_SOCKET, ops::SO_SNDSPACE) => {
+                let (capacity, queued) = match entry.kind {
+                    SocketKind::Tcp => {
+                        let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+                        (socket.send_capacity(), socket.send_queue() + entry.corked.len())
+                    }
+                    SocketKind::Udp => {
+                        let socket = self.server.sockets.get::<udp::Socket>(entry.handle);
+                        (socket.payload_send_capacity(), socket.send_queue())
+                    }
+                };
+                write_int(optval, entry.send_room(capacity, queued) as i32)
+            }
+            (ops::SOL_SOCKET, ops::SO_LINGER) => {
+                let onoff = write_int(optval, entry.linger.is_some() as i32)?;
+                let secs = write_int(&mut optval[onoff..], entry.linger.unwrap_or(0) as i32)?;
+                Ok(onoff + secs)
+            }
+            // Peeks only; the GETSOCKOPT op clears it through `take_error`
+            (ops::SOL_SOCKET, ops::SO_ERROR) => {
+                write_int(optval, entry.so_error.map_or(0, |e| e as i32))
+            }
+            (ops::IPPROTO_TCP, ops::TCP_KEEPIDLE) => {
+                write_int(optval, entry.keepalive_idle_secs as i32)
+            }
+            (ops::IPPROTO_TCP, ops::TCP_USER_TIMEOUT) => {
+                write_int(optval, entry.timeout_ms.unwrap_or(0) as i32)
+            }
+            (ops::IPPROTO_TCP, ops::TCP_NODELAY) if entry.kind == SocketKind::Tcp => {
+                let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+                write_int(optval, !socket.nagle_enabled() as i32)
+            }
+            (ops::IPPROTO_TCP, ops::TCP_CORK) => write_int(optval, entry.cork as i32),
+            (ops::IPPROTO_TCP, ops::TCP_CONGESTION) if entry.kind == SocketKind::Tcp => {
+                let socket = self.server.sockets.get::<tcp::Socket>(entry.handle);
+                let name = congestion_control_name(socket.congestion_control()).as_bytes();
+                let out = optval.get_mut(..name.len()).ok_or(Error::InvalidArgs)?;
+                out.copy_from_slice(name);
+                Ok(name.len())
+            }
+            _ => Err(Error::NotSupported),
+        }
     }
 
     fn setup_iouring(
         &mut self,
-        _client_vaddr: usize,
+        client_vaddr: usize,
         size: usize,
         frame: Option<Page>,
     ) -> Result<(), Error> {
-        let _handle = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
+        let _entry = self.server.socket_map.get(&self.badge).ok_or(Error::NotFound)?;
         let size_aligned = align_up(size, 4096);
         // In GopherServer, we allocate the server vaddr
+        let (addr_server, range) = self.server.alloc_ring_va(size_aligned);
+        let mapping = match frame {
+            Some(f) => {
+                if let Err(e) = self.server.vspace.map_page(
+                    f,
+                    addr_server,
+                    glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+                    size_aligned / 4096,
+                    self.server.res_client,
+                    self.server.cspace,
+                ) {
+                    self.server.ring_va.free(addr_server, range);
+                    return Err(e);
+                }
+                Some(RingMapping {
+                    frame: f,
+                    va: addr_server,
+                    size: range,
+                    pages: size_aligned / 4096,
+                })
+            }
+            None => None,
+        };
 
-        let addr_server = self
-            .server
-            .next_ring_vaddr
-            .fetch_add(size_aligned, core::sync::atomic::Ordering::SeqCst);
-        if let Some(f) = frame {
-            self.server.vspace.map_page(
-                f,
-                addr_server,
-                glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-                size_aligned / 4096,
-                self.server.res_client,
-                self.server.cspace,
-            )?;
-        }
-
-        let ring =
-            unsafe { glenda::io::uring::IoUringBuffer::attach(addr_server as *mut u8, size) };
-        let uring_server = glenda::io::uring::IoUringServer::new(ring);
-        self.server.uring_servers.insert(self.badge, uring_server);
+        self.attach_ring(UringWindow { client: client_vaddr, server: addr_server, size });
+        if let Some(mapping) = mapping {
+            self.server.ring_maps.insert(self.badge, mapping);
+        }
         Ok(())
     }
 
@@ -138,41 +1972,440 @@ impl<'a, 'b> SocketService for GopherSocket<'a, 'b> {
         let mut uring_server =
             self.server.uring_servers.remove(&self.badge).ok_or(Error::NotFound)?;
 
+        let mut prev_user_data = None;
         while let Some(sqe) = uring_server.next_request() {
+            let user_data = sqe.user_data;
             match sqe.opcode {
                 IOURING_OP_READ => {
-                    let buf = unsafe {
-                        core::slice::from_raw_parts_mut(sqe.addr as *mut u8, sqe.len as usize)
+                    let (addr, len) = (sqe.addr as usize, sqe.len as usize);
+                    let buf = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+                    let res = if self.server.has_pending_read(self.badge) {
+                        Err(Error::WouldBlock)
+                    } else {
+                        self.recv(buf, 0)
                     };
-                    match self.recv(buf, 0) {
+                    match res {
                         Ok(len) => {
                             let _ = uring_server.complete(sqe.user_data, len as i32);
                         }
+                        Err(Error::WouldBlock) => {
+                            let kind = PendingKind::Read { addr, len };
+                            self.server.park_uring(
+                                self.badge,
+                                PendingOp { user_data: sqe.user_data, kind },
+                            );
+                        }
                         Err(e) => {
                             let _ = uring_server.complete(sqe.user_data, -(e as i32));
                         }
                     }
                 }
                 IOURING_OP_WRITE => {
-                    let buf = unsafe {
-                        core::slice::from_raw_parts(sqe.addr as *const u8, sqe.len as usize)
+                    let (addr, len) = (sqe.addr as usize, sqe.len as usize);
+                    let buf = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+                    let res = if self.server.has_pending_write(self.badge) {
+                        Err(Error::WouldBlock)
+                    } else {
+                        self.send(buf, 0)
                     };
-                    match self.send(buf, 0) {
+                    match res {
                         Ok(len) => {
                             let _ = uring_server.complete(sqe.user_data, len as i32);
                         }
+                        Err(Error::WouldBlock) => {
+                            let kind = PendingKind::Write { addr, len };
+                            self.server.park_uring(
+                                self.badge,
+                                PendingOp { user_data: sqe.user_data, kind },
+                            );
+                        }
+                        Err(e) => {
+                            let _ = uring_server.complete(sqe.user_data, -(e as i32));
+                        }
+                    }
+                }
+                ops::IOURING_OP_WRITEV => {
+                    let segments = match self.server.uring_windows.get(&self.badge) {
+                        Some(window) => window.iovecs(sqe.addr as usize, sqe.len as usize),
+                        None => Err(Error::NotInitialized),
+                    };
+                    let res = match &segments {
+                        Ok(_) if self.server.has_pending_write(self.badge) => {
+                            Err(Error::WouldBlock)
+                        }
+                        Ok(segments) => self.send_vectored(segments),
+                        Err(e) => Err(*e),
+                    };
+                    match (res, segments) {
+                        (Ok(len), _) => {
+                            let _ = uring_server.complete(sqe.user_data, len as i32);
+                        }
+                        (Err(Error::WouldBlock), Ok(segments)) => {
+                            let kind = PendingKind::Writev { segments };
+                            self.server.park_uring(
+                                self.badge,
+                                PendingOp { user_data: sqe.user_data, kind },
+                            );
+                        }
+                        (Err(e), _) => {
+                            let _ = uring_server.complete(sqe.user_data, -(e as i32));
+                        }
+                    }
+                }
+                ops::IOURING_OP_POLL_ADD => {
+                    let events = sqe.len as u32;
+                    let edge = events & ops::POLL_EDGE != 0;
+                    // Edge-triggered polls only fire on readiness gained after registration
+                    let seen = if edge { self.readiness().unwrap_or(0) } else { 0 };
+                    let kind = PendingKind::Poll { events: events & !ops::POLL_EDGE, edge, seen };
+                    self.server
+                        .park_uring(self.badge, PendingOp { user_data: sqe.user_data, kind });
+                }
+                ops::IOURING_OP_TIMEOUT => {
+                    let now = self.server.time_client.mono_now(Badge::null()).unwrap_or(0);
+                    let deadline_ns = now.saturating_add(sqe.addr as u64);
+                    let link = if sqe.len & ops::TIMEOUT_LINK != 0 { prev_user_data } else { None };
+                    let kind = PendingKind::Timeout { deadline_ns, link };
+                    self.server
+                        .park_uring(self.badge, PendingOp { user_data: sqe.user_data, kind });
+                    self.server.arm_timer(deadline_ns);
+                }
+                ops::IOURING_OP_ACCEPT => {
+                    let op = PendingOp { user_data: sqe.user_data, kind: PendingKind::Accept };
+                    self.server.park_uring(self.badge, op);
+                }
+                ops::IOURING_OP_CONNECT => {
+                    let addr = unsafe {
+                        core::slice::from_raw_parts(sqe.addr as *const u8, sqe.len as usize)
+                    };
+                    match self.connect(addr) {
+                        Ok(()) => {
+                            let op =
+                                PendingOp { user_data: sqe.user_data, kind: PendingKind::Connect };
+                            self.server.park_uring(self.badge, op);
+                        }
                         Err(e) => {
                             let _ = uring_server.complete(sqe.user_data, -(e as i32));
                         }
                     }
                 }
+                ops::IOURING_OP_CLOSE => {
+                    self.server.abort_uring(
+                        self.badge,
+                        &mut uring_server,
+                        Error::ConnectionAborted,
+                    );
+                    let res = self.close().map_or_else(|e| -(e as i32), |_| 0);
+                    let _ = uring_server.complete(sqe.user_data, res);
+                }
+                ops::IOURING_OP_SHUTDOWN => {
+                    let res = self.shutdown(sqe.len as i32).map_or_else(|e| -(e as i32), |_| 0);
+                    let _ = uring_server.complete(sqe.user_data, res);
+                }
+                ops::IOURING_OP_ASYNC_CANCEL => {
+                    let all = sqe.len & ops::ASYNC_CANCEL_ALL != 0;
+                    let target = if all { None } else { Some(sqe.addr as u64) };
+                    let cancelled = self.server.cancel_uring(self.badge, &mut uring_server, target);
+                    let res = match cancelled {
+                        0 if !all => -(Error::NotFound as i32),
+                        n => n as i32,
+                    };
+                    let _ = uring_server.complete(sqe.user_data, res);
+                }
                 _ => {
                     let _ = uring_server.complete(sqe.user_data, -(Error::NotSupported as i32));
                 }
             }
+            prev_user_data = Some(user_data);
         }
 
         self.server.uring_servers.insert(self.badge, uring_server);
+        // Closed by one of its own SQEs, which could not release the ring while we held it
+        if !self.server.socket_map.contains_key(&self.badge) {
+            self.server.release_ring(self.badge);
+        }
         Ok(())
     }
 }
+
+/// Drop `item` from the set of `port` in a port index, and the set once it is empty.
+fn remove_port<T: Ord>(index: &mut BTreeMap<u16, BTreeSet<T>>, port: u16, item: &T) {
+    if let Some(set) = index.get_mut(&port) {
+        set.remove(item);
+        if set.is_empty() {
+            index.remove(&port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gopher::clock::Clock;
+    use crate::gopher::stack::InterfaceContext;
+    use crate::layout::{DEVICE_CAP, INIT_CAP};
+    use alloc::boxed::Box;
+    use glenda::cap::{CSPACE_CAP, CapPtr, MONITOR_CAP, VSPACE_CAP};
+    use glenda::client::{DeviceClient, InitClient, ResourceClient};
+    use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+    const PAYLOAD: &[u8] = b"gopher-loopback";
+
+    /// Every read is a millisecond past the last, so smoltcp timers move along.
+    struct FakeClock(u64);
+
+    impl Clock for FakeClock {
+        fn mono_now(&mut self, _badge: Badge) -> Result<u64, Error> {
+            self.0 += 1_000_000;
+            Ok(self.0)
+        }
+
+        fn notify_at(
+            &mut self,
+            _badge: Badge,
+            _deadline_ns: u64,
+            _ep: CapPtr,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// A server with just `lo`, as `init` leaves it without network.json. None of the
+    /// clients are called on the socket paths, so they never leave the host.
+    fn server() -> GopherServer<'static> {
+        let mut server = GopherServer::new(
+            Box::leak(Box::new(ResourceClient::new(MONITOR_CAP))),
+            Box::leak(Box::new(CSpaceManager::new(CSPACE_CAP, 16))),
+            Box::leak(Box::new(VSpaceManager::new(VSPACE_CAP, 0x7000_0000, 0x1000_0000))),
+            Box::leak(Box::new(DeviceClient::new(DEVICE_CAP))),
+            Box::leak(Box::new(InitClient::new(INIT_CAP))),
+            Box::leak(Box::new(FakeClock(0))),
+        );
+        server.setup_loopback();
+        server
+    }
+
+    fn client(id: usize) -> Badge {
+        Badge::new(id << SOCKET_ID_BITS)
+    }
+
+    fn open(server: &mut GopherServer<'static>, owner: Badge, socket_type: i32) -> Badge {
+        let id = server.open_socket(owner, protocol::network::AF_INET, socket_type, 0).unwrap();
+        Badge::new(id)
+    }
+
+    fn addr(port: u16) -> Vec<u8> {
+        let mut buf = alloc::vec![0; sockaddr::SOCKADDR_IN_LEN];
+        let end = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(127, 0, 0, 1)), port);
+        sockaddr::write(end, &mut buf).unwrap();
+        buf
+    }
+
+    /// Run `poll()` until `done` holds, as the run loop would between calls.
+    fn pump(
+        server: &mut GopherServer<'static>,
+        mut done: impl FnMut(&mut GopherServer<'static>) -> bool,
+    ) {
+        for _ in 0..256 {
+            server.poll().unwrap();
+            if done(server) {
+                return;
+            }
+        }
+        panic!("loopback stalled");
+    }
+
+    fn socket<'s>(
+        server: &'s mut GopherServer<'static>,
+        badge: Badge,
+    ) -> GopherSocket<'s, 'static> {
+        GopherSocket { server, badge }
+    }
+
+    #[test]
+    fn tcp_round_trip_through_server() {
+        let mut server = server();
+        let owner = client(1);
+        let listener = open(&mut server, owner, protocol::network::SOCK_STREAM);
+        socket(&mut server, listener).bind(&addr(8080)).unwrap();
+        socket(&mut server, listener).listen(4).unwrap();
+        let conn = open(&mut server, owner, protocol::network::SOCK_STREAM);
+        socket(&mut server, conn).connect(&addr(8080)).unwrap();
+
+        let mut accepted = None;
+        pump(&mut server, |s| {
+            accepted = socket(s, listener).accept().ok().map(Badge::new);
+            accepted.is_some()
+        });
+        let accepted = accepted.unwrap();
+        assert_eq!(server.socket_map[&accepted].owner, owner);
+
+        for (from, to) in [(conn, accepted), (accepted, conn)] {
+            pump(
+                &mut server,
+                |s| matches!(socket(s, from).send(PAYLOAD, 0), Ok(len) if len == PAYLOAD.len()),
+            );
+            let mut received = Vec::new();
+            pump(&mut server, |s| {
+                let mut buf = [0u8; 64];
+                if let Ok(len) = socket(s, to).recv(&mut buf, 0) {
+                    received.extend_from_slice(&buf[..len]);
+                }
+                received.len() >= PAYLOAD.len()
+            });
+            assert_eq!(received, PAYLOAD);
+        }
+
+        socket(&mut server, conn).close().unwrap();
+        pump(&mut server, |s| matches!(socket(s, accepted).recv(&mut [0u8; 8], 0), Ok(0)));
+        assert!(!server.socket_map.contains_key(&conn));
+    }
+
+    /// A socket id is only good on the endpoint of the client that opened it.
+    #[test]
+    fn other_clients_cannot_use_a_socket() {
+        let mut server = server();
+        let first = server.new_client().unwrap();
+        let second = server.new_client().unwrap();
+        assert!(first != second);
+        let id = open(&mut server, first, protocol::network::SOCK_DGRAM);
+        let send = protocol::network::SEND;
+
+        let (caller, badge) = server.admit(Badge::new(first.bits() | id.bits()), send).unwrap();
+        assert!(caller == first && badge == id);
+        let denied = server.admit(Badge::new(second.bits() | id.bits()), send);
+        assert!(matches!(denied, Err(Error::PermissionDenied)));
+        // Neither the raw endpoint nor NET_ENDPOINT stands in for a client
+        assert!(matches!(server.admit(id, send), Err(Error::PermissionDenied)));
+        let bootstrap = Badge::new(BOOTSTRAP_BADGE | id.bits());
+        assert!(matches!(server.admit(bootstrap, send), Err(Error::PermissionDenied)));
+        assert!(server.admit(Badge::new(BOOTSTRAP_BADGE), ops::HELLO).is_ok());
+        assert!(matches!(server.admit(first, ops::HELLO), Err(Error::PermissionDenied)));
+    }
+
+    /// Admin labels only go through the management endpoint, and no client badge can
+    /// ever turn into it.
+    #[test]
+    fn admin_labels_need_the_admin_endpoint() {
+        let mut server = server();
+        let client = server.new_client().unwrap();
+        let admin = Badge::new(ADMIN_BADGE);
+        assert!(matches!(server.admit(client, ops::DRAIN), Err(Error::PermissionDenied)));
+        let bootstrap = Badge::new(BOOTSTRAP_BADGE);
+        assert!(matches!(server.admit(bootstrap, ops::DRAIN), Err(Error::PermissionDenied)));
+        assert!(server.admit(admin, ops::DRAIN).is_ok());
+        let socket = protocol::network::SOCKET;
+        assert!(matches!(server.admit(admin, socket), Err(Error::PermissionDenied)));
+
+        server.next_client_id = MAX_CLIENT_ID - 1;
+        let last = server.new_client().unwrap();
+        assert_eq!(last.bits() & (ADMIN_BADGE | BOOTSTRAP_BADGE), 0);
+        assert!(matches!(server.new_client(), Err(Error::Exhausted)));
+    }
+
+    #[test]
+    fn udp_round_trip_through_server() {
+        let mut server = server();
+        let owner = client(1);
+        let ends = [
+            open(&mut server, owner, protocol::network::SOCK_DGRAM),
+            open(&mut server, owner, protocol::network::SOCK_DGRAM),
+        ];
+        socket(&mut server, ends[0]).bind(&addr(5000)).unwrap();
+        socket(&mut server, ends[1]).bind(&addr(5001)).unwrap();
+
+        let to = sockaddr::parse(&addr(5001)).unwrap();
+        let sent = socket(&mut server, ends[0]).send_to(PAYLOAD, to);
+        assert!(matches!(sent, Ok(len) if len == PAYLOAD.len()));
+        let mut buf = [0u8; 64];
+        let mut from = None;
+        pump(&mut server, |s| match socket(s, ends[1]).recv_from(&mut buf, 0) {
+            Ok((len, meta)) => {
+                assert_eq!(&buf[..len], PAYLOAD);
+                from = Some(meta.endpoint);
+                true
+            }
+            Err(e) => {
+                assert!(matches!(e, Error::WouldBlock));
+                false
+            }
+        });
+        assert_eq!(from, Some(sockaddr::parse(&addr(5000)).unwrap()));
+        assert_eq!(server.socket_map[&ends[1]].stats.bytes_received, PAYLOAD.len() as u64);
+    }
+
+    /// Two interfaces share the server's one SocketSet, as `poll_order` describes. A
+    /// datagram between sockets on `lo` must come out exactly once, even with the other
+    /// interface polled first: it has no route to 127/8, so the datagram waits for `lo`.
+    #[test]
+    fn shared_socket_set_delivers_once() {
+        let mut server = server();
+        let now = server.get_time();
+        let other = InterfaceContext::loopback(Ipv4Address::new(10, 0, 0, 1), 8, 65535, now);
+        server.interfaces.insert(0, other);
+        let owner = client(1);
+        let ends = [
+            open(&mut server, owner, protocol::network::SOCK_DGRAM),
+            open(&mut server, owner, protocol::network::SOCK_DGRAM),
+        ];
+        socket(&mut server, ends[0]).bind(&addr(5000)).unwrap();
+        socket(&mut server, ends[1]).bind(&addr(5001)).unwrap();
+        // A socket on the wildcard address keeps both interfaces in every poll
+        let wildcard = open(&mut server, owner, protocol::network::SOCK_DGRAM);
+        let mut any = addr(5002);
+        any[4..8].fill(0);
+        socket(&mut server, wildcard).bind(&any).unwrap();
+
+        let to = sockaddr::parse(&addr(5001)).unwrap();
+        socket(&mut server, ends[0]).send_to(PAYLOAD, to).unwrap();
+        let mut received = 0;
+        for _ in 0..8 {
+            server.poll().unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok((len, _)) = socket(&mut server, ends[1]).recv_from(&mut buf, 0) {
+                assert_eq!(&buf[..len], PAYLOAD);
+                received += 1;
+            }
+        }
+        assert_eq!(received, 1);
+    }
+
+    #[test]
+    fn reused_slot_gets_a_new_generation() {
+        let mut ids = SocketIds::new();
+        let first = ids.alloc().unwrap();
+        ids.free(first);
+        assert!(ids.is_stale(first));
+        let second = ids.alloc().unwrap();
+        assert_eq!(split_id(second).0, split_id(first).0);
+        assert_ne!(second, first);
+        assert!(!ids.is_stale(second));
+        assert!(ids.is_stale(first));
+        // Never handed out, so not stale; that is NotFound's job
+        assert!(!ids.is_stale(Badge::new(1 << SOCKET_INDEX_BITS | 7)));
+    }
+
+    #[test]
+    fn generation_wraps_past_zero() {
+        let mut ids = SocketIds::new();
+        let mut badge = ids.alloc().unwrap();
+        for _ in 0..GENERATION_MASK {
+            ids.free(badge);
+            badge = ids.alloc().unwrap();
+            assert_ne!(split_id(badge).1, 0);
+        }
+        assert_eq!(split_id(badge), (0, 1));
+    }
+
+    #[test]
+    fn closed_socket_badge_is_stale() {
+        let mut server = server();
+        let owner = client(1);
+        let closed = open(&mut server, owner, protocol::network::SOCK_DGRAM);
+        socket(&mut server, closed).close().unwrap();
+        assert!(server.socket_ids.is_stale(closed));
+        let next = open(&mut server, owner, protocol::network::SOCK_DGRAM);
+        assert_eq!(split_id(next).0, split_id(closed).0);
+        assert!(!server.socket_ids.is_stale(next));
+    }
+}
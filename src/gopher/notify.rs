@@ -0,0 +1,37 @@
+use super::GopherServer;
+use super::network::GopherSocket;
+use alloc::vec::Vec;
+use glenda::ipc::Badge;
+
+impl<'a> GopherServer<'a> {
+    /// Signal each client whose socket became readable, writable or errored since the
+    /// last poll. Only newly set readiness bits are sent, so a socket that stays readable
+    /// is announced once until it drains.
+    pub fn drive_readiness(&mut self) {
+        let badges: Vec<Badge> = self
+            .socket_map
+            .iter()
+            .filter(|(_, entry)| entry.notify.is_some())
+            .map(|(badge, _)| *badge)
+            .collect();
+        for badge in badges {
+            let socket = GopherSocket { server: self, badge };
+            let Ok(ready) = socket.readiness() else {
+                continue;
+            };
+            let Some(entry) = self.socket_map.get_mut(&badge) else {
+                continue;
+            };
+            let rising = ready & !entry.notified;
+            entry.notified = ready;
+            if rising == 0 {
+                continue;
+            }
+            if let Some(ep) = &entry.notify {
+                if let Err(e) = ep.notify(rising as usize) {
+                    warn!("Failed to notify badge {}: {:?}", badge.bits(), e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Gopher-specific labels under `NETWORK_PROTO`, extending `glenda::protocol::network`.
+
+pub const IF_STATUS: usize = 0x100;
+pub const STATS: usize = 0x101;
+pub const SHUTDOWN: usize = 0x102;
+pub const SENDTO: usize = 0x103;
+pub const RECVFROM: usize = 0x104;
+pub const PING: usize = 0x105;
+pub const NEIGHBORS: usize = 0x106;
+pub const NEIGHBOR_ADD: usize = 0x107;
+pub const SETSOCKOPT: usize = 0x108;
+pub const GETSOCKOPT: usize = 0x109;
+// Admin: close every socket, wait up to mr0 ms (0 = default) for FINs, then stop
+pub const DRAIN: usize = 0x10A;
+// Hostname in the buffer, with `.domain` appended when mr0 is non-zero
+pub const GET_HOSTNAME: usize = 0x10B;
+// Admin: re-read network.json and apply what changed
+pub const RELOAD_CONFIG: usize = 0x10C;
+// Poll the socket's interface right away so queued data goes out without waiting
+pub const FLUSH: usize = 0x10D;
+// Debug builds: TCP echo and UDP datagram over loopback, answered with a JSON pass/fail
+// report naming the stage that failed
+pub const SELFTEST: usize = 0x10E;
+// TCP state, endpoints, queue lengths and retransmit stats of the calling socket as JSON
+pub const SOCK_STATE: usize = 0x10F;
+// Map the attached frame (mr0: size) as the socket's receive buffer for RECV_SHM
+pub const SETUP_RECV_SHM: usize = 0x110;
+// Receive up to mr1 bytes at offset mr0 of that buffer; the length comes back in mr0
+pub const RECV_SHM: usize = 0x111;
+// Map the attached frame (mr0: client vaddr, mr1: size) once for all of the caller's
+// sockets; mr0 of the reply is the region id. SETUP_IOURING without a frame then
+// takes the region id plus one in mr2 and places the ring inside that region
+pub const REGISTER_REGION: usize = 0x112;
+// Interface name in the buffer; the MAC travels in the low 48 bits of mr0
+pub const GET_MAC: usize = 0x113;
+pub const SET_MAC: usize = 0x114;
+// Alias of IF_STATUS, for ifconfig-style clients: name, cidrs, MAC, MTU and flags
+pub const LIST_INTERFACES: usize = 0x115;
+// Buffer names the interface, empty for all; learned ARP entries are dropped
+pub const NEIGHBOR_FLUSH: usize = 0x116;
+// mr0: port, buffer: host name or dotted IPv4 address; answered once connected
+pub const CONNECT_HOST: usize = 0x117;
+// RECVFROM that also reports where the datagram arrived: the buffer holds the sender's
+// sockaddr, then the local address it was sent to, then the data. mr0 is the data
+// length and mr1 the interface index, `usize::MAX` when it cannot be told
+pub const RECVMSG: usize = 0x118;
+// Run one poll of every interface; mr0 of the reply is how many ms until the stack next
+// needs one, `usize::MAX` when no timer is pending
+pub const PUMP: usize = 0x119;
+// TCP CONNECT whose buffer carries the sockaddr followed by data to send as soon as the
+// handshake completes; mr0 of the reply is the number of bytes taken
+pub const CONNECT_DATA: usize = 0x11A;
+// Up to mr0 datagrams (0 = as many as fit), each as a native-endian u32 length, the
+// sender's sockaddr and the data; mr0 of the reply is the count
+pub const RECVMMSG: usize = 0x11B;
+// Admin: the connection table as JSON, one entry per TCP connection or connected UDP
+// socket, including ones closed in the last 30 seconds
+pub const CONNTRACK: usize = 0x11C;
+// Buffer: sockaddr of a peer listed in `conn_pools`; mr0 of the reply is a new socket
+// already connected to it, WouldBlock while the pool has none ready
+pub const POOL_CONNECT: usize = 0x11D;
+// Like PUMP without the poll: mr0 of the reply is how many ns until the stack next needs
+// one, `usize::MAX` when no timer is pending
+pub const POLL_DELAY: usize = 0x11E;
+// mr0: a region id from REGISTER_REGION; unmaps it, InvalidState while a ring is in it
+pub const UNREGISTER_REGION: usize = 0x11F;
+// The only call `NET_ENDPOINT` answers: the reply carries a copy of the endpoint badged
+// with a new client id, which every other call goes through; mr0 is the id
+pub const HELLO: usize = 0x120;
+// mr0: one of the caller's socket ids; the reply carries a copy of the caller's endpoint
+// badged with it, for the calls about that socket
+pub const SOCKET_ENDPOINT: usize = 0x121;
+
+// Interface flags reported by IF_STATUS
+pub const IFF_UP: u32 = 1 << 0;
+pub const IFF_LOOPBACK: u32 = 1 << 1;
+
+// RECV/RECVFROM/RECVMSG flag: copy data out but leave it queued for the next read
+pub const MSG_PEEK: i32 = 0x2;
+// SEND/RECV flag: return WouldBlock even on a blocking socket
+pub const MSG_DONTWAIT: i32 = 0x40;
+// RECV flag on a blocking stream: park until the whole buffer can be filled, or EOF
+pub const MSG_WAITALL: i32 = 0x100;
+// SEND flag: queue the whole buffer or nothing, MessageTooLong if it can never fit
+pub const MSG_ATOMIC: i32 = 0x10000;
+// SEND flag on TCP: more follows, so hold this back like TCP_CORK until a send without it
+pub const MSG_MORE: i32 = 0x8000;
+
+// `how` argument of SHUTDOWN
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+// io_uring opcodes handled by Gopher on top of `glenda::io::uring`
+pub const IOURING_OP_ACCEPT: u8 = 0x40;
+pub const IOURING_OP_CONNECT: u8 = 0x41;
+pub const IOURING_OP_POLL_ADD: u8 = 0x42;
+pub const IOURING_OP_TIMEOUT: u8 = 0x43;
+// `sqe.addr` points at `sqe.len` `{ base: u64, len: u64 }` iovecs in the ring buffer
+pub const IOURING_OP_WRITEV: u8 = 0x44;
+pub const IOV_MAX: usize = 64;
+// Close the socket; parked ops on it complete with ConnectionAborted first
+pub const IOURING_OP_CLOSE: u8 = 0x45;
+// Shut down one or both directions, `how` in `sqe.len`
+pub const IOURING_OP_SHUTDOWN: u8 = 0x46;
+// Cancel the parked op whose user_data is in `sqe.addr`; it completes with
+// ConnectionAborted, the cancel itself with 1 or NotFound when nothing matched
+pub const IOURING_OP_ASYNC_CANCEL: u8 = 0x47;
+// IOURING_OP_ASYNC_CANCEL flag in `sqe.len`: cancel every op parked on the socket and
+// complete with how many there were
+pub const ASYNC_CANCEL_ALL: u32 = 1 << 0;
+
+// Readiness bits for IOURING_OP_POLL_ADD, passed in `sqe.len` and returned in the CQE.
+// A notify endpoint attached to SOCKET is signalled with the same bits
+pub const POLLIN: u32 = 1 << 0;
+pub const POLLOUT: u32 = 1 << 2;
+pub const POLLERR: u32 = 1 << 3;
+pub const POLLHUP: u32 = 1 << 4;
+// Edge-triggered: only complete on a readiness bit that was not set at registration
+pub const POLL_EDGE: u32 = 1 << 31;
+
+// IOURING_OP_TIMEOUT takes a relative timeout in nanoseconds in `sqe.addr`; with this
+// bit set in `sqe.len` it also cancels the SQE submitted right before it
+pub const TIMEOUT_LINK: u32 = 1 << 0;
+
+// Socket option levels and names
+pub const SOL_SOCKET: i32 = 1;
+pub const IPPROTO_IP: i32 = 0;
+pub const IPPROTO_TCP: i32 = 6;
+pub const SO_REUSEADDR: i32 = 2;
+pub const SO_ERROR: i32 = 4;
+pub const SO_BROADCAST: i32 = 6;
+pub const SO_KEEPALIVE: i32 = 9;
+// optval is an interface name; empty unpins the socket again
+pub const SO_BINDTODEVICE: i32 = 25;
+// optval is a `struct linger { l_onoff: i32, l_linger: i32 }`, seconds in `l_linger`
+pub const SO_LINGER: i32 = 13;
+// Idle time in seconds before keepalive probes start
+pub const TCP_KEEPIDLE: i32 = 4;
+pub const DEFAULT_KEEPIDLE_SECS: u32 = 7200;
+// Milliseconds before an idle or unacknowledged connection is dropped, 0 disables
+pub const TCP_USER_TIMEOUT: i32 = 18;
+// optval is the algorithm name: "none", "reno" or "cubic"
+pub const TCP_CONGESTION: i32 = 13;
+// Non-zero turns Nagle off: small segments go out even while earlier ones are unacked
+pub const TCP_NODELAY: i32 = 1;
+// Non-zero holds back partial segments until it is cleared, a full segment has built up
+// or `CORK_TIMEOUT_MS` passes. Corked data never reaches smoltcp, so this applies with
+// or without TCP_NODELAY; clearing it sends what is held right away
+pub const TCP_CORK: i32 = 3;
+pub const CORK_TIMEOUT_MS: u64 = 200;
+// Non-zero (the default) returns WouldBlock, zero parks SEND/RECV until ready
+pub const SO_NONBLOCK: i32 = 0x4000;
+// getsockopt only: bytes SEND would queue right now, within SO_SNDHIWAT if one is set
+pub const SO_SNDSPACE: i32 = 0x4001;
+// Stop taking writes once this many bytes are queued; 0 (the default) allows the whole
+// TX buffer
+pub const SO_SNDHIWAT: i32 = 0x4002;
+pub const IP_TTL: i32 = 2;
+// optval is a `struct ip_mreq { imr_multiaddr, imr_interface }`
+pub const IP_ADD_MEMBERSHIP: i32 = 35;
+pub const IP_DROP_MEMBERSHIP: i32 = 36;
+pub const DEFAULT_TTL: u8 = 64;
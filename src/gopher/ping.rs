@@ -0,0 +1,115 @@
+use super::GopherServer;
+use glenda::cap::CapPtr;
+use glenda::error::Error;
+use glenda::ipc::Badge;
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::icmp;
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, Ipv4Address};
+
+const PING_PAYLOAD: &[u8] = b"gopher-ping";
+
+/// An echo request in flight, answered through the caller's deferred reply.
+pub struct PendingPing {
+    pub handle: SocketHandle,
+    pub target: Ipv4Address,
+    pub ident: u16,
+    pub sent_at: Option<u64>, // Monotonic ns
+    pub deadline_ns: u64,
+    pub reply: CapPtr,
+}
+
+impl<'a> GopherServer<'a> {
+    /// Start pinging `target`; the caller is answered from `poll()` with the RTT in
+    /// microseconds or `Error::Timeout`.
+    pub fn ping(
+        &mut self,
+        badge: Badge,
+        target: Ipv4Address,
+        timeout_ms: u64,
+    ) -> Result<(), Error> {
+        if self.pending_pings.contains_key(&badge) {
+            return Err(Error::InvalidArgs);
+        }
+        let ident = self.next_ping_ident;
+        self.next_ping_ident = self.next_ping_ident.wrapping_add(1);
+
+        let rx_buffer = icmp::PacketBuffer::new(
+            alloc::vec![icmp::PacketMetadata::EMPTY; 4],
+            alloc::vec![0; 256],
+        );
+        let tx_buffer = icmp::PacketBuffer::new(
+            alloc::vec![icmp::PacketMetadata::EMPTY; 4],
+            alloc::vec![0; 256],
+        );
+        let mut socket = icmp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(icmp::Endpoint::Ident(ident)).map_err(|_| Error::InvalidArgs)?;
+        let handle = self.sockets.add(socket);
+
+        let reply = match self.defer_reply() {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.sockets.remove(handle);
+                return Err(e);
+            }
+        };
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let deadline_ns = now.saturating_add(timeout_ms.saturating_mul(1_000_000));
+        let mut ping = PendingPing { handle, target, ident, sent_at: None, deadline_ns, reply };
+        // Queued now so the poll the run loop does next sends it; `drive_pings` runs
+        // after the interfaces were polled and would leave it sitting until the deadline
+        queue_echo(self.sockets.get_mut::<icmp::Socket>(handle), &mut ping, now);
+        self.pending_pings.insert(badge, ping);
+        self.arm_timer(deadline_ns);
+        Ok(())
+    }
+
+    /// Send outstanding echo requests, match replies and expire timed out pings.
+    pub fn drive_pings(&mut self) {
+        if self.pending_pings.is_empty() {
+            return;
+        }
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let caps = ChecksumCapabilities::default();
+        let mut done = alloc::vec::Vec::new();
+
+        for (badge, ping) in self.pending_pings.iter_mut() {
+            let socket = self.sockets.get_mut::<icmp::Socket>(ping.handle);
+            queue_echo(socket, ping, now);
+            while let Ok((payload, _)) = socket.recv() {
+                let Ok(packet) = Icmpv4Packet::new_checked(payload) else {
+                    continue;
+                };
+                if let Ok(Icmpv4Repr::EchoReply { ident, .. }) = Icmpv4Repr::parse(&packet, &caps) {
+                    if ident == ping.ident {
+                        let rtt_us = now.saturating_sub(ping.sent_at.unwrap_or(now)) / 1000;
+                        done.push((*badge, Ok(rtt_us as usize)));
+                        break;
+                    }
+                }
+            }
+            if now >= ping.deadline_ns && !done.iter().any(|(b, _)| b == badge) {
+                done.push((*badge, Err(Error::Timeout)));
+            }
+        }
+
+        for (badge, result) in done {
+            if let Some(ping) = self.pending_pings.remove(&badge) {
+                self.sockets.remove(ping.handle);
+                self.finish_reply(ping.reply, result, &[]);
+            }
+        }
+    }
+}
+
+/// Put the echo request of `ping` in its socket's TX buffer unless it already went out.
+fn queue_echo(socket: &mut icmp::Socket, ping: &mut PendingPing, now: u64) {
+    if ping.sent_at.is_some() || !socket.can_send() {
+        return;
+    }
+    let repr = Icmpv4Repr::EchoRequest { ident: ping.ident, seq_no: 0, data: PING_PAYLOAD };
+    if let Ok(buf) = socket.send(repr.buffer_len(), IpAddress::Ipv4(ping.target)) {
+        repr.emit(&mut Icmpv4Packet::new_unchecked(buf), &ChecksumCapabilities::default());
+        ping.sent_at = Some(now);
+    }
+}
@@ -0,0 +1,181 @@
+use super::GopherServer;
+use super::network::{SocketEntry, SocketKind};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::ipc::Badge;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint};
+
+// How long a pooled connection may take to complete its handshake
+const POOL_CONNECT_TIMEOUT_NS: u64 = 10_000_000_000;
+
+/// Pre-established TCP connections to one configured peer. The sockets belong to no
+/// client until POOL_CONNECT hands one out, so they are not in `socket_map`.
+pub struct ConnPool {
+    pub size: usize,
+    pub idle_timeout_ns: u64,
+    // Last POOL_CONNECT for this peer, or when the pool was configured (mono ns)
+    pub last_demand_ns: u64,
+    // Each socket with when it started connecting and, once up, when it was established
+    pub conns: Vec<PooledConn>,
+}
+
+pub struct PooledConn {
+    pub handle: SocketHandle,
+    pub opened_ns: u64,
+    pub established_ns: Option<u64>,
+}
+
+impl<'a> GopherServer<'a> {
+    /// Bring the pools in line with `conn_pools` in the config. Pools no longer listed
+    /// are closed; the others are filled by `drive_pools`.
+    pub fn sync_pools(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let wanted: BTreeMap<IpEndpoint, (usize, u64)> = self
+            .config
+            .as_ref()
+            .map(|c| {
+                c.conn_pools
+                    .iter()
+                    .filter_map(|p| Some((p.endpoint()?, (p.size, p.idle_timeout_ms * 1_000_000))))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let dropped: Vec<IpEndpoint> =
+            self.pools.keys().filter(|peer| !wanted.contains_key(peer)).copied().collect();
+        for peer in dropped {
+            if let Some(pool) = self.pools.remove(&peer) {
+                log!("Closing connection pool to {}", peer);
+                self.close_pooled(pool.conns);
+            }
+        }
+        for (peer, (size, idle_timeout_ns)) in wanted {
+            let pool = self.pools.entry(peer).or_insert_with(|| ConnPool {
+                size,
+                idle_timeout_ns,
+                last_demand_ns: now,
+                conns: Vec::new(),
+            });
+            pool.size = size;
+            pool.idle_timeout_ns = idle_timeout_ns;
+        }
+    }
+
+    /// Close every pooled connection, for shutdown.
+    pub fn close_pools(&mut self) {
+        for (_, pool) in core::mem::take(&mut self.pools) {
+            self.close_pooled(pool.conns);
+        }
+    }
+
+    // The orphan sweep in `reap_closed` frees the sockets once they are closed
+    fn close_pooled(&mut self, conns: Vec<PooledConn>) {
+        for conn in conns {
+            self.sockets.get_mut::<tcp::Socket>(conn.handle).close();
+        }
+    }
+
+    /// Drop pooled connections that failed, were closed by the peer or sat idle past
+    /// the pool's timeout, then top up every pool asked for within that timeout. Runs
+    /// before `reap_closed`, which would otherwise free sockets the pools still list.
+    pub fn drive_pools(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let peers: Vec<IpEndpoint> = self.pools.keys().copied().collect();
+        for peer in peers {
+            let Some(pool) = self.pools.get_mut(&peer) else {
+                continue;
+            };
+            let idle_timeout_ns = pool.idle_timeout_ns;
+            let sockets = &mut self.sockets;
+            pool.conns.retain_mut(|conn| {
+                let socket = sockets.get_mut::<tcp::Socket>(conn.handle);
+                match socket.state() {
+                    tcp::State::Established => {
+                        let since = *conn.established_ns.get_or_insert(now);
+                        if now.saturating_sub(since) < idle_timeout_ns {
+                            return true;
+                        }
+                        socket.close();
+                    }
+                    tcp::State::SynSent | tcp::State::SynReceived
+                        if now.saturating_sub(conn.opened_ns) < POOL_CONNECT_TIMEOUT_NS =>
+                    {
+                        return true;
+                    }
+                    tcp::State::Closed => {}
+                    _ => socket.abort(),
+                }
+                false
+            });
+            if now.saturating_sub(pool.last_demand_ns) >= idle_timeout_ns {
+                continue;
+            }
+            let missing = pool.size.saturating_sub(pool.conns.len());
+            for _ in 0..missing {
+                match self.open_pooled(peer) {
+                    Ok(handle) => {
+                        let conn = PooledConn { handle, opened_ns: now, established_ns: None };
+                        if let Some(pool) = self.pools.get_mut(&peer) {
+                            pool.conns.push(conn);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Cannot fill connection pool to {}: {:?}", peer, e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn open_pooled(&mut self, peer: IpEndpoint) -> Result<SocketHandle, Error> {
+        let idx = self.route_interface(peer.addr).ok_or(Error::NetworkUnreachable)?;
+        let addr = self.source_address(idx, peer.addr).map(IpAddress::Ipv4);
+        let local = IpListenEndpoint { addr, port: self.alloc_port() };
+        let handle = self.new_tcp_socket();
+        let ctx = &mut self.interfaces[idx];
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if let Err(e) = socket.connect(ctx.iface.context(), peer, local) {
+            self.sockets.remove(handle);
+            return Err(match e {
+                tcp::ConnectError::InvalidState => Error::InvalidArgs,
+                tcp::ConnectError::Unaddressable => Error::AddrNotAvailable,
+            });
+        }
+        ctx.dirty = true;
+        Ok(handle)
+    }
+
+    /// POOL_CONNECT: hand `owner` an established connection to `peer` from its pool
+    /// as a new socket. `WouldBlock` when none is ready yet; the pool refills on the
+    /// next poll either way.
+    pub fn take_pooled(&mut self, owner: Badge, peer: IpEndpoint) -> Result<usize, Error> {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let pool = self.pools.get_mut(&peer).ok_or(Error::NotFound)?;
+        pool.last_demand_ns = now;
+        let sockets = &self.sockets;
+        let pos = pool
+            .conns
+            .iter()
+            .position(|conn| {
+                sockets.get::<tcp::Socket>(conn.handle).state() == tcp::State::Established
+            })
+            .ok_or(Error::WouldBlock)?;
+        self.check_socket_limit(owner)?;
+        let pool = self.pools.get_mut(&peer).ok_or(Error::NotFound)?;
+        let conn = pool.conns.remove(pos);
+        let socket = self.sockets.get::<tcp::Socket>(conn.handle);
+        let mut entry = SocketEntry::new(conn.handle, SocketKind::Tcp);
+        let local = socket.local_endpoint();
+        entry.remote = socket.remote_endpoint();
+        entry.timeout_ms = self.default_tcp_timeout();
+        entry.owner = owner;
+        entry.established = true;
+        entry.iface = local.and_then(|local| self.interface_for_addr(local.addr));
+        let badge = self.register_socket(entry)?;
+        self.set_local(badge, local);
+        Ok(badge.bits())
+    }
+}
@@ -0,0 +1,106 @@
+use super::GopherServer;
+use alloc::vec::Vec;
+use glenda::cap::{CSPACE_CAP, CapPtr, Reply};
+use glenda::error::Error;
+use glenda::interface::CSpaceService;
+use glenda::ipc::{MsgTag, UTCB};
+
+/// Reply capabilities moved out of the IPC reply slot so a call can be answered
+/// later from `poll()`. Slots are recycled once the reply has been sent, along with
+/// slots of received caps that were given back after a failed setup.
+pub struct DeferredReplies {
+    free: Vec<CapPtr>,
+}
+
+impl DeferredReplies {
+    pub const fn new() -> Self {
+        Self { free: Vec::new() }
+    }
+}
+
+impl<'a> GopherServer<'a> {
+    /// Take ownership of the reply capability of the call being dispatched. The
+    /// handler must then return `Err(Error::Success)` so the run loop skips its reply.
+    pub fn defer_reply(&mut self) -> Result<CapPtr, Error> {
+        self.take_cap(self.ipc.reply.cap())
+    }
+
+    /// Move the capability attached to the call being dispatched into a slot of our own.
+    pub fn take_recv_cap(&mut self) -> Result<CapPtr, Error> {
+        self.take_cap(glenda::cap::RECV_SLOT)
+    }
+
+    fn take_cap(&mut self, src: CapPtr) -> Result<CapPtr, Error> {
+        let slot = match self.deferred.free.pop() {
+            Some(slot) => slot,
+            None => self.cspace.alloc(self.res_client)?,
+        };
+        if let Err(e) = CSPACE_CAP.transfer_self(src, slot) {
+            self.deferred.free.push(slot);
+            return Err(e);
+        }
+        Ok(slot)
+    }
+
+    /// Drop whatever `slot` holds and keep the slot for the next cap we take.
+    pub fn release_slot(&mut self, slot: CapPtr) {
+        let _ = CSPACE_CAP.delete(slot);
+        self.deferred.free.push(slot);
+    }
+
+    /// Answer a deferred call with `mr0 = value` and `payload` in the buffer on
+    /// success, or an error tag otherwise.
+    pub fn finish_reply(&mut self, slot: CapPtr, result: Result<usize, Error>, payload: &[u8]) {
+        let mut utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        match result {
+            Ok(value) => {
+                utcb.set_msg_tag(MsgTag::ok());
+                utcb.set_mr(0, value);
+                if !payload.is_empty() {
+                    utcb.buffer_mut()[..payload.len()].copy_from_slice(payload);
+                    utcb.set_size(payload.len());
+                }
+            }
+            Err(e) => {
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, e as usize);
+            }
+        }
+        self.send_deferred(slot, &mut utcb);
+    }
+
+    /// Answer a deferred call whose payload `fill` writes straight into the reply buffer,
+    /// returning the payload length. Nothing is sent and `false` comes back if `fill`
+    /// would block, so the call can stay parked.
+    pub fn try_finish_reply(
+        &mut self,
+        slot: CapPtr,
+        fill: impl FnOnce(&mut Self, &mut [u8]) -> Result<usize, Error>,
+    ) -> bool {
+        let mut utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        match fill(self, utcb.buffer_mut()) {
+            Err(Error::WouldBlock) => return false,
+            Ok(len) => {
+                utcb.set_msg_tag(MsgTag::ok());
+                utcb.set_mr(0, len);
+                utcb.set_size(len);
+            }
+            Err(e) => {
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, e as usize);
+            }
+        }
+        self.send_deferred(slot, &mut utcb);
+        true
+    }
+
+    fn send_deferred(&mut self, slot: CapPtr, utcb: &mut UTCB) {
+        if let Err(e) = Reply::from(slot).reply(utcb) {
+            warn!("Deferred reply failed: {:?}", e);
+            let _ = CSPACE_CAP.delete(slot);
+        }
+        self.deferred.free.push(slot);
+    }
+}
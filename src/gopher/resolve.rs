@@ -0,0 +1,243 @@
+use super::GopherServer;
+use super::network::{GopherSocket, SocketKind};
+use super::sockaddr;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use glenda::cap::CapPtr;
+use glenda::error::Error;
+use glenda::interface::SocketService;
+use glenda::ipc::Badge;
+use smoltcp::socket::{dns, tcp};
+use smoltcp::wire::{DnsQueryType, IpAddress, IpEndpoint, Ipv4Address};
+
+/// Matches the `dns-max-server-count-*` feature smoltcp is built with.
+pub const DNS_MAX_SERVERS: usize = 4;
+// How long a name may take to resolve, and each of its addresses to finish the handshake
+const RESOLVE_TIMEOUT_NS: u64 = 5_000_000_000;
+const ATTEMPT_TIMEOUT_NS: u64 = 2_000_000_000;
+// Wakeup interval while a query is outstanding, so smoltcp can retransmit it
+const RESOLVE_TICK_NS: u64 = 250_000_000;
+
+/// A CONNECT_HOST in flight: resolving the name, then trying its addresses in order.
+/// The caller is answered through its deferred reply once one of them connects.
+pub struct HostConnect {
+    pub reply: CapPtr,
+    pub port: u16,
+    // Outstanding DNS query; `None` once the addresses are known
+    pub query: Option<dns::QueryHandle>,
+    // Addresses not tried yet
+    pub addrs: VecDeque<Ipv4Address>,
+    // Deadline of the query or of the handshake in progress (mono ns)
+    pub deadline_ns: u64,
+    // Binding of the socket before the first attempt, restored for each retry
+    pub local: Option<IpEndpoint>,
+    pub iface: Option<usize>,
+    // Why the last address failed, reported if none is left
+    pub last_error: Error,
+}
+
+impl<'a> GopherServer<'a> {
+    /// Resolve `host` (a name, or an IPv4 address in dotted form) and connect the TCP
+    /// socket `badge` to `port` on the first of its addresses that answers. The caller
+    /// is answered from `poll()` once the handshake completes or every address failed.
+    pub fn connect_host(&mut self, badge: Badge, host: &str, port: u16) -> Result<(), Error> {
+        let entry = self.socket_map.get(&badge).ok_or(Error::NotFound)?;
+        if entry.kind != SocketKind::Tcp || port == 0 || self.host_connects.contains_key(&badge) {
+            return Err(Error::InvalidArgs);
+        }
+        if entry.listening || entry.connecting.is_some() || entry.remote.is_some() {
+            return Err(Error::InvalidState);
+        }
+        let (local, iface) = (entry.local, entry.iface);
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let (query, addrs) = match host.parse::<Ipv4Address>() {
+            Ok(addr) => (None, VecDeque::from([addr])),
+            Err(_) => (Some(self.start_query(host)?), VecDeque::new()),
+        };
+        let reply = match self.defer_reply() {
+            Ok(reply) => reply,
+            Err(e) => {
+                if let (Some(query), Some(handle)) = (query, self.dns_handle) {
+                    self.sockets.get_mut::<dns::Socket>(handle).cancel_query(query);
+                }
+                return Err(e);
+            }
+        };
+        let mut hc = HostConnect {
+            reply,
+            port,
+            query,
+            addrs,
+            deadline_ns: now.saturating_add(RESOLVE_TIMEOUT_NS),
+            local,
+            iface,
+            last_error: Error::NotFound,
+        };
+        let wakeup = match hc.query {
+            Some(_) => now.saturating_add(RESOLVE_TICK_NS).min(hc.deadline_ns),
+            // Nothing to resolve, so the handshake starts right away
+            None => match self.next_attempt(badge, &mut hc, now) {
+                Err(Error::WouldBlock) => hc.deadline_ns,
+                res => {
+                    self.finish_reply(hc.reply, res.map(|_| 0), &[]);
+                    return Ok(());
+                }
+            },
+        };
+        self.arm_timer(wakeup);
+        self.host_connects.insert(badge, hc);
+        Ok(())
+    }
+
+    /// Start an A query for `name` on the shared DNS socket, creating it on first use.
+    fn start_query(&mut self, name: &str) -> Result<dns::QueryHandle, Error> {
+        let servers = self.dns_servers();
+        let server = *servers.first().ok_or(Error::NotSupported)?;
+        let idx = self.route_interface(server).ok_or(Error::NetworkUnreachable)?;
+        let handle = match self.dns_handle {
+            Some(handle) => handle,
+            None => {
+                let handle = self.sockets.add(dns::Socket::new(&servers, Vec::new()));
+                self.dns_handle = Some(handle);
+                handle
+            }
+        };
+        let ctx = &mut self.interfaces[idx];
+        let socket = self.sockets.get_mut::<dns::Socket>(handle);
+        let query = socket.start_query(ctx.iface.context(), name, DnsQueryType::A).map_err(
+            |e| match e {
+                dns::StartQueryError::NoFreeSlot => Error::Exhausted,
+                dns::StartQueryError::InvalidName | dns::StartQueryError::NameTooLong => {
+                    Error::InvalidArgs
+                }
+            },
+        )?;
+        ctx.dirty = true;
+        Ok(query)
+    }
+
+    /// Configured name servers that parse, at most as many as smoltcp can hold.
+    pub fn dns_servers(&self) -> Vec<IpAddress> {
+        let Some(config) = self.config.as_ref() else {
+            return Vec::new();
+        };
+        config
+            .dns_servers
+            .iter()
+            .filter_map(|server| match server.parse::<Ipv4Address>() {
+                Ok(addr) => Some(IpAddress::Ipv4(addr)),
+                Err(_) => {
+                    warn!("Ignoring invalid DNS server {}", server);
+                    None
+                }
+            })
+            .take(DNS_MAX_SERVERS)
+            .collect()
+    }
+
+    /// Point the DNS socket at the configured servers after a config reload.
+    pub fn update_dns_servers(&mut self) {
+        let servers = self.dns_servers();
+        if let Some(handle) = self.dns_handle {
+            self.sockets.get_mut::<dns::Socket>(handle).update_servers(&servers);
+        }
+    }
+
+    /// Move every CONNECT_HOST along: collect finished queries, start the next address
+    /// when a handshake fails or takes too long, and answer the ones that are done.
+    pub fn drive_host_connects(&mut self) {
+        if self.host_connects.is_empty() {
+            return;
+        }
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let badges: Vec<Badge> = self.host_connects.keys().copied().collect();
+        for badge in badges {
+            let Some(mut hc) = self.host_connects.remove(&badge) else {
+                continue;
+            };
+            let res = match hc.query {
+                Some(query) => self.poll_query(badge, &mut hc, query, now),
+                None => self.poll_attempt(badge, &mut hc, now),
+            };
+            match res {
+                Err(Error::WouldBlock) => {
+                    let wakeup = match hc.query {
+                        Some(_) => now.saturating_add(RESOLVE_TICK_NS).min(hc.deadline_ns),
+                        None => hc.deadline_ns,
+                    };
+                    self.arm_timer(wakeup);
+                    self.host_connects.insert(badge, hc);
+                }
+                res => self.finish_reply(hc.reply, res.map(|_| 0), &[]),
+            }
+        }
+    }
+
+    /// Collect the answer to `query`, then start on the first address it returned.
+    fn poll_query(
+        &mut self,
+        badge: Badge,
+        hc: &mut HostConnect,
+        query: dns::QueryHandle,
+        now: u64,
+    ) -> Result<(), Error> {
+        let handle = self.dns_handle.ok_or(Error::NotInitialized)?;
+        let socket = self.sockets.get_mut::<dns::Socket>(handle);
+        match socket.get_query_result(query) {
+            Ok(addrs) => {
+                hc.query = None;
+                hc.addrs = addrs
+                    .iter()
+                    .map(|addr| {
+                        let IpAddress::Ipv4(addr) = *addr;
+                        addr
+                    })
+                    .collect();
+                self.next_attempt(badge, hc, now)
+            }
+            Err(dns::GetQueryResultError::Pending) if now >= hc.deadline_ns => {
+                socket.cancel_query(query);
+                Err(Error::Timeout)
+            }
+            Err(dns::GetQueryResultError::Pending) => Err(Error::WouldBlock),
+            Err(dns::GetQueryResultError::Failed) => Err(Error::NotFound),
+        }
+    }
+
+    /// Check the handshake in progress, moving on to the next address if it failed.
+    fn poll_attempt(&mut self, badge: Badge, hc: &mut HostConnect, now: u64) -> Result<(), Error> {
+        match (GopherSocket { server: self, badge }).connect_result() {
+            Err(Error::WouldBlock) if now < hc.deadline_ns => return Err(Error::WouldBlock),
+            Err(Error::WouldBlock) => hc.last_error = Error::Timeout,
+            Err(e) => hc.last_error = e,
+            Ok(()) => return Ok(()),
+        }
+        self.next_attempt(badge, hc, now)
+    }
+
+    /// Reset the socket and connect it to the next untried address. `WouldBlock` means
+    /// a handshake is under way; anything else is the final answer.
+    fn next_attempt(&mut self, badge: Badge, hc: &mut HostConnect, now: u64) -> Result<(), Error> {
+        while let Some(addr) = hc.addrs.pop_front() {
+            let entry = self.socket_map.get_mut(&badge).ok_or(Error::NotFound)?;
+            self.sockets.get_mut::<tcp::Socket>(entry.handle).abort();
+            entry.iface = hc.iface;
+            entry.remote = None;
+            entry.connecting = None;
+            entry.so_error = None;
+            self.set_local(badge, hc.local);
+
+            let mut buf = [0u8; sockaddr::SOCKADDR_IN_LEN];
+            sockaddr::write(IpEndpoint::new(IpAddress::Ipv4(addr), hc.port), &mut buf)?;
+            match (GopherSocket { server: self, badge }).connect(&buf) {
+                Ok(()) => {
+                    log!("Connecting socket {} to {}:{}", badge.bits(), addr, hc.port);
+                    hc.deadline_ns = now.saturating_add(ATTEMPT_TIMEOUT_NS);
+                    return Err(Error::WouldBlock);
+                }
+                Err(e) => hc.last_error = e,
+            }
+        }
+        Err(hc.last_error)
+    }
+}
@@ -0,0 +1,353 @@
+use super::GopherServer;
+use super::stack::{DeviceVariant, RxVariant, TxVariant};
+use super::stats::TcpSenderStats;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::cell::RefCell;
+use smoltcp::phy::{Device, DeviceCapabilities, RxToken, TxToken};
+use smoltcp::socket::{Socket, tcp};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpAddress, IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet,
+    TcpPacket, TcpSeqNumber,
+};
+
+// Bounds smoltcp's RTT estimator keeps the RTO within
+const MIN_RTO_MS: u64 = 1000;
+const MAX_RTO_MS: u64 = 60_000;
+// Fragmented datagrams followed at once per interface; ones past this go uncounted
+const MAX_TRACKED_DATAGRAMS: usize = 32;
+
+/// What the wire shows of one TCP connection's sender. smoltcp keeps its retransmit
+/// timer to itself, so retransmissions are counted as segments that do not advance
+/// the highest sequence sent, and the RTO is derived from ACK round trips the way
+/// RFC 6298 (and smoltcp) computes it.
+#[derive(Debug, Default)]
+pub struct SegmentTrack {
+    // End of the highest sequence sent so far
+    snd_max: Option<TcpSeqNumber>,
+    pub retransmits: u64,
+    // End of the segment being timed for an RTT sample, and when it went out
+    timing: Option<(TcpSeqNumber, Instant)>,
+    srtt_us: Option<u64>,
+    rttvar_us: u64,
+}
+
+impl SegmentTrack {
+    fn sent(&mut self, end: TcpSeqNumber, keepalive: bool, now: Instant) {
+        match self.snd_max {
+            // A keep-alive probe resends the last byte on purpose
+            Some(max) if end <= max && !keepalive => {
+                self.retransmits += 1;
+                // Karn: the ACK of a resent segment is no round-trip sample
+                self.timing = None;
+            }
+            Some(max) if end <= max => {}
+            _ => {
+                self.snd_max = Some(end);
+                self.timing.get_or_insert((end, now));
+            }
+        }
+    }
+
+    fn acked(&mut self, ack: TcpSeqNumber, now: Instant) {
+        let Some((end, sent)) = self.timing else {
+            return;
+        };
+        if ack < end {
+            return;
+        }
+        self.timing = None;
+        let sample = (now - sent).total_micros();
+        match self.srtt_us {
+            None => {
+                self.srtt_us = Some(sample);
+                self.rttvar_us = sample / 2;
+            }
+            Some(srtt) => {
+                self.rttvar_us = (3 * self.rttvar_us + srtt.abs_diff(sample)) / 4;
+                self.srtt_us = Some((7 * srtt + sample) / 8);
+            }
+        }
+    }
+
+    /// Smoothed round trip, once a segment has been acknowledged.
+    pub fn srtt_ms(&self) -> Option<u64> {
+        self.srtt_us.map(|srtt| srtt / 1000)
+    }
+
+    /// Retransmission timeout the estimate gives; none before the first sample.
+    pub fn rto_ms(&self) -> Option<u64> {
+        let srtt = self.srtt_us?;
+        Some(((srtt + 4 * self.rttvar_us) / 1000).clamp(MIN_RTO_MS, MAX_RTO_MS))
+    }
+}
+
+/// Sender tracks keyed by (local, remote) endpoint of the connection.
+#[derive(Debug, Default)]
+pub struct SegmentLog {
+    flows: BTreeMap<(IpEndpoint, IpEndpoint), SegmentTrack>,
+    // Connections, as (local, remote), a reset arrived for since `take_resets`
+    resets: BTreeSet<(IpEndpoint, IpEndpoint)>,
+}
+
+impl SegmentLog {
+    pub fn take_resets(&mut self) -> BTreeSet<(IpEndpoint, IpEndpoint)> {
+        core::mem::take(&mut self.resets)
+    }
+
+    pub fn get(&self, local: IpEndpoint, remote: IpEndpoint) -> Option<&SegmentTrack> {
+        self.flows.get(&(local, remote))
+    }
+
+    fn on_tx(&mut self, frame: &[u8], ip_medium: bool, now: Instant) {
+        let Some((src, dst, segment)) = tcp_segment(frame, ip_medium) else {
+            return;
+        };
+        let payload = segment.payload().len();
+        let len = payload + segment.syn() as usize + segment.fin() as usize;
+        if len == 0 || segment.rst() {
+            return;
+        }
+        let keepalive = payload == 1 && !segment.syn() && !segment.fin();
+        let track = self.flows.entry((src, dst)).or_default();
+        track.sent(segment.seq_number() + len, keepalive, now);
+    }
+
+    fn on_rx(&mut self, frame: &[u8], ip_medium: bool, now: Instant) {
+        let Some((src, dst, segment)) = tcp_segment(frame, ip_medium) else {
+            return;
+        };
+        if segment.rst() {
+            self.resets.insert((dst, src));
+        }
+        if let Some(track) = self.flows.get_mut(&(dst, src))
+            && segment.ack()
+        {
+            track.acked(segment.ack_number(), now);
+        }
+    }
+}
+
+struct PartialDatagram {
+    first_seen: Instant,
+    // Payload bytes of the fragments seen so far
+    received: usize,
+    // Length of the whole datagram, known once its last fragment arrived
+    total: Option<usize>,
+}
+
+/// Outcome of the fragmented IPv4 datagrams an interface received. smoltcp keeps no
+/// count of the reassemblies it abandons, so the fragments are followed here as they
+/// pass: a datagram whose bytes all arrived counts as completed (or oversize, when it
+/// is larger than smoltcp's buffer and so was dropped), one still missing pieces after
+/// the reassembly timeout as timed out.
+#[derive(Default)]
+pub struct FragmentWatch {
+    pending: BTreeMap<(Ipv4Address, Ipv4Address, u16), PartialDatagram>,
+    pub completed: u64,
+    pub timed_out: u64,
+    pub oversize: u64,
+}
+
+impl FragmentWatch {
+    fn on_rx(&mut self, frame: &[u8], ip_medium: bool, now: Instant) {
+        let Some(packet) = ipv4_packet(frame, ip_medium) else {
+            return;
+        };
+        if !packet.more_frags() && packet.frag_offset() == 0 {
+            return;
+        }
+        let key = (packet.src_addr(), packet.dst_addr(), packet.ident());
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_TRACKED_DATAGRAMS {
+            return;
+        }
+        let len = (packet.total_len() - packet.header_len() as u16) as usize;
+        let datagram = self.pending.entry(key).or_insert(PartialDatagram {
+            first_seen: now,
+            received: 0,
+            total: None,
+        });
+        datagram.received += len;
+        if !packet.more_frags() {
+            datagram.total = Some(packet.frag_offset() as usize + len);
+        }
+        if let Some(total) = datagram.total
+            && datagram.received >= total
+        {
+            self.pending.remove(&key);
+            if total > super::REASSEMBLY_BUFFER_SIZE {
+                self.oversize += 1;
+            } else {
+                self.completed += 1;
+            }
+        }
+    }
+
+    /// Count the datagrams still incomplete after `timeout` as timed out.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) {
+        let before = self.pending.len();
+        self.pending.retain(|_, datagram| now - datagram.first_seen < timeout);
+        self.timed_out += (before - self.pending.len()) as u64;
+    }
+}
+
+/// The IPv4 packet in `frame`, if it carries one. Loopback frames start at the IP
+/// header, the rest at Ethernet.
+fn ipv4_packet(frame: &[u8], ip_medium: bool) -> Option<Ipv4Packet<&[u8]>> {
+    let ip = if ip_medium {
+        frame
+    } else {
+        let eth = EthernetFrame::new_checked(frame).ok()?;
+        if eth.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+        &frame[EthernetFrame::<&[u8]>::header_len()..]
+    };
+    Ipv4Packet::new_checked(ip).ok()
+}
+
+/// Source and destination of an unfragmented IPv4 TCP segment in `frame`, with the
+/// segment itself.
+fn tcp_segment(
+    frame: &[u8],
+    ip_medium: bool,
+) -> Option<(IpEndpoint, IpEndpoint, TcpPacket<&[u8]>)> {
+    let packet = ipv4_packet(frame, ip_medium)?;
+    if packet.next_header() != IpProtocol::Tcp || packet.more_frags() || packet.frag_offset() != 0 {
+        return None;
+    }
+    let (src, dst) = (packet.src_addr(), packet.dst_addr());
+    let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+    Some((
+        IpEndpoint::new(IpAddress::Ipv4(src), segment.src_port()),
+        IpEndpoint::new(IpAddress::Ipv4(dst), segment.dst_port()),
+        segment,
+    ))
+}
+
+/// The device an interface is polled through, noting the TCP segments and IPv4
+/// fragments that pass.
+pub struct Tap<'a> {
+    pub device: &'a mut DeviceVariant,
+    // Shared by the RX and TX tokens smoltcp holds at once
+    pub log: RefCell<&'a mut SegmentLog>,
+    pub fragments: RefCell<&'a mut FragmentWatch>,
+}
+
+impl<'a> Tap<'a> {
+    pub fn new(
+        device: &'a mut DeviceVariant,
+        log: &'a mut SegmentLog,
+        fragments: &'a mut FragmentWatch,
+    ) -> Self {
+        Self { device, log: RefCell::new(log), fragments: RefCell::new(fragments) }
+    }
+}
+
+impl<'a> Device for Tap<'a> {
+    type RxToken<'b>
+        = TapRx<'b, 'a>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = TapTx<'b, 'a>
+    where
+        Self: 'b;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let ip_medium = self.device.is_loopback();
+        let (rx, tx) = self.device.receive(timestamp)?;
+        let log = &self.log;
+        Some((
+            TapRx { inner: rx, log, fragments: &self.fragments, ip_medium, now: timestamp },
+            TapTx { inner: tx, log, ip_medium, now: timestamp },
+        ))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let ip_medium = self.device.is_loopback();
+        let tx = self.device.transmit(timestamp)?;
+        Some(TapTx { inner: tx, log: &self.log, ip_medium, now: timestamp })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.device.capabilities()
+    }
+}
+
+pub struct TapRx<'b, 'a> {
+    inner: RxVariant<'b>,
+    log: &'b RefCell<&'a mut SegmentLog>,
+    fragments: &'b RefCell<&'a mut FragmentWatch>,
+    ip_medium: bool,
+    now: Instant,
+}
+
+impl<'b, 'a> RxToken for TapRx<'b, 'a> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let Self { inner, log, fragments, ip_medium, now } = self;
+        inner.consume(|frame| {
+            // Released before `f`, which may transmit the reply
+            log.borrow_mut().on_rx(frame, ip_medium, now);
+            fragments.borrow_mut().on_rx(frame, ip_medium, now);
+            f(frame)
+        })
+    }
+}
+
+pub struct TapTx<'b, 'a> {
+    inner: TxVariant<'b>,
+    log: &'b RefCell<&'a mut SegmentLog>,
+    ip_medium: bool,
+    now: Instant,
+}
+
+impl<'b, 'a> TxToken for TapTx<'b, 'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let Self { inner, log, ip_medium, now } = self;
+        inner.consume(len, |frame| {
+            let result = f(frame);
+            log.borrow_mut().on_tx(frame, ip_medium, now);
+            result
+        })
+    }
+}
+
+impl<'a> GopherServer<'a> {
+    /// Retransmits and RTT of a TCP socket; zeroes until it has sent anything.
+    pub fn sender_stats(&self, socket: &tcp::Socket) -> TcpSenderStats {
+        let track = socket
+            .local_endpoint()
+            .zip(socket.remote_endpoint())
+            .and_then(|(local, remote)| self.segment_log.get(local, remote));
+        match track {
+            Some(track) => TcpSenderStats {
+                retransmits: track.retransmits,
+                srtt_ms: track.srtt_ms(),
+                rto_ms: track.rto_ms(),
+            },
+            None => TcpSenderStats::default(),
+        }
+    }
+
+    /// Forget the sender tracks of connections smoltcp no longer has.
+    pub fn prune_segment_log(&mut self) {
+        let live: BTreeSet<_> = self
+            .sockets
+            .iter()
+            .filter_map(|(_, socket)| match socket {
+                Socket::Tcp(socket) if socket.state() != tcp::State::Closed => {
+                    Some((socket.local_endpoint()?, socket.remote_endpoint()?))
+                }
+                _ => None,
+            })
+            .collect();
+        self.segment_log.flows.retain(|key, _| live.contains(key));
+    }
+}
@@ -0,0 +1,173 @@
+use super::GopherServer;
+use super::network::GopherSocket;
+use super::sockaddr;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::interface::SocketService;
+use glenda::ipc::Badge;
+use glenda::protocol;
+use serde::Serialize;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+const SELFTEST_PAYLOAD: &[u8] = b"gopher-selftest";
+// Poll passes a stage may take before the loopback path counts as stuck
+const SELFTEST_ROUNDS: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    // Stage that failed and the error it hit, when the test did not pass
+    pub stage: Option<&'static str>,
+    pub error: Option<String>,
+    pub elapsed_us: i64,
+}
+
+impl<'a> GopherServer<'a> {
+    /// Connect two TCP sockets over loopback and pass a known payload both ways, then
+    /// send it in a UDP datagram, checking that everything arrives intact. Goes through
+    /// the same socket calls as a client would, so a pass means bind, listen, connect,
+    /// accept, send and receive are wired up through smoltcp and the loopback poll.
+    pub fn selftest(&mut self) -> SelfTestReport {
+        let start = self.get_time();
+        let mut badges = Vec::new();
+        let res = self.selftest_run(&mut badges);
+        for badge in badges {
+            let _ = GopherSocket { server: self, badge }.close();
+        }
+        let elapsed_us = (self.get_time() - start).total_micros() as i64;
+        match res {
+            Ok(()) => {
+                log!("Selftest passed in {}us", elapsed_us);
+                SelfTestReport { passed: true, stage: None, error: None, elapsed_us }
+            }
+            Err((stage, e)) => {
+                warn!("Selftest failed at {}: {:?}", stage, e);
+                let error = Some(alloc::format!("{:?}", e));
+                SelfTestReport { passed: false, stage: Some(stage), error, elapsed_us }
+            }
+        }
+    }
+
+    fn selftest_run(&mut self, badges: &mut Vec<Badge>) -> Result<(), (&'static str, Error)> {
+        // Whatever address the config gave `lo`
+        let lo = self
+            .interfaces
+            .iter()
+            .find(|ctx| ctx.device.is_loopback())
+            .and_then(|ctx| ctx.iface.ipv4_addr())
+            .ok_or(("listen", Error::NotFound))?;
+        self.selftest_tcp(lo, badges)?;
+        self.selftest_udp(lo, badges)
+    }
+
+    /// Listener and client on `lo`; the payload goes out from the client and is echoed
+    /// back over the accepted connection.
+    fn selftest_tcp(
+        &mut self,
+        lo: Ipv4Address,
+        badges: &mut Vec<Badge>,
+    ) -> Result<(), (&'static str, Error)> {
+        let addr = selftest_addr(lo, self.alloc_port()).map_err(|e| ("listen", e))?;
+        let listener = self
+            .selftest_open(protocol::network::SOCK_STREAM, badges)
+            .map_err(|e| ("listen", e))?;
+        let mut socket = GopherSocket { server: self, badge: listener };
+        socket.bind(&addr).and_then(|_| socket.listen(1)).map_err(|e| ("listen", e))?;
+
+        let client = self
+            .selftest_open(protocol::network::SOCK_STREAM, badges)
+            .map_err(|e| ("connect", e))?;
+        GopherSocket { server: self, badge: client }.connect(&addr).map_err(|e| ("connect", e))?;
+
+        let conn = self
+            .selftest_wait(|s| GopherSocket { server: s, badge: listener }.accept())
+            .map_err(|e| ("accept", e))?;
+        let conn = Badge::new(conn);
+        badges.push(conn);
+
+        for (stage, from, to) in [("send", client, conn), ("echo", conn, client)] {
+            GopherSocket { server: self, badge: from }
+                .send(SELFTEST_PAYLOAD, 0)
+                .map_err(|e| (stage, e))?;
+            let received = self.selftest_recv(to).map_err(|e| (stage, e))?;
+            if received != SELFTEST_PAYLOAD {
+                return Err((stage, Error::Generic));
+            }
+        }
+        Ok(())
+    }
+
+    /// One datagram between two UDP sockets bound on `lo`, checking the sender the
+    /// receiver sees as well as the bytes.
+    fn selftest_udp(
+        &mut self,
+        lo: Ipv4Address,
+        badges: &mut Vec<Badge>,
+    ) -> Result<(), (&'static str, Error)> {
+        let mut ends = [IpEndpoint::new(IpAddress::Ipv4(lo), 0); 2];
+        let mut socks = [Badge::null(); 2];
+        for (end, sock) in ends.iter_mut().zip(socks.iter_mut()) {
+            end.port = self.alloc_port();
+            let addr = selftest_addr(lo, end.port).map_err(|e| ("udp-bind", e))?;
+            *sock = self
+                .selftest_open(protocol::network::SOCK_DGRAM, badges)
+                .map_err(|e| ("udp-bind", e))?;
+            GopherSocket { server: self, badge: *sock }.bind(&addr).map_err(|e| ("udp-bind", e))?;
+        }
+
+        GopherSocket { server: self, badge: socks[0] }
+            .send_to(SELFTEST_PAYLOAD, ends[1])
+            .map_err(|e| ("udp-send", e))?;
+        let mut buf = [0u8; 64];
+        let (len, meta) = self
+            .selftest_wait(|s| GopherSocket { server: s, badge: socks[1] }.recv_from(&mut buf, 0))
+            .map_err(|e| ("udp-recv", e))?;
+        if &buf[..len] != SELFTEST_PAYLOAD || meta.endpoint != ends[0] {
+            return Err(("udp-verify", Error::Generic));
+        }
+        Ok(())
+    }
+
+    /// Read from a stream socket until a whole payload has arrived.
+    fn selftest_recv(&mut self, badge: Badge) -> Result<Vec<u8>, Error> {
+        let mut received = Vec::new();
+        while received.len() < SELFTEST_PAYLOAD.len() {
+            let mut buf = [0u8; 64];
+            let len =
+                self.selftest_wait(|s| GopherSocket { server: s, badge }.recv(&mut buf, 0))?;
+            if len == 0 {
+                return Err(Error::NotConnected);
+            }
+            received.extend_from_slice(&buf[..len]);
+        }
+        Ok(received)
+    }
+
+    fn selftest_open(&mut self, ty: i32, badges: &mut Vec<Badge>) -> Result<Badge, Error> {
+        let id = self.open_socket(Badge::null(), protocol::network::AF_INET, ty, 0)?;
+        badges.push(Badge::new(id));
+        Ok(Badge::new(id))
+    }
+
+    /// Poll until `attempt` stops returning `WouldBlock`, giving up with a timeout.
+    fn selftest_wait<T>(
+        &mut self,
+        mut attempt: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        for _ in 0..SELFTEST_ROUNDS {
+            let _ = self.poll();
+            match attempt(self) {
+                Err(Error::WouldBlock) => continue,
+                res => return res,
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+fn selftest_addr(lo: Ipv4Address, port: u16) -> Result<[u8; sockaddr::SOCKADDR_IN_LEN], Error> {
+    let mut addr = [0u8; sockaddr::SOCKADDR_IN_LEN];
+    sockaddr::write(IpEndpoint::new(IpAddress::Ipv4(lo), port), &mut addr)?;
+    Ok(addr)
+}
@@ -1,6 +1,15 @@
 use super::GopherServer;
-use super::network::GopherSocket;
-use crate::layout::CONFIG_SLOT;
+use super::network::{self, GopherSocket};
+use super::ops;
+use super::retransmit::Tap;
+use super::shutdown::DEFAULT_DRAIN_MS;
+use super::sockaddr;
+use super::stack::DeviceVariant;
+use crate::GlendaNetDevice;
+use crate::layout::{ADMIN_ENDPOINT_SLOT, BOOTSTRAP_ENDPOINT_SLOT, CONFIG_SLOT, MINT_SLOT};
+use crate::shm::{SHM_SLOT_SIZE, ShmPool};
+use alloc::string::String;
+use alloc::vec::Vec;
 use glenda::cap::{CSPACE_CAP, CapPtr, Endpoint, Reply};
 use glenda::error::Error;
 use glenda::interface::{CSpaceService, VSpaceService};
@@ -13,53 +22,52 @@ use glenda::protocol;
 use glenda::protocol::device::{HookTarget, LogicDeviceType};
 use glenda::protocol::init::ServiceState;
 use glenda::utils::align::align_up;
+use smoltcp::iface::{PollResult, SocketSet};
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+const LOOPBACK_DRAIN_ROUNDS: usize = 8;
+// Bytes of each line shown around the column of a network.json parse error
+const CONFIG_SNIPPET_WIDTH: usize = 72;
 
 impl<'a> SystemService for GopherServer<'a> {
     fn init(&mut self) -> Result<(), Error> {
         // 0. Load Network Config (network.json)
         log!("Loading network.json...");
-        match self.res_client.get_config(Badge::null(), "network.json", CONFIG_SLOT) {
-            Ok((frame, size)) => {
-                let size_aligned = align_up(size, 4096);
-                let addr = self
-                    .next_ring_vaddr
-                    .fetch_add(size_aligned, core::sync::atomic::Ordering::SeqCst);
-                {
-                    self.vspace.map_page(
-                        frame,
-                        addr,
-                        glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-                        size_aligned / 4096,
-                        self.res_client,
-                        self.cspace,
-                    )?;
-                }
-                let data = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
-                if let Ok(config_str) = core::str::from_utf8(data) {
-                    // Truncate at first null byte if any
-                    let config_str = config_str.split('\0').next().unwrap_or(config_str);
-                    match serde_json::from_str::<super::NetworkConfig>(config_str) {
-                        Ok(config) => {
-                            log!("Network config loaded: buffer_size={}", config.buffer_size);
-                            self.config = Some(config);
-                        }
-                        Err(e) => log!("Failed to parse network.json: {:?}", e),
-                    }
-                }
+        match self.load_config() {
+            Ok(config) => {
+                log!("Network config loaded: buffer_size={}", config.buffer_size);
+                self.config = Some(config);
             }
-            Err(e) => {
-                warn!("No network.json found or failed to load: {:?}", e);
+            Err(Error::InvalidArgs) if cfg!(feature = "strict-config") => {
+                return Err(Error::InvalidArgs);
             }
+            Err(_) => warn!("Continuing without network.json, interfaces use fallback addresses"),
         }
+        if let Some(capacity) = self.config.as_ref().and_then(|c| c.socket_capacity) {
+            // No sockets exist yet, so the set can simply be replaced
+            self.sockets = SocketSet::new(Vec::with_capacity(capacity));
+        }
+        self.sync_pools();
 
         // 1. Setup global SHM for network packets
         let shm_size = self.config.as_ref().map(|c| c.buffer_size).unwrap_or(1024 * 1024);
-        let shm_pages = (shm_size + 4095) / 4096;
-        let shm_size_aligned = shm_pages * 4096;
+        let mut shm_pages = (shm_size + 4095) / 4096;
 
         let shm_slot = self.cspace.alloc(self.res_client)?;
-        let (shm_paddr, shm_frame) =
-            self.res_client.dma_alloc(Badge::null(), shm_pages, shm_slot)?;
+        // Settle for less on a tight boot, down to what a single device needs
+        let min_pages = (GlendaNetDevice::shm_slots(1, 1) * SHM_SLOT_SIZE / 4096).min(shm_pages);
+        let (shm_paddr, shm_frame) = loop {
+            match self.res_client.dma_alloc(Badge::null(), shm_pages, shm_slot) {
+                Ok(alloc) => break alloc,
+                Err(e) if shm_pages <= min_pages => return Err(e),
+                Err(e) => {
+                    let fewer = (shm_pages / 2).max(min_pages);
+                    warn!("SHM of {} pages unavailable ({:?}), trying {}", shm_pages, e, fewer);
+                    shm_pages = fewer;
+                }
+            }
+        };
+        let shm_size_aligned = shm_pages * 4096;
         let shm_vaddr =
             self.next_shm_vaddr.fetch_add(shm_size_aligned, core::sync::atomic::Ordering::SeqCst);
         {
@@ -73,9 +81,14 @@ impl<'a> SystemService for GopherServer<'a> {
             )?;
         }
         self.shm_frame = Some((shm_frame, shm_vaddr, shm_size_aligned, shm_paddr as usize));
+        self.shm_pool = ShmPool::new(shm_size_aligned);
 
-        // 2. Setup Loopback
-        self.setup_loopback();
+        // 2. Setup Loopback, unless the config leaves it out
+        if self.config.as_ref().is_none_or(|c| c.loopback.enabled) {
+            self.setup_loopback();
+        } else {
+            log!("Loopback interface disabled by config");
+        }
 
         // 3. One-shot initial scan/probe for existing devices
         self.sync_devices()?;
@@ -86,14 +99,34 @@ impl<'a> SystemService for GopherServer<'a> {
         let target = HookTarget::Type(LogicDeviceType::Net);
         self.device_client.hook(Badge::null(), target, self.ipc.endpoint.cap())?;
 
-        // 5. Register Network service
+        // 5. Register Network service. Clients only ever see badged copies: this one
+        // answers HELLO, which hands each client a copy badged with its own id
         log!("Registering Network Service...");
+        CSPACE_CAP.mint_self(
+            self.ipc.endpoint.cap(),
+            BOOTSTRAP_ENDPOINT_SLOT,
+            Badge::new(network::BOOTSTRAP_BADGE),
+        )?;
         self.res_client
             .register_cap(
                 Badge::null(),
                 glenda::protocol::resource::ResourceType::Endpoint,
                 glenda::protocol::resource::NET_ENDPOINT,
-                self.ipc.endpoint.cap(),
+                BOOTSTRAP_ENDPOINT_SLOT,
+            )
+            .ok();
+        // Same endpoint, but calls through this copy carry ADMIN_BADGE
+        CSPACE_CAP.mint_self(
+            self.ipc.endpoint.cap(),
+            ADMIN_ENDPOINT_SLOT,
+            Badge::new(network::ADMIN_BADGE),
+        )?;
+        self.res_client
+            .register_cap(
+                Badge::null(),
+                glenda::protocol::resource::ResourceType::Endpoint,
+                network::NET_ADMIN_ENDPOINT,
+                ADMIN_ENDPOINT_SLOT,
             )
             .ok();
 
@@ -120,55 +153,91 @@ impl<'a> SystemService for GopherServer<'a> {
             if let Err(e) = self.poll() {
                 error!("Poll error: {:?}", e);
             }
-
-            // Network stack poll
-            let mut utcb = unsafe { UTCB::new() };
-            utcb.clear();
-            utcb.set_reply_window(self.ipc.reply.cap());
-            utcb.set_recv_window(self.ipc.recv);
-
-            if let Err(e) = self.ipc.endpoint.recv(&mut utcb) {
-                error!("Recv error: {:?}", e);
-                continue;
-            }
-
-            match self.dispatch(&mut utcb) {
-                Ok(()) => {
-                    let _ = self.reply(&mut utcb);
-                }
-                Err(Error::Success) | Err(Error::WouldBlock) | Err(Error::Timeout) => {
-                    // Handled notification, skip reply
-                    let _ = CSPACE_CAP.delete(self.ipc.reply.cap());
-                }
-                Err(e) => {
-                    let badge = utcb.get_badge();
-                    let tag = utcb.get_msg_tag();
-                    log!(
-                        "Dispatch error: {:?} badge={}, proto={:#x}, label={:#x}",
-                        e,
-                        badge,
-                        tag.proto(),
-                        tag.label()
-                    );
-                    utcb.set_msg_tag(MsgTag::err());
-                    utcb.set_mr(0, e as usize);
-                    let _ = self.reply(&mut utcb);
-                }
-            }
+            self.disable_wedged_interfaces();
+            self.serve_one();
         }
         Ok(())
     }
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
-        let badge = utcb.get_badge();
+        let (caller, badge) = if utcb.get_msg_tag().proto() == protocol::NETWORK_PROTO {
+            // Timers and device notifications still get through while draining
+            if self.ipc.draining {
+                return Err(Error::InvalidState);
+            }
+            let (caller, badge) = self.admit(utcb.get_badge(), utcb.get_msg_tag().label())?;
+            // Only the owner's next call about the socket hears of the timeout
+            if self.timed_out.remove(&(caller, badge)).is_some() {
+                // The run loop treats Err(Timeout) as "no reply", so answer explicitly
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, Error::Timeout as usize);
+                return Ok(());
+            }
+            if let Some(err) = self.reaped.remove(&(caller, badge)) {
+                // Closing a socket that is already gone is not the client's mistake
+                if utcb.get_msg_tag().label() == protocol::network::CLOSE {
+                    utcb.set_msg_tag(MsgTag::ok());
+                } else {
+                    utcb.set_msg_tag(MsgTag::err());
+                    utcb.set_mr(0, err as usize);
+                }
+                return Ok(());
+            }
+            if self.socket_ids.is_stale(badge) {
+                return Err(Error::BadHandle);
+            }
+            (caller, badge)
+        } else {
+            network::split_badge(utcb.get_badge())
+        };
 
         glenda::ipc_dispatch! {
             self, utcb,
+            (protocol::NETWORK_PROTO, ops::HELLO) => |s: &mut Self, u: &mut UTCB| {
+                let client = s.new_client()?;
+                s.reply_endpoint(u, client)?;
+                u.set_mr(0, client.bits() >> network::SOCKET_ID_BITS);
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::SOCKET_ENDPOINT) => |s: &mut Self, u: &mut UTCB| {
+                let (_, id) = network::split_badge(Badge::new(u.get_mr(0)));
+                if id.bits() == 0 {
+                    return Err(Error::InvalidArgs);
+                }
+                s.check_owner(caller, id)?;
+                if !s.socket_map.contains_key(&id) {
+                    return Err(Error::NotFound);
+                }
+                s.reply_endpoint(u, Badge::new(caller.bits() | id.bits()))?;
+                u.set_mr(0, id.bits());
+                Ok(())
+            },
             (protocol::NETWORK_PROTO, protocol::network::SOCKET) => |s: &mut Self, u: &mut UTCB| {
                 let domain = u.get_mr(0) as i32;
                 let socket_type = u.get_mr(1) as i32;
                 let proto = u.get_mr(2) as i32;
-                handle_call(u, |_| s.socket(domain, socket_type, proto))
+                // An attached endpoint receives readiness notifications for the socket
+                let notify = if u.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                    Some(Endpoint::from(s.take_recv_cap()?))
+                } else {
+                    None
+                };
+                handle_call(u, |_| {
+                    let id = match s.open_socket(caller, domain, socket_type, proto) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            // No socket to notify about, so the cap goes back
+                            if let Some(ep) = &notify {
+                                s.release_slot(ep.cap());
+                            }
+                            return Err(e);
+                        }
+                    };
+                    if let Some(entry) = s.socket_map.get_mut(&Badge::new(id)) {
+                        entry.notify = notify;
+                    }
+                    Ok(id)
+                })
             },
             (protocol::NETWORK_PROTO, protocol::network::BIND) => |s: &mut Self, u: &mut UTCB| {
                 let res = {
@@ -206,60 +275,298 @@ impl<'a> SystemService for GopherServer<'a> {
                 }
             },
             (protocol::NETWORK_PROTO, protocol::network::ACCEPT) => |s: &mut Self, u: &mut UTCB| {
-                handle_call(u, |_| {
+                let res = if s.has_blocked_accept(badge) {
+                    Err(Error::WouldBlock)
+                } else {
                     let mut socket = GopherSocket { server: s, badge };
                     socket.accept()
-                })
+                };
+                match res {
+                    Ok(id) => {
+                        u.set_msg_tag(MsgTag::ok());
+                        u.set_mr(0, id);
+                        Ok(())
+                    }
+                    Err(Error::WouldBlock) if s.is_blocking(badge) => {
+                        s.block_accept(badge)?;
+                        // Answered with the new badge from poll() once a peer connects
+                        Err(Error::Success)
+                    }
+                    Err(e) => Err(e),
+                }
             },
             (protocol::NETWORK_PROTO, protocol::network::CLOSE) => |s: &mut Self, u: &mut UTCB| {
+                let linger = s.linger_on_close(badge);
+                GopherSocket { server: s, badge }.close()?;
+                if let Some((handle, secs)) = linger {
+                    if !s.tcp_flushed(handle) {
+                        s.block_linger(handle, secs)?;
+                        // Answered from poll() once the FIN is acknowledged or time runs out
+                        return Err(Error::Success);
+                    }
+                }
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::DRAIN) => |s: &mut Self, u: &mut UTCB| {
+                let timeout_ms = match u.get_mr(0) as u64 {
+                    0 => DEFAULT_DRAIN_MS,
+                    ms => ms,
+                };
+                // The reply still goes out: the run loop exits after this call
+                handle_call(u, |_| {
+                    s.shutdown_gracefully(timeout_ms);
+                    Ok(())
+                })
+            },
+            (protocol::NETWORK_PROTO, ops::SHUTDOWN) => |s: &mut Self, u: &mut UTCB| {
+                let how = u.get_mr(0) as i32;
                 handle_call(u, |_| {
                     let mut socket = GopherSocket { server: s, badge };
-                    socket.close()
+                    socket.shutdown(how)
                 })
             },
             (protocol::NETWORK_PROTO, protocol::network::SEND) => |s: &mut Self, u: &mut UTCB| {
-                let res = {
+                let flags = u.get_mr(0) as i32;
+                let blocking = s.may_block(badge, flags);
+                let res = if s.has_blocked_send(badge) {
+                    Err(Error::WouldBlock)
+                } else {
                     let data = u.buffer();
                     let mut socket = GopherSocket { server: s, badge };
-                    socket.send(data, 0)
+                    socket.send(data, flags)
                 };
                 match res {
+                    // A blocking socket keeps the rest of a short write until it is queued
+                    Ok(len) if len < u.buffer().len() && blocking => {
+                        s.block_send(badge, u.buffer(), len, flags)?;
+                        Err(Error::Success)
+                    }
                     Ok(len) => {
                         u.set_msg_tag(MsgTag::ok());
                         u.set_mr(0, len);
                         Ok(())
                     }
+                    Err(Error::WouldBlock) if blocking => {
+                        s.block_send(badge, u.buffer(), 0, flags)?;
+                        // Answered from poll() once the socket can take the data
+                        Err(Error::Success)
+                    }
                     Err(e) => Err(e),
                 }
             },
             (protocol::NETWORK_PROTO, protocol::network::RECV) => |s: &mut Self, u: &mut UTCB| {
-                let mut buf = [0u8; 2048];
-                let mut socket = GopherSocket { server: s, badge };
-                match socket.recv(&mut buf, 0) {
+                let mut flags = u.get_mr(0) as i32;
+                let blocking = s.may_block(badge, flags);
+                if !blocking {
+                    // A call that cannot park takes what is there instead of waiting for all
+                    flags &= !ops::MSG_WAITALL;
+                }
+                let max_len = s.recv_size(u.buffer_mut().len());
+                let res = if s.has_blocked_recv(badge) {
+                    Err(Error::WouldBlock)
+                } else {
+                    let mut socket = GopherSocket { server: s, badge };
+                    socket.recv(&mut u.buffer_mut()[..max_len], flags)
+                };
+                match res {
                     Ok(len) => {
-                        u.buffer_mut()[..len].copy_from_slice(&buf[..len]);
                         u.set_size(len);
                         u.set_msg_tag(MsgTag::ok());
                         Ok(())
                     }
+                    Err(Error::WouldBlock) if blocking => {
+                        s.block_recv(badge, max_len, flags)?;
+                        // Answered from poll() once data arrives
+                        Err(Error::Success)
+                    }
+                    Err(e) => Err(e),
+                }
+            },
+            (protocol::NETWORK_PROTO, ops::FLUSH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| s.flush(badge))
+            },
+            (protocol::NETWORK_PROTO, ops::PUMP) => |s: &mut Self, u: &mut UTCB| {
+                let delay = s.pump()?;
+                u.set_mr(0, delay.map_or(usize::MAX, |d| d.total_millis() as usize));
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::POLL_DELAY) => |s: &mut Self, u: &mut UTCB| {
+                let delay = s.poll_delay();
+                u.set_mr(0, delay.map_or(usize::MAX, |d| (d.total_micros() * 1000) as usize));
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::SETSOCKOPT) => |s: &mut Self, u: &mut UTCB| {
+                let level = u.get_mr(0) as i32;
+                let optname = u.get_mr(1) as i32;
+                let res = {
+                    let optval = u.buffer();
+                    let mut socket = GopherSocket { server: s, badge };
+                    socket.setsockopt(level, optname, optval)
+                };
+                res?;
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::GETSOCKOPT) => |s: &mut Self, u: &mut UTCB| {
+                let level = u.get_mr(0) as i32;
+                let optname = u.get_mr(1) as i32;
+                let mut socket = GopherSocket { server: s, badge };
+                let len = if (level, optname) == (ops::SOL_SOCKET, ops::SO_ERROR) {
+                    socket.take_error(u.buffer_mut())?
+                } else {
+                    socket.getsockopt(level, optname, u.buffer_mut())?
+                };
+                u.set_size(len);
+                u.set_mr(0, len);
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::CONNECT_DATA) => |s: &mut Self, u: &mut UTCB| {
+                let res = {
+                    let data = u.buffer();
+                    let head = data.get(..sockaddr::SOCKADDR_IN_LEN).ok_or(Error::InvalidArgs)?;
+                    let mut socket = GopherSocket { server: s, badge };
+                    socket.connect_with_data(head, &data[sockaddr::SOCKADDR_IN_LEN..])
+                };
+                let len = res?;
+                u.set_msg_tag(MsgTag::ok());
+                u.set_mr(0, len);
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::SENDTO) => |s: &mut Self, u: &mut UTCB| {
+                let res = {
+                    let data = u.buffer();
+                    let remote = sockaddr::parse(data)?;
+                    let mut socket = GopherSocket { server: s, badge };
+                    socket.send_to(&data[sockaddr::SOCKADDR_IN_LEN..], remote)
+                };
+                match res {
+                    Ok(len) => {
+                        u.set_msg_tag(MsgTag::ok());
+                        u.set_mr(0, len);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            },
+            (protocol::NETWORK_PROTO, ops::RECVFROM) => |s: &mut Self, u: &mut UTCB| {
+                // The sender's sockaddr goes first, the datagram right behind it
+                let flags = u.get_mr(0) as i32;
+                let head = sockaddr::SOCKADDR_IN_LEN;
+                if u.buffer_mut().len() < head {
+                    return Err(Error::InvalidArgs);
+                }
+                let max_len = s.recv_size(u.buffer_mut().len().saturating_sub(head));
+                let mut socket = GopherSocket { server: s, badge };
+                let out = u.buffer_mut();
+                match socket.recv_from(&mut out[head..head + max_len], flags) {
+                    Ok((len, meta)) => {
+                        sockaddr::write(meta.endpoint, &mut out[..head])?;
+                        u.set_size(head + len);
+                        u.set_mr(0, len);
+                        u.set_msg_tag(MsgTag::ok());
+                        Ok(())
+                    }
                     Err(e) => Err(e),
                 }
             },
+            (protocol::NETWORK_PROTO, ops::RECVMMSG) => |s: &mut Self, u: &mut UTCB| {
+                let max = u.get_mr(0);
+                let size = s.recv_size(u.buffer_mut().len());
+                let mut socket = GopherSocket { server: s, badge };
+                let (count, len) = socket.recv_batch(&mut u.buffer_mut()[..size], max)?;
+                u.set_size(len);
+                u.set_mr(0, count);
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::RECVMSG) => |s: &mut Self, u: &mut UTCB| {
+                // Sender, then the local address it was sent to, then the datagram
+                let flags = u.get_mr(0) as i32;
+                let head = 2 * sockaddr::SOCKADDR_IN_LEN;
+                if u.buffer_mut().len() < head {
+                    return Err(Error::InvalidArgs);
+                }
+                let max_len = s.recv_size(u.buffer_mut().len().saturating_sub(head));
+                let mut socket = GopherSocket { server: s, badge };
+                let out = u.buffer_mut();
+                let (len, meta) = socket.recv_from(&mut out[head..head + max_len], flags)?;
+                let (dst, iface) = socket.arrival(&meta)?;
+                let (from, to) = out[..head].split_at_mut(sockaddr::SOCKADDR_IN_LEN);
+                sockaddr::write(meta.endpoint, from)?;
+                sockaddr::write(dst, to)?;
+                u.set_size(head + len);
+                u.set_mr(0, len);
+                u.set_mr(1, iface.unwrap_or(usize::MAX));
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
             (protocol::NETWORK_PROTO, protocol::network::SETUP_IOURING) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
                     let addr_user = u_inner.get_mr(0);
                     let size = u_inner.get_mr(1);
 
                     let frame = if u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
-                        let slot = s.cspace.alloc(s.res_client)?;
-                        glenda::cap::CSPACE_CAP.transfer_self(glenda::cap::RECV_SLOT, slot)?;
-                        Some(glenda::cap::Page::from(slot))
+                        Some(s.take_recv_cap()?)
                     } else {
                         None
                     };
 
                     let mut socket = GopherSocket { server: s, badge };
-                    socket.setup_iouring(addr_user, size, frame)
+                    if let (None, Some(region)) = (frame, u_inner.get_mr(2).checked_sub(1)) {
+                        return socket.setup_iouring_in_region(caller, region, addr_user, size);
+                    }
+                    let page = frame.map(glenda::cap::Page::from);
+                    let res = socket.setup_iouring(addr_user, size, page);
+                    // A frame we could not map is of no use, don't let its slot leak
+                    if let (Err(_), Some(slot)) = (&res, frame) {
+                        s.release_slot(slot);
+                    }
+                    res
+                })
+            },
+            (protocol::NETWORK_PROTO, ops::UNREGISTER_REGION) => |s: &mut Self, u: &mut UTCB| {
+                let id = u.get_mr(0);
+                handle_call(u, |_| s.unregister_region(caller, id))
+            },
+            (protocol::NETWORK_PROTO, ops::REGISTER_REGION) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let (addr_user, size) = (u_inner.get_mr(0), u_inner.get_mr(1));
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let slot = s.take_recv_cap()?;
+                    let frame = glenda::cap::Page::from(slot);
+                    let res = s.register_region(caller, addr_user, size, frame);
+                    if res.is_err() {
+                        s.release_slot(slot);
+                    }
+                    res
+                })
+            },
+            (protocol::NETWORK_PROTO, ops::SETUP_RECV_SHM) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let size = u_inner.get_mr(0);
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let slot = s.take_recv_cap()?;
+                    let mut socket = GopherSocket { server: s, badge };
+                    let res = socket.setup_recv_shm(size, glenda::cap::Page::from(slot));
+                    if res.is_err() {
+                        s.release_slot(slot);
+                    }
+                    res
+                })
+            },
+            (protocol::NETWORK_PROTO, ops::RECV_SHM) => |s: &mut Self, u: &mut UTCB| {
+                let (offset, len) = (u.get_mr(0), u.get_mr(1));
+                handle_call(u, |_| {
+                    let mut socket = GopherSocket { server: s, badge };
+                    socket.recv_shm(offset, len)
                 })
             },
             (protocol::NETWORK_PROTO, protocol::network::PROCESS_IOURING) => |s: &mut Self, u: &mut UTCB| {
@@ -268,6 +575,107 @@ impl<'a> SystemService for GopherServer<'a> {
                     socket.process_iouring()
                 })
             },
+            (protocol::NETWORK_PROTO, ops::IF_STATUS) => |s: &mut Self, u: &mut UTCB| {
+                s.reply_interface_status(u)
+            },
+            (protocol::NETWORK_PROTO, ops::LIST_INTERFACES) => |s: &mut Self, u: &mut UTCB| {
+                s.reply_interface_status(u)
+            },
+            (protocol::NETWORK_PROTO, ops::RELOAD_CONFIG) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| s.reload_config())
+            },
+            (protocol::NETWORK_PROTO, ops::SELFTEST) => |s: &mut Self, u: &mut UTCB| {
+                #[cfg(debug_assertions)]
+                {
+                    let report = s.selftest();
+                    reply_json(u, &report)
+                }
+                // Left out of release builds
+                #[cfg(not(debug_assertions))]
+                {
+                    let _ = (s, u);
+                    Err(Error::NotSupported)
+                }
+            },
+            (protocol::NETWORK_PROTO, ops::GET_HOSTNAME) => |s: &mut Self, u: &mut UTCB| {
+                let mut name = s.hostname();
+                if u.get_mr(0) != 0 {
+                    if let Some(domain) = s.domain() {
+                        name.push('.');
+                        name.push_str(domain);
+                    }
+                }
+                let buf = u.buffer_mut();
+                if name.len() > buf.len() {
+                    return Err(Error::InvalidArgs);
+                }
+                buf[..name.len()].copy_from_slice(name.as_bytes());
+                u.set_size(name.len());
+                u.set_mr(0, name.len());
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::PING) => |s: &mut Self, u: &mut UTCB| {
+                // mr0: target IPv4 (host order u32), mr1: timeout in ms
+                let target = Ipv4Address::from(u.get_mr(0) as u32);
+                let timeout_ms = u.get_mr(1) as u64;
+                s.ping(caller, target, timeout_ms)?;
+                // Answered from poll() once the echo reply arrives or the timeout hits
+                Err(Error::Success)
+            },
+            (protocol::NETWORK_PROTO, ops::CONNECT_HOST) => |s: &mut Self, u: &mut UTCB| {
+                let port = u16::try_from(u.get_mr(0)).map_err(|_| Error::InvalidArgs)?;
+                let host = String::from(buffer_str(u.buffer())?);
+                s.connect_host(badge, &host, port)?;
+                // Answered from poll() once the handshake completes or every address failed
+                Err(Error::Success)
+            },
+            (protocol::NETWORK_PROTO, ops::POOL_CONNECT) => |s: &mut Self, u: &mut UTCB| {
+                let peer = sockaddr::parse(u.buffer())?;
+                handle_call(u, |_| s.take_pooled(caller, peer))
+            },
+            (protocol::NETWORK_PROTO, ops::NEIGHBORS) => |s: &mut Self, u: &mut UTCB| {
+                let neighbors = s.neighbors();
+                reply_json(u, &neighbors)
+            },
+            (protocol::NETWORK_PROTO, ops::NEIGHBOR_ADD) => |s: &mut Self, u: &mut UTCB| {
+                // mr0: IPv4 (host order u32), mr1: MAC in the low 48 bits
+                let ip = Ipv4Address::from(u.get_mr(0) as u32);
+                let mac = (u.get_mr(1) as u64).to_be_bytes();
+                let mac = EthernetAddress([mac[2], mac[3], mac[4], mac[5], mac[6], mac[7]]);
+                handle_call(u, |_| s.add_static_neighbor(ip, mac))
+            },
+            (protocol::NETWORK_PROTO, ops::SOCK_STATE) => |s: &mut Self, u: &mut UTCB| {
+                let report = GopherSocket { server: s, badge }.state_report()?;
+                reply_json(u, &report)
+            },
+            (protocol::NETWORK_PROTO, ops::NEIGHBOR_FLUSH) => |s: &mut Self, u: &mut UTCB| {
+                let name = String::from(buffer_str(u.buffer())?);
+                handle_call(u, |_| s.flush_neighbors(&name))
+            },
+            (protocol::NETWORK_PROTO, ops::GET_MAC) => |s: &mut Self, u: &mut UTCB| {
+                let mac = s.interface_mac(buffer_str(u.buffer())?)?;
+                let mut bits = [0u8; 8];
+                bits[2..].copy_from_slice(&mac.0);
+                u.set_mr(0, u64::from_be_bytes(bits) as usize);
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::SET_MAC) => |s: &mut Self, u: &mut UTCB| {
+                let mac = (u.get_mr(0) as u64).to_be_bytes();
+                let mac = EthernetAddress([mac[2], mac[3], mac[4], mac[5], mac[6], mac[7]]);
+                s.set_interface_mac(buffer_str(u.buffer())?, mac)?;
+                u.set_msg_tag(MsgTag::ok());
+                Ok(())
+            },
+            (protocol::NETWORK_PROTO, ops::STATS) => |s: &mut Self, u: &mut UTCB| {
+                let stats = s.stats();
+                reply_json(u, &stats)
+            },
+            (protocol::NETWORK_PROTO, ops::CONNTRACK) => |s: &mut Self, u: &mut UTCB| {
+                let flows = s.flow_report();
+                reply_json(u, &flows)
+            },
             (glenda::protocol::KERNEL_PROTO, glenda::protocol::kernel::NOTIFY) => |s: &mut Self, u: &mut UTCB| {
                 handle_notify(u, |u| {
                     let badge = u.get_badge();
@@ -285,6 +693,18 @@ impl<'a> SystemService for GopherServer<'a> {
                         }
                     }
                     if is_sq || is_cq {
+                        s.mark_devices_dirty();
+                    }
+                    // A timer we armed may have fired as well
+                    if is_sq
+                        || is_cq
+                        || s.next_wakeup.is_some()
+                        || !s.pending_pings.is_empty()
+                        || !s.host_connects.is_empty()
+                        || !s.blocked.is_empty()
+                        || !s.lingering.is_empty()
+                        || !s.pools.is_empty()
+                    {
                         if let Err(e) = s.poll() {
                             error!("Poll failed: {:?}", e);
                         }
@@ -301,16 +721,464 @@ impl<'a> SystemService for GopherServer<'a> {
     }
 
     fn stop(&mut self) {
-        self.ipc.running = false;
+        self.shutdown_gracefully(DEFAULT_DRAIN_MS);
+    }
+}
+
+/// Parse network.json, logging where it went wrong: what kind of error, serde's line
+/// and column with the matching byte offset, and the region around it.
+fn parse_network_config(data: &[u8]) -> Result<super::NetworkConfig, Error> {
+    let config_str = core::str::from_utf8(data).map_err(|e| {
+        error!("network.json is not valid UTF-8 at byte {}", e.valid_up_to());
+        Error::InvalidArgs
+    })?;
+    // Truncate at first null byte if any
+    let config_str = config_str.split('\0').next().unwrap_or(config_str);
+    let relaxed = strip_json_extras(config_str);
+    serde_json::from_str::<super::NetworkConfig>(&relaxed)
+        .or_else(|e| serde_json::from_str::<super::NetworkConfig>(config_str).map_err(|_| e))
+        .map_err(|e| {
+            let kind = match e.classify() {
+                serde_json::error::Category::Io => "read error",
+                serde_json::error::Category::Syntax => "syntax error",
+                serde_json::error::Category::Data => "invalid value",
+                serde_json::error::Category::Eof => "unexpected end of file",
+            };
+            if e.line() == 0 {
+                error!("Failed to parse network.json: {}: {}", kind, e);
+                return Error::InvalidArgs;
+            }
+            let offset =
+                config_str.split_inclusive('\n').take(e.line() - 1).map(str::len).sum::<usize>()
+                    + e.column().saturating_sub(1);
+            error!(
+                "Failed to parse network.json: {} at line {} column {} (byte {}): {}",
+                kind,
+                e.line(),
+                e.column(),
+                offset,
+                e
+            );
+            log_config_snippet(config_str, e.line(), e.column());
+            Error::InvalidArgs
+        })
+}
+
+/// Log the line at `line` and the one before it, numbered, with a caret under
+/// `column`. Long lines are cut to a window around the column.
+fn log_config_snippet(text: &str, line: usize, column: usize) {
+    let start = column.saturating_sub(CONFIG_SNIPPET_WIDTH / 2);
+    let cut = if start > 0 { "..." } else { "" };
+    let first = line.saturating_sub(1).max(1);
+    for (no, content) in text.lines().enumerate().skip(first - 1).take(line + 1 - first) {
+        let bytes = content.as_bytes();
+        let window =
+            &bytes[start.min(bytes.len())..(start + CONFIG_SNIPPET_WIDTH).min(bytes.len())];
+        error!("{:>5} | {}{}", no + 1, cut, String::from_utf8_lossy(window));
+    }
+    let pad = cut.len() + column.saturating_sub(1) - start;
+    error!("      | {}^", " ".repeat(pad));
+}
+
+/// Blank out `//` and `/* */` comments and trailing commas so hand-edited configs
+/// parse as JSON. Everything removed becomes spaces, and newlines are kept, so serde's
+/// line and column still point into the original file.
+fn strip_json_extras(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = bytes.to_vec();
+    let mut i = 0;
+    let mut in_string = false;
+    // Position of a comma that is only followed by whitespace and comments so far
+    let mut pending_comma = None;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_string {
+            match c {
+                b'\\' => i += 1,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            i += 1;
+            continue;
+        }
+        match (c, bytes.get(i + 1)) {
+            (b'/', Some(b'/')) => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    out[i] = b' ';
+                    i += 1;
+                }
+                continue;
+            }
+            (b'/', Some(b'*')) => {
+                let end = text[i + 2..].find("*/").map_or(bytes.len(), |end| i + 2 + end + 2);
+                for byte in &mut out[i..end] {
+                    if *byte != b'\n' {
+                        *byte = b' ';
+                    }
+                }
+                i = end;
+                continue;
+            }
+            (b'}' | b']', _) => {
+                if let Some(comma) = pending_comma.take() {
+                    out[comma] = b' ';
+                }
+            }
+            (b',', _) => pending_comma = Some(i),
+            (b'"', _) => in_string = true,
+            _ => {}
+        }
+        if !c.is_ascii_whitespace() && c != b',' {
+            pending_comma = None;
+        }
+        i += 1;
+    }
+    // Comments are blanked whole, so the result is still valid UTF-8
+    String::from_utf8(out).unwrap_or_else(|_| String::from(text))
+}
+
+/// Labels served only on the management endpoint: everything that reconfigures or
+/// stops the stack, or reports on sockets of other clients. IF_STATUS and its alias
+/// LIST_INTERFACES only read link state any client can already see, so both stay on
+/// the data plane.
+fn is_admin_label(label: usize) -> bool {
+    matches!(
+        label,
+        ops::STATS
+            | ops::DRAIN
+            | ops::RELOAD_CONFIG
+            | ops::SELFTEST
+            | ops::NEIGHBOR_ADD
+            | ops::NEIGHBOR_FLUSH
+            | ops::SET_MAC
+            | ops::CONNTRACK
+    )
+}
+
+/// String sent in the UTCB buffer, up to the first NUL.
+fn buffer_str(buf: &[u8]) -> Result<&str, Error> {
+    let name = buf.split(|b| *b == 0).next().unwrap_or(buf);
+    core::str::from_utf8(name).map_err(|_| Error::InvalidArgs)
+}
+
+/// Serialize `value` as JSON into the UTCB buffer and mark the reply as successful.
+fn reply_json<T: serde::Serialize>(u: &mut UTCB, value: &T) -> Result<(), Error> {
+    let data = serde_json::to_vec(value).map_err(|_| Error::Generic)?;
+    let buf = u.buffer_mut();
+    if data.len() > buf.len() {
+        return Err(Error::InvalidArgs);
     }
+    buf[..data.len()].copy_from_slice(&data);
+    u.set_size(data.len());
+    u.set_msg_tag(MsgTag::ok());
+    Ok(())
 }
 
 impl<'a> GopherServer<'a> {
+    /// Check that a NETWORK_PROTO call with `label` may be made on the endpoint copy it
+    /// arrived on, and split its badge into the calling client and the socket it names.
+    /// `NET_ENDPOINT` only serves HELLO, the management endpoint only admin labels, and
+    /// a client's own copy everything else, on its own sockets. The raw endpoint names
+    /// no client, so it serves none of them.
+    pub fn admit(&self, raw: Badge, label: usize) -> Result<(Badge, Badge), Error> {
+        if raw.bits() & network::BOOTSTRAP_BADGE != 0 || label == ops::HELLO {
+            if raw.bits() == network::BOOTSTRAP_BADGE && label == ops::HELLO {
+                return Ok((Badge::null(), Badge::null()));
+            }
+            return Err(Error::PermissionDenied);
+        }
+        let admin = raw.bits() & network::ADMIN_BADGE != 0;
+        if admin != is_admin_label(label) {
+            return Err(Error::PermissionDenied);
+        }
+        let (caller, badge) = network::split_badge(raw);
+        if caller.bits() == 0 {
+            return Err(Error::PermissionDenied);
+        }
+        self.check_owner(caller, badge)?;
+        Ok((caller, badge))
+    }
+
+    /// Attach a copy of our endpoint minted with `badge` to the reply of the call being
+    /// dispatched. The copy from the previous reply goes first; the client holds its own.
+    fn reply_endpoint(&mut self, u: &mut UTCB, badge: Badge) -> Result<(), Error> {
+        let _ = CSPACE_CAP.delete(MINT_SLOT);
+        CSPACE_CAP.mint_self(self.ipc.endpoint.cap(), MINT_SLOT, badge)?;
+        let tag = MsgTag::ok();
+        u.set_msg_tag(MsgTag::new(tag.proto(), tag.label(), tag.flags() | MsgFlags::HAS_CAP));
+        u.set_cap_transfer(MINT_SLOT);
+        Ok(())
+    }
+
+    /// IF_STATUS and LIST_INTERFACES: carrier is re-queried first, so `up` is current.
+    fn reply_interface_status(&mut self, u: &mut UTCB) -> Result<(), Error> {
+        self.refresh_link_state();
+        let status = self.interface_status();
+        reply_json(u, &status)
+    }
+
+    /// Wait for one message on the endpoint, dispatch it and send the reply it calls for.
+    pub fn serve_one(&mut self) {
+        let mut utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        utcb.set_reply_window(self.ipc.reply.cap());
+        utcb.set_recv_window(self.ipc.recv);
+
+        if let Err(e) = self.ipc.endpoint.recv(&mut utcb) {
+            error!("Recv error: {:?}", e);
+            return;
+        }
+
+        match self.dispatch(&mut utcb) {
+            Ok(()) => {
+                let _ = self.reply(&mut utcb);
+            }
+            Err(Error::Success) | Err(Error::WouldBlock) | Err(Error::Timeout) => {
+                // Handled notification, skip reply
+                let _ = CSPACE_CAP.delete(self.ipc.reply.cap());
+            }
+            Err(e) => {
+                let badge = utcb.get_badge();
+                let tag = utcb.get_msg_tag();
+                log!(
+                    "Dispatch error: {:?} badge={}, proto={:#x}, label={:#x}",
+                    e,
+                    badge,
+                    tag.proto(),
+                    tag.label()
+                );
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, e as usize);
+                let _ = self.reply(&mut utcb);
+            }
+        }
+    }
+
+    /// Fetch network.json from the resource server and parse it. Parse errors come back
+    /// as `Error::InvalidArgs`.
+    fn load_config(&mut self) -> Result<super::NetworkConfig, Error> {
+        // Only still there if unmapping the previous load failed
+        let _ = CSPACE_CAP.delete(CONFIG_SLOT);
+        let (frame, size) = self
+            .res_client
+            .get_config(Badge::null(), "network.json", CONFIG_SLOT)
+            .map_err(|e| {
+                warn!("No network.json found or failed to load: {:?}", e);
+                e
+            })?;
+        let size_aligned = align_up(size, 4096);
+        let pages = size_aligned / 4096;
+        let (addr, range) = self.alloc_ring_va(size_aligned);
+        if let Err(e) = self.vspace.map_page(
+            frame,
+            addr,
+            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+            pages,
+            self.res_client,
+            self.cspace,
+        ) {
+            self.ring_va.free(addr, range);
+            let _ = CSPACE_CAP.delete(CONFIG_SLOT);
+            return Err(e);
+        }
+        let data = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
+        let config = parse_network_config(data);
+        // The parsed config owns its strings, so the file is not needed past this point
+        match self.vspace.unmap(addr, pages) {
+            Ok(()) => {
+                self.ring_va.free(addr, range);
+                let _ = CSPACE_CAP.delete(CONFIG_SLOT);
+            }
+            Err(e) => warn!("Failed to unmap network.json: {:?}", e),
+        }
+        config
+    }
+
+    /// Re-read network.json and apply it. Interfaces whose addresses, gateway, MTU and
+    /// routes are unchanged are left alone; the rest get their addresses and routes
+    /// rebuilt, and only sockets bound to an address that went away are cut off.
+    /// SHM and RX buffer sizes only take effect for devices probed afterwards.
+    pub fn reload_config(&mut self) -> Result<(), Error> {
+        let config = self.load_config()?;
+        let old = self.config.replace(config);
+        let config = self.config.as_ref().ok_or(Error::NotInitialized)?;
+        let routes_changed = old.as_ref().is_none_or(|old| old.routes != config.routes);
+        let default_changed =
+            old.as_ref().is_none_or(|old| old.default_interface != config.default_interface);
+        let mut orphaned = Vec::new();
+        for (idx, ctx) in self.interfaces.iter_mut().enumerate() {
+            let DeviceVariant::Net(device) = &mut ctx.device else {
+                continue;
+            };
+            super::configure_reassembly(&mut ctx.iface, Some(config));
+            super::configure_neighbor_ttl(&mut device.neighbors, Some(config));
+            // Follows `tcp_mss_clamp` as well as the interface's own entry
+            device.mtu = super::interface_mtu(&ctx.name, Some(config));
+            let new_cfg = config.interfaces.iter().find(|i| i.name == ctx.name);
+            let old_cfg =
+                old.as_ref().and_then(|c| c.interfaces.iter().find(|i| i.name == ctx.name));
+            // Interfaces without an entry of their own run on the fallback
+            let fallback_changed = new_cfg.is_none() && default_changed;
+            if new_cfg == old_cfg && !routes_changed && !fallback_changed {
+                continue;
+            }
+            log!("Reconfiguring interface {}", ctx.name);
+            ctx.iface.update_ip_addrs(|addrs| addrs.clear());
+            ctx.iface.routes_mut().update(|routes| routes.clear());
+            ctx.gateway = super::configure_interface(&mut ctx.iface, &ctx.name, Some(config));
+            ctx.dirty = true;
+            orphaned.push(idx);
+        }
+        for idx in orphaned {
+            self.mark_orphaned(idx);
+        }
+        self.update_dns_servers();
+        self.sync_pools();
+        Ok(())
+    }
+
+    /// Poll interfaces that have sockets on them or were flagged by a device notification.
+    /// Interfaces with sockets are always polled so smoltcp timers keep firing.
     pub fn poll(&mut self) -> Result<(), Error> {
+        self.polling = true;
+        self.release_stale_corks();
         let timestamp = self.get_time(); // Time Service
-        for ctx in &mut self.interfaces {
-            let _ = ctx.iface.poll(timestamp, &mut ctx.device, &mut self.sockets);
+        for idx in self.poll_order() {
+            let active = self.interface_has_sockets(idx)
+                || !self.pending_pings.is_empty()
+                || !self.host_connects.is_empty()
+                || !self.pools.is_empty();
+            let ctx = &self.interfaces[idx];
+            if !ctx.up || !(active || ctx.dirty) {
+                continue;
+            }
+            self.poll_interface(idx, timestamp);
         }
+        self.track_tcp_state();
+        // Before reaping, so connections see their last state and byte counts
+        self.track_flows();
+        self.prune_segment_log();
+        self.reap_timed_out();
+        self.drive_uring();
+        self.drive_blocked();
+        self.drive_host_connects();
+        self.drive_readiness();
+        // Before reaping, which drops the closed sockets lingering CLOSEs look at
+        self.drive_linger();
+        self.drive_pools();
+        self.reap_closed();
+        self.drive_pings();
+        self.polling = false;
         Ok(())
     }
+
+    /// Order in which `poll()` visits the interfaces. They all share one SocketSet, so
+    /// receiving is safe: an interface only reads its own device, and smoltcp hands each
+    /// packet to the one socket whose endpoints match it. Sending is not tied to the
+    /// socket's interface, though; the first interface polled that has a route to the
+    /// peer transmits what the socket queued. Interfaces without a default gateway only
+    /// reach their own subnets, so they go first and anything they cannot route stays
+    /// queued for the gateway interface after them. Two interfaces that both have a
+    /// default gateway can still carry each other's routed traffic.
+    fn poll_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.interfaces.len()).collect();
+        order.sort_by_key(|idx| self.interfaces[*idx].gateway.is_some());
+        order
+    }
+
+    pub fn poll_interface(&mut self, idx: usize, timestamp: smoltcp::time::Instant) {
+        let started = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let ctx = &mut self.interfaces[idx];
+        // A neighbor TTL shorter than smoltcp's only holds if its cache is flushed for it
+        if let DeviceVariant::Net(dev) = &mut ctx.device
+            && dev.neighbors.expire(timestamp)
+        {
+            super::flush_neighbor_cache(ctx, timestamp);
+        }
+        ctx.dirty = false;
+        let before = ctx.device.stats();
+        let loopback = ctx.device.is_loopback();
+        let mut device = Tap::new(&mut ctx.device, &mut self.segment_log, &mut ctx.fragments);
+        if loopback {
+            // Every hop of a local flow is another pass through the loopback queue
+            for _ in 0..LOOPBACK_DRAIN_ROUNDS {
+                let res = ctx.iface.poll(timestamp, &mut device, &mut self.sockets);
+                ctx.poll_stats.record(res);
+                if res == PollResult::None {
+                    break;
+                }
+            }
+        } else {
+            let res = ctx.iface.poll(timestamp, &mut device, &mut self.sockets);
+            ctx.poll_stats.record(res);
+        }
+        ctx.fragments.expire(timestamp, ctx.iface.reassembly_timeout());
+        // Drops with no traffic at all count toward the watchdog in `run()`
+        let after = ctx.device.stats();
+        if after.rx_packets + after.tx_packets > before.rx_packets + before.tx_packets {
+            ctx.failed_polls = 0;
+        } else if after.rx_drops + after.tx_drops > before.rx_drops + before.tx_drops {
+            ctx.failed_polls += 1;
+        }
+        let elapsed_ns =
+            self.time_client.mono_now(Badge::null()).unwrap_or(0).saturating_sub(started);
+        self.interfaces[idx].poll_stats.poll_time_us += elapsed_ns / 1000;
+    }
+
+    /// Push out what `badge` has queued now instead of on the next loop iteration. Only
+    /// the socket's interface is polled; a socket not pinned to one polls everything.
+    /// Does nothing from inside `poll()`, which is about to transmit anyway.
+    pub fn flush(&mut self, badge: Badge) -> Result<(), Error> {
+        let entry = self.socket_map.get(&badge).ok_or(Error::NotFound)?;
+        if self.polling {
+            return Ok(());
+        }
+        let Some(idx) = entry.iface else {
+            return self.poll();
+        };
+        if !self.interfaces.get(idx).is_some_and(|ctx| ctx.up) {
+            return Err(Error::NetworkUnreachable);
+        }
+        let timestamp = self.get_time();
+        self.poll_interface(idx, timestamp);
+        Ok(())
+    }
+
+    /// PUMP: move timers and queued traffic along for clients that get no notifications,
+    /// and tell them when to come back. The delay is the shortest smoltcp asks for on
+    /// any interface that is up, `None` when no interface has a timer running.
+    pub fn pump(&mut self) -> Result<Option<smoltcp::time::Duration>, Error> {
+        if !self.polling {
+            self.poll()?;
+        }
+        Ok(self.poll_delay())
+    }
+
+    /// How long until smoltcp next needs a poll on any interface that is up, `None`
+    /// when no interface has a timer running. Zero means a poll is due now.
+    pub fn poll_delay(&mut self) -> Option<smoltcp::time::Duration> {
+        let timestamp = self.get_time();
+        self.interfaces
+            .iter_mut()
+            .filter(|ctx| ctx.up)
+            .filter_map(|ctx| ctx.iface.poll_delay(timestamp, &self.sockets))
+            .min()
+    }
+
+    pub fn mark_devices_dirty(&mut self) {
+        for ctx in &mut self.interfaces {
+            if !ctx.device.is_loopback() {
+                ctx.dirty = true;
+            }
+        }
+    }
+
+    /// Whether any socket is bound to an address on this interface or to the wildcard.
+    fn interface_has_sockets(&self, idx: usize) -> bool {
+        let iface = &self.interfaces[idx].iface;
+        self.socket_map.values().any(|entry| match entry.local {
+            Some(local) => local.addr.is_unspecified() || iface.has_ip_addr(local.addr),
+            None => true,
+        })
+    }
 }
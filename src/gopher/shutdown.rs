@@ -0,0 +1,121 @@
+use super::GopherServer;
+use super::network::SocketKind;
+use super::stack::DeviceVariant;
+use alloc::vec::Vec;
+use glenda::drivers::interface::DriverClient;
+use glenda::error::Error;
+use glenda::interface::VSpaceService;
+use glenda::ipc::Badge;
+use smoltcp::socket::tcp;
+
+/// How long `stop()` waits for peers to acknowledge our FINs.
+pub const DEFAULT_DRAIN_MS: u64 = 5000;
+
+impl<'a> GopherServer<'a> {
+    /// Close every socket with a FIN, keep polling until all TCP connections are done
+    /// or `timeout_ms` passes, then answer whatever is still deferred, release the
+    /// devices and every mapping and stop the run loop. Between polls it sleeps on the
+    /// endpoint until smoltcp's next timer, a device notification or the deadline.
+    pub fn shutdown_gracefully(&mut self, timeout_ms: u64) {
+        log!("Draining {} sockets before shutdown", self.socket_map.len());
+        self.ipc.draining = true;
+
+        // Nothing parked will be answered by a socket that is about to close
+        let badges: Vec<Badge> = self.socket_map.keys().copied().collect();
+        for badge in &badges {
+            self.fail_blocked(*badge, Error::NotConnected);
+        }
+        for (badge, ops) in core::mem::take(&mut self.pending_uring) {
+            if let Some(uring_server) = self.uring_servers.get_mut(&badge) {
+                for op in ops {
+                    let _ = uring_server.complete(op.user_data, -(Error::NotConnected as i32));
+                }
+            }
+        }
+        for entry in self.socket_map.values() {
+            if entry.kind == SocketKind::Tcp {
+                self.sockets.get_mut::<tcp::Socket>(entry.handle).close();
+            }
+        }
+        self.close_pools();
+        self.mark_devices_dirty();
+
+        let start = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        let deadline_ns = start.saturating_add(timeout_ms.saturating_mul(1_000_000));
+        loop {
+            self.mark_devices_dirty();
+            let _ = self.poll();
+            if self.tcp_drained() {
+                break;
+            }
+            let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+            if now >= deadline_ns {
+                warn!("Shutdown deadline passed with connections still open");
+                break;
+            }
+            let wake = self
+                .poll_delay()
+                .map_or(deadline_ns, |d| now.saturating_add(d.total_micros() as u64 * 1000))
+                .min(deadline_ns);
+            self.arm_timer(wake);
+            self.serve_one();
+        }
+
+        for badge in badges {
+            if let Some(entry) = self.unregister_socket(badge) {
+                self.sockets.remove(entry.handle);
+            }
+        }
+        self.uring_servers.clear();
+        for linger in core::mem::take(&mut self.lingering) {
+            self.finish_reply(linger.reply, Ok(0), &[]);
+        }
+        for (_, ping) in core::mem::take(&mut self.pending_pings) {
+            self.sockets.remove(ping.handle);
+            self.finish_reply(ping.reply, Err(Error::NotConnected), &[]);
+        }
+        for (_, hc) in core::mem::take(&mut self.host_connects) {
+            self.finish_reply(hc.reply, Err(Error::NotConnected), &[]);
+        }
+        for ctx in &mut self.interfaces {
+            if let DeviceVariant::Net(device) = &mut ctx.device {
+                match device.disconnect() {
+                    Ok(()) => device.release_shm(&mut self.shm_pool),
+                    Err(e) => warn!("Failed to disconnect {}: {:?}", ctx.name, e),
+                }
+            }
+            ctx.up = false;
+        }
+        self.release_mappings();
+        self.ipc.running = false;
+    }
+
+    /// Unmap the shared regions clients registered and the packet SHM. Socket rings and
+    /// receive buffers went with their sockets.
+    fn release_mappings(&mut self) {
+        for (client, regions) in core::mem::take(&mut self.regions) {
+            for region in regions.into_iter().flatten() {
+                if let Err(e) = self.unmap_frame(region.mapping) {
+                    warn!("Failed to unmap region of client {}: {:?}", client.bits(), e);
+                }
+            }
+        }
+        if let Some((frame, vaddr, size, _)) = self.shm_frame.take() {
+            match self.vspace.unmap(vaddr, size / 4096) {
+                Ok(()) => self.release_slot(frame.cap()),
+                Err(e) => warn!("Failed to unmap packet SHM: {:?}", e),
+            }
+        }
+    }
+
+    /// Whether every TCP socket has finished its close handshake. TIME-WAIT counts as
+    /// done, since our FIN has already been acknowledged.
+    fn tcp_drained(&self) -> bool {
+        self.socket_map.values().filter(|entry| entry.kind == SocketKind::Tcp).all(|entry| {
+            matches!(
+                self.sockets.get::<tcp::Socket>(entry.handle).state(),
+                tcp::State::Closed | tcp::State::TimeWait
+            )
+        })
+    }
+}
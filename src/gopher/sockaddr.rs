@@ -0,0 +1,39 @@
+use glenda::error::Error;
+use glenda::protocol;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint, Ipv4Address};
+
+/// Size of an IPv4 `sockaddr_in`: family, port, address and padding.
+pub const SOCKADDR_IN_LEN: usize = 16;
+
+/// Parse a `sockaddr_in` (family in native order, port and address in network order).
+pub fn parse(addr: &[u8]) -> Result<IpEndpoint, Error> {
+    if addr.len() < SOCKADDR_IN_LEN {
+        return Err(Error::InvalidArgs);
+    }
+    let family = u16::from_ne_bytes([addr[0], addr[1]]) as i32;
+    if family != protocol::network::AF_INET {
+        return Err(Error::InvalidArgs);
+    }
+    let port = u16::from_be_bytes([addr[2], addr[3]]);
+    let ip = Ipv4Address::new(addr[4], addr[5], addr[6], addr[7]);
+    Ok(IpEndpoint::new(IpAddress::Ipv4(ip), port))
+}
+
+/// Encode `ep` as a `sockaddr_in` at the start of `out`, returning the bytes written.
+pub fn write(ep: IpEndpoint, out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < SOCKADDR_IN_LEN {
+        return Err(Error::InvalidArgs);
+    }
+    let IpAddress::Ipv4(ip) = ep.addr;
+    out[..SOCKADDR_IN_LEN].fill(0);
+    out[0..2].copy_from_slice(&(protocol::network::AF_INET as u16).to_ne_bytes());
+    out[2..4].copy_from_slice(&ep.port.to_be_bytes());
+    out[4..8].copy_from_slice(&ip.octets());
+    Ok(SOCKADDR_IN_LEN)
+}
+
+/// Turn a bind address into a listen endpoint, treating `INADDR_ANY` as a wildcard.
+pub fn listen_endpoint(ep: IpEndpoint) -> IpListenEndpoint {
+    let addr = if ep.addr.is_unspecified() { None } else { Some(ep.addr) };
+    IpListenEndpoint { addr, port: ep.port }
+}
@@ -1,23 +1,54 @@
+use super::retransmit::FragmentWatch;
+use super::stats::{NetStats, PollStats};
 use crate::GlendaNetDevice;
-use smoltcp::iface::Interface;
+use crate::device::LinkMode;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::Serialize;
+use smoltcp::iface::{Config, Interface};
 use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address};
 
 pub enum DeviceVariant {
     Net(GlendaNetDevice),
-    Loopback(smoltcp::phy::Loopback),
+    // With the MTU it reports, which smoltcp's loopback fixes at 65535
+    Loopback(smoltcp::phy::Loopback, usize),
 }
 
 impl DeviceVariant {
     pub fn mac_address(&self) -> smoltcp::wire::EthernetAddress {
         match self {
-            Self::Net(d) => {
-                use glenda::drivers::interface::NetDriver;
-                let mac = d.mac_address();
-                smoltcp::wire::EthernetAddress(mac.octets)
-            }
-            Self::Loopback(_) => smoltcp::wire::EthernetAddress([0, 0, 0, 0, 0, 0]),
+            Self::Net(d) => d.ethernet_addr(),
+            // IP medium, there is no link-layer address
+            Self::Loopback(..) => smoltcp::wire::EthernetAddress([0, 0, 0, 0, 0, 0]),
+        }
+    }
+
+    pub fn link_up(&mut self) -> bool {
+        match self {
+            Self::Net(d) => d.link_up(),
+            Self::Loopback(..) => true,
+        }
+    }
+
+    /// Loopback has no physical link to negotiate, so it reads as unknown.
+    pub fn link_mode(&mut self) -> LinkMode {
+        match self {
+            Self::Net(d) => d.link_mode(),
+            Self::Loopback(..) => LinkMode::default(),
+        }
+    }
+
+    pub fn stats(&self) -> NetStats {
+        match self {
+            Self::Net(d) => d.stats,
+            Self::Loopback(..) => NetStats::default(),
         }
     }
+
+    pub fn is_loopback(&self) -> bool {
+        matches!(self, Self::Loopback(..))
+    }
 }
 
 impl smoltcp::phy::Device for DeviceVariant {
@@ -35,7 +66,7 @@ impl smoltcp::phy::Device for DeviceVariant {
             Self::Net(d) => {
                 d.receive(timestamp).map(|(rx, tx)| (RxVariant::Net(rx), TxVariant::Net(tx)))
             }
-            Self::Loopback(d) => d
+            Self::Loopback(d, _) => d
                 .receive(timestamp)
                 .map(|(rx, tx)| (RxVariant::Loopback(rx), TxVariant::Loopback(tx))),
         }
@@ -44,14 +75,18 @@ impl smoltcp::phy::Device for DeviceVariant {
     fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
         match self {
             Self::Net(d) => d.transmit(timestamp).map(|tx| TxVariant::Net(tx)),
-            Self::Loopback(d) => d.transmit(timestamp).map(|tx| TxVariant::Loopback(tx)),
+            Self::Loopback(d, _) => d.transmit(timestamp).map(|tx| TxVariant::Loopback(tx)),
         }
     }
 
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
         match self {
             Self::Net(d) => d.capabilities(),
-            Self::Loopback(d) => d.capabilities(),
+            Self::Loopback(d, mtu) => {
+                let mut caps = d.capabilities();
+                caps.max_transmission_unit = *mtu;
+                caps
+            }
         }
     }
 }
@@ -91,6 +126,74 @@ impl<'a> smoltcp::phy::TxToken for TxVariant<'a> {
 }
 
 pub struct InterfaceContext {
+    pub name: String,
+    // Hardware id the device was probed from; none for loopback
+    pub hw_id: Option<usize>,
     pub device: DeviceVariant,
     pub iface: Interface,
+    pub up: bool,
+    // Speed and duplex read when the link last came up; unknown while it is down
+    pub link: LinkMode,
+    // Set when the device signals completions, cleared once the interface is polled
+    pub dirty: bool,
+    pub poll_stats: PollStats,
+    // Polls in a row in which the device dropped frames and moved none
+    pub failed_polls: u32,
+    // Taken down by the poll watchdog; link changes no longer bring it back up
+    pub disabled: bool,
+    // Default route `configure_interface` installed, for picking an egress interface
+    pub gateway: Option<Ipv4Address>,
+    pub fragments: FragmentWatch,
+}
+
+impl InterfaceContext {
+    /// `lo` on smoltcp's IP-medium `Loopback` with `addr/mask` assigned. Needs nothing
+    /// from the IPC side, so tests build the same interface the server runs.
+    pub fn loopback(addr: Ipv4Address, mask: u8, mtu: usize, now: Instant) -> Self {
+        let mut device =
+            DeviceVariant::Loopback(smoltcp::phy::Loopback::new(smoltcp::phy::Medium::Ip), mtu);
+        let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, now);
+        iface.update_ip_addrs(|addrs| {
+            if addrs.push(IpCidr::new(IpAddress::Ipv4(addr), mask)).is_err() {
+                warn!("Failed to assign {} to lo", addr);
+            }
+        });
+        Self {
+            name: String::from("lo"),
+            hw_id: None,
+            device,
+            iface,
+            up: true,
+            link: LinkMode::default(),
+            dirty: true,
+            poll_stats: PollStats::default(),
+            failed_polls: 0,
+            disabled: false,
+            gateway: None,
+            fragments: FragmentWatch::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NeighborReport {
+    pub iface: String,
+    pub ip: String,
+    pub mac: [u8; 6],
+    // Remaining lifetime of learned entries; static entries never expire
+    pub expires_in_ms: Option<u64>,
+    pub permanent: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceStatus {
+    pub name: String,
+    pub mac: [u8; 6],
+    pub ips: Vec<String>,
+    pub mtu: usize,
+    pub flags: u32,
+    // Negotiated link speed, none when the driver cannot tell
+    pub speed_mbps: Option<u32>,
+    // "full", "half" or "unknown"
+    pub duplex: &'static str,
 }
@@ -0,0 +1,98 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::Serialize;
+use smoltcp::iface::PollResult;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_drops: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_drops: u64,
+}
+
+/// What smoltcp reported for the polls of one interface. Mostly idle polls with a
+/// growing poll time mean the stack is spinning rather than moving traffic.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PollStats {
+    pub state_changed: u64,
+    pub idle: u64,
+    pub poll_time_us: u64,
+}
+
+impl PollStats {
+    pub fn record(&mut self, res: PollResult) {
+        match res {
+            PollResult::SocketStateChanged => self.state_changed += 1,
+            PollResult::None => self.idle += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SocketStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Sender side of a TCP connection as seen on the wire; see `retransmit::SegmentTrack`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TcpSenderStats {
+    pub retransmits: u64,
+    // Derived from ACK round trips; none until the first segment is acknowledged
+    pub srtt_ms: Option<u64>,
+    pub rto_ms: Option<u64>,
+}
+
+/// Fragmented datagrams received on an interface, as `retransmit::FragmentWatch` saw
+/// them, with the limits they were reassembled under.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReassemblyStats {
+    pub completed: u64,
+    // Still missing fragments when the timeout ran out
+    pub timed_out: u64,
+    // Larger than smoltcp's reassembly buffer, so dropped once complete
+    pub oversize: u64,
+    pub timeout_ms: u64,
+    pub buffer_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceStatsReport {
+    pub name: String,
+    pub stats: NetStats,
+    pub poll: PollStats,
+    pub reassembly: ReassemblyStats,
+    // Taken down by the poll watchdog after the device kept failing
+    pub disabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SocketStatsReport {
+    pub badge: usize,
+    pub stats: SocketStats,
+    // Bytes still queued inside smoltcp
+    pub send_queue: usize,
+    pub recv_queue: usize,
+    // None for UDP
+    pub tcp: Option<TcpSenderStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub interfaces: Vec<InterfaceStatsReport>,
+    pub sockets: Vec<SocketStatsReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SocketStateReport {
+    // smoltcp's TCP state, e.g. "SYN-SENT"; none for UDP
+    pub state: Option<String>,
+    pub local: Option<String>,
+    pub remote: Option<String>,
+    pub send_queue: usize,
+    pub recv_queue: usize,
+    pub tcp: Option<TcpSenderStats>,
+}
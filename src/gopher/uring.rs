@@ -0,0 +1,416 @@
+use super::GopherServer;
+use super::network::GopherSocket;
+use super::ops;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use glenda::cap::Page;
+use glenda::error::Error;
+use glenda::interface::{SocketService, VSpaceService};
+use glenda::io::uring::IoUringServer;
+use glenda::ipc::Badge;
+
+/// Address ranges in Gopher for the frames it maps on behalf of clients and devices:
+/// socket rings, receive buffers, shared regions and device rings. A range given back
+/// is reused before the window grows, so mapping and unmapping forever stays bounded.
+pub struct RingVaPool {
+    next: usize,
+    free: Vec<(usize, usize)>,
+}
+
+impl RingVaPool {
+    pub fn new(base: usize) -> Self {
+        Self { next: base, free: Vec::new() }
+    }
+
+    /// Range of at least `size` bytes, as `(vaddr, size of the range)`.
+    pub fn alloc(&mut self, size: usize) -> (usize, usize) {
+        match self.free.iter().position(|(_, len)| *len >= size) {
+            Some(i) => self.free.swap_remove(i),
+            None => {
+                let va = self.next;
+                self.next += size;
+                (va, size)
+            }
+        }
+    }
+
+    /// Give back a range `alloc` returned, once nothing is mapped there anymore.
+    pub fn free(&mut self, va: usize, range: usize) {
+        self.free.push((va, range));
+    }
+
+    /// End of the address space handed out so far.
+    pub fn high_water(&self) -> usize {
+        self.next
+    }
+}
+
+/// Where a client's io_uring buffer is mapped, on the client side and in Gopher.
+pub struct UringWindow {
+    pub client: usize,
+    pub server: usize,
+    pub size: usize,
+}
+
+impl UringWindow {
+    /// Server address of the client range `[addr, addr + len)`, if it lies inside the window.
+    pub fn translate(&self, addr: usize, len: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.client)?;
+        (offset.checked_add(len)? <= self.size).then_some(self.server + offset)
+    }
+
+    /// Read an iovec array of `count` `{ base: u64, len: u64 }` entries at client address
+    /// `addr`, translating every segment. Fails if any part falls outside the window.
+    pub fn iovecs(&self, addr: usize, count: usize) -> Result<Vec<(usize, usize)>, Error> {
+        if count > ops::IOV_MAX {
+            return Err(Error::InvalidArgs);
+        }
+        let table = self.translate(addr, count * 16).ok_or(Error::InvalidArgs)?;
+        (0..count)
+            .map(|i| {
+                let entry = (table + i * 16) as *const u64;
+                let (base, len) = unsafe {
+                    (entry.read_unaligned() as usize, entry.add(1).read_unaligned() as usize)
+                };
+                let seg = self.translate(base, len).ok_or(Error::InvalidArgs)?;
+                Ok((seg, len))
+            })
+            .collect()
+    }
+}
+
+/// A frame SETUP_IOURING or SETUP_RECV_SHM mapped for one socket, owned by that socket.
+/// `size` is the whole range taken for it, which may be larger than the pages mapped.
+pub struct RingMapping {
+    pub frame: Page,
+    pub va: usize,
+    pub size: usize,
+    pub pages: usize,
+}
+
+/// A frame REGISTER_REGION mapped once for all of a client's sockets, with the part of
+/// it each socket's ring was placed in.
+pub struct Region {
+    pub window: UringWindow,
+    pub mapping: RingMapping,
+    // Client address and size of each ring inside the region, by socket
+    pub rings: BTreeMap<Badge, (usize, usize)>,
+}
+
+impl Region {
+    /// Whether `[addr, addr + size)` overlaps the ring of a socket other than `badge`.
+    pub fn overlaps(&self, badge: Badge, addr: usize, size: usize) -> bool {
+        self.rings.iter().any(|(other, &(start, len))| {
+            *other != badge && addr < start + len && start < addr + size
+        })
+    }
+}
+
+/// An io_uring request that could not complete inline and is retried from `poll()`.
+pub struct PendingOp {
+    pub user_data: u64,
+    pub kind: PendingKind,
+}
+
+pub enum PendingKind {
+    Accept,
+    Connect,
+    Read { addr: usize, len: usize },
+    Write { addr: usize, len: usize },
+    // Segments already translated to server addresses
+    Writev { segments: Vec<(usize, usize)> },
+    Poll { events: u32, edge: bool, seen: u32 },
+    Timeout { deadline_ns: u64, link: Option<u64> },
+}
+
+impl PendingKind {
+    fn is_read(&self) -> bool {
+        matches!(self, Self::Read { .. })
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self, Self::Write { .. } | Self::Writev { .. })
+    }
+
+    fn is_timeout(&self) -> bool {
+        matches!(self, Self::Timeout { .. })
+    }
+}
+
+impl<'a> GopherServer<'a> {
+    pub fn park_uring(&mut self, badge: Badge, op: PendingOp) {
+        self.pending_uring.entry(badge).or_default().push(op);
+    }
+
+    /// Whether a read (or write) is already parked for `badge`; new ones must queue
+    /// behind it to keep the byte stream in submission order.
+    pub fn has_pending_read(&self, badge: Badge) -> bool {
+        self.pending_uring.get(&badge).is_some_and(|ops| ops.iter().any(|op| op.kind.is_read()))
+    }
+
+    pub fn has_pending_write(&self, badge: Badge) -> bool {
+        self.pending_uring.get(&badge).is_some_and(|ops| ops.iter().any(|op| op.kind.is_write()))
+    }
+
+    /// Complete every op parked for `badge` with `err`, for a socket that is going away.
+    pub fn abort_uring(&mut self, badge: Badge, uring_server: &mut IoUringServer, err: Error) {
+        for op in self.pending_uring.remove(&badge).unwrap_or_default() {
+            let _ = uring_server.complete(op.user_data, -(err as i32));
+        }
+    }
+
+    /// IOURING_OP_ASYNC_CANCEL: complete the op parked for `badge` under `user_data`, or
+    /// every parked op when `user_data` is `None`, with `ConnectionAborted`. Returns how
+    /// many were taken off the list.
+    pub fn cancel_uring(
+        &mut self,
+        badge: Badge,
+        uring_server: &mut IoUringServer,
+        user_data: Option<u64>,
+    ) -> usize {
+        let Some(ops) = self.pending_uring.get_mut(&badge) else {
+            return 0;
+        };
+        let mut cancelled = 0;
+        ops.retain(|op| {
+            if user_data.is_some_and(|target| target != op.user_data) {
+                return true;
+            }
+            let _ = uring_server.complete(op.user_data, -(Error::ConnectionAborted as i32));
+            cancelled += 1;
+            false
+        });
+        if ops.is_empty() {
+            self.pending_uring.remove(&badge);
+        }
+        cancelled
+    }
+
+    /// Address range of at least `size` bytes for a ring mapping, reusing one a closed
+    /// socket gave back before growing into fresh address space.
+    pub fn alloc_ring_va(&mut self, size: usize) -> (usize, usize) {
+        self.ring_va.alloc(size)
+    }
+
+    /// Drop the ring of `badge`, completing its parked ops with `ConnectionAborted`,
+    /// and unmap the frame SETUP_IOURING mapped for it. A ring checked out by
+    /// `process_iouring` is left to it, which releases it once the SQEs are done.
+    pub fn release_ring(&mut self, badge: Badge) {
+        let Some(mut uring_server) = self.uring_servers.remove(&badge) else {
+            return;
+        };
+        self.abort_uring(badge, &mut uring_server, Error::ConnectionAborted);
+        self.uring_windows.remove(&badge);
+        for region in self.regions.values_mut().flatten().flatten() {
+            region.rings.remove(&badge);
+        }
+        if let Some(map) = self.ring_maps.remove(&badge)
+            && let Err(e) = self.unmap_frame(map)
+        {
+            warn!("Failed to unmap ring of socket {}: {:?}", badge.bits(), e);
+        }
+    }
+
+    pub fn region_mut(&mut self, client: Badge, id: usize) -> Option<&mut Region> {
+        self.regions.get_mut(&client)?.get_mut(id)?.as_mut()
+    }
+
+    /// UNREGISTER_REGION: unmap region `id` of `client`. Refused while a socket still has
+    /// its ring in it.
+    pub fn unregister_region(&mut self, client: Badge, id: usize) -> Result<(), Error> {
+        let regions = self.regions.get_mut(&client).ok_or(Error::NotFound)?;
+        let slot = regions.get_mut(id).ok_or(Error::NotFound)?;
+        match slot {
+            None => return Err(Error::NotFound),
+            Some(region) if !region.rings.is_empty() => return Err(Error::InvalidState),
+            Some(_) => {}
+        }
+        let Some(region) = slot.take() else {
+            return Err(Error::NotFound);
+        };
+        // Ids of the regions after it stay valid, so only trailing holes can go
+        while regions.last().is_some_and(Option::is_none) {
+            regions.pop();
+        }
+        if regions.is_empty() {
+            self.regions.remove(&client);
+        }
+        self.unmap_frame(region.mapping)
+    }
+
+    /// Unmap the receive buffer SETUP_RECV_SHM mapped for `badge`, if it has one.
+    pub fn release_recv_shm(&mut self, badge: Badge) {
+        if let Some((map, _)) = self.recv_shm.remove(&badge)
+            && let Err(e) = self.unmap_frame(map)
+        {
+            warn!("Failed to unmap receive buffer of socket {}: {:?}", badge.bits(), e);
+        }
+    }
+
+    /// Unmap a frame mapped for a socket, drop its cap and give its address range back
+    /// for the next mapping. Nothing is released if the unmap fails.
+    pub fn unmap_frame(&mut self, map: RingMapping) -> Result<(), Error> {
+        self.vspace.unmap(map.va, map.pages)?;
+        self.release_slot(map.frame.cap());
+        self.ring_va.free(map.va, map.size);
+        Ok(())
+    }
+
+    /// Ask the time service to wake us at `deadline_ns` so parked timeouts fire
+    /// even when no other IPC arrives.
+    pub fn arm_timer(&mut self, deadline_ns: u64) {
+        if self.next_wakeup.is_some_and(|next| next <= deadline_ns) {
+            return;
+        }
+        match self.time_client.notify_at(Badge::null(), deadline_ns, self.ipc.endpoint.cap()) {
+            Ok(()) => self.next_wakeup = Some(deadline_ns),
+            Err(e) => warn!("Failed to arm timer: {:?}", e),
+        }
+    }
+
+    /// Retry every parked request and post CQEs for the ones that finished.
+    pub fn drive_uring(&mut self) {
+        let now = self.time_client.mono_now(Badge::null()).unwrap_or(0);
+        if self.next_wakeup.is_some_and(|next| next <= now) {
+            self.next_wakeup = None;
+        }
+        let badges: Vec<Badge> = self.pending_uring.keys().copied().collect();
+        for badge in badges {
+            let Some(mut ops) = self.pending_uring.remove(&badge) else {
+                continue;
+            };
+            let Some(mut uring_server) = self.uring_servers.remove(&badge) else {
+                // The ring is gone, nobody is left to complete these
+                continue;
+            };
+            // Expired timeouts take the op they are linked to down with them
+            let cancelled: Vec<u64> = ops
+                .iter()
+                .filter_map(|op| match op.kind {
+                    PendingKind::Timeout { deadline_ns, link } if deadline_ns <= now => link,
+                    _ => None,
+                })
+                .collect();
+            ops.retain(|op| {
+                if op.kind.is_timeout() || !cancelled.contains(&op.user_data) {
+                    return true;
+                }
+                let _ = uring_server.complete(op.user_data, -(Error::Timeout as i32));
+                false
+            });
+            let live: Vec<u64> = ops.iter().map(|op| op.user_data).collect();
+
+            // Once a read or write has to keep waiting, later ones of the same kind wait too
+            let (mut read_blocked, mut write_blocked) = (false, false);
+            let mut next_deadline = None;
+            ops.retain_mut(|op| {
+                if (op.kind.is_read() && read_blocked) || (op.kind.is_write() && write_blocked) {
+                    return true;
+                }
+                if let PendingKind::Timeout { deadline_ns, link } = op.kind {
+                    let res = if deadline_ns <= now {
+                        -(Error::Timeout as i32)
+                    } else if link.is_some_and(|target| !live.contains(&target)) {
+                        // The linked op finished first, the timeout is moot
+                        0
+                    } else {
+                        next_deadline =
+                            Some(next_deadline.map_or(deadline_ns, |d: u64| d.min(deadline_ns)));
+                        return true;
+                    };
+                    let _ = uring_server.complete(op.user_data, res);
+                    return false;
+                }
+                match self.try_complete(badge, op) {
+                    Some(res) => {
+                        let _ = uring_server.complete(op.user_data, res);
+                        false
+                    }
+                    None => {
+                        read_blocked |= op.kind.is_read();
+                        write_blocked |= op.kind.is_write();
+                        true
+                    }
+                }
+            });
+            self.uring_servers.insert(badge, uring_server);
+            if !ops.is_empty() {
+                self.pending_uring.insert(badge, ops);
+            }
+            if let Some(deadline) = next_deadline {
+                self.arm_timer(deadline);
+            }
+        }
+    }
+
+    /// Result to post for `op`, or `None` if it still has to wait.
+    fn try_complete(&mut self, badge: Badge, op: &mut PendingOp) -> Option<i32> {
+        let mut socket = GopherSocket { server: self, badge };
+        let res = match &mut op.kind {
+            PendingKind::Poll { events, edge, seen } => {
+                let ready = socket.readiness().unwrap_or(ops::POLLERR)
+                    & (*events | ops::POLLERR | ops::POLLHUP);
+                let fired = if *edge { ready & !*seen } else { ready };
+                // Bits that dropped can fire again on their next rising edge
+                *seen = ready;
+                if fired != 0 { Ok(fired as i32) } else { Err(Error::WouldBlock) }
+            }
+            PendingKind::Accept => socket.accept().map(|badge| badge as i32),
+            PendingKind::Connect => socket.connect_result().map(|_| 0),
+            PendingKind::Read { addr, len } => {
+                let buf = unsafe { core::slice::from_raw_parts_mut(*addr as *mut u8, *len) };
+                socket.recv(buf, 0).map(|len| len as i32)
+            }
+            PendingKind::Write { addr, len } => {
+                let buf = unsafe { core::slice::from_raw_parts(*addr as *const u8, *len) };
+                socket.send(buf, 0).map(|len| len as i32)
+            }
+            PendingKind::Writev { segments } => {
+                socket.send_vectored(segments).map(|len| len as i32)
+            }
+            // Handled by drive_uring, which knows the current time
+            PendingKind::Timeout { .. } => Err(Error::WouldBlock),
+        };
+        match res {
+            Ok(res) => Some(res),
+            Err(Error::WouldBlock) => None,
+            Err(e) => Some(-(e as i32)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x6000_0000;
+    const RING: usize = 0x4000;
+
+    /// SETUP_IOURING and close over and over, as a client reopening its socket does:
+    /// the ring keeps landing on the same range and the window never grows.
+    #[test]
+    fn ring_setup_and_close_reuses_va() {
+        let mut pool = RingVaPool::new(BASE);
+        let first = pool.alloc(RING);
+        pool.free(first.0, first.1);
+        for _ in 0..1000 {
+            let (va, range) = pool.alloc(RING);
+            assert_eq!(va, first.0);
+            pool.free(va, range);
+        }
+        assert_eq!(pool.high_water(), BASE + RING);
+    }
+
+    #[test]
+    fn live_ranges_do_not_overlap() {
+        let mut pool = RingVaPool::new(BASE);
+        let a = pool.alloc(RING);
+        let b = pool.alloc(2 * RING);
+        pool.free(a.0, a.1);
+        // Too small for the freed range, so it grows instead of overlapping `b`
+        let c = pool.alloc(2 * RING);
+        assert!(c.0 >= b.0 + b.1);
+        // Smaller requests take the whole freed range back with them
+        assert_eq!(pool.alloc(RING / 2), a);
+    }
+}
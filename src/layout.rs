@@ -11,5 +11,14 @@ pub const TIME_CAP: Endpoint = Endpoint::from(TIME_SLOT);
 
 pub const CONFIG_SLOT: CapPtr = CapPtr::from(12);
 
+// Gopher's endpoint minted with `ADMIN_BADGE`, for the management endpoint
+pub const ADMIN_ENDPOINT_SLOT: CapPtr = CapPtr::from(13);
+
+// Gopher's endpoint minted with `BOOTSTRAP_BADGE`, registered as `NET_ENDPOINT`
+pub const BOOTSTRAP_ENDPOINT_SLOT: CapPtr = CapPtr::from(14);
+
+// Badged copy of Gopher's endpoint on its way out in a HELLO or SOCKET_ENDPOINT reply
+pub const MINT_SLOT: CapPtr = CapPtr::from(15);
+
 pub const RING_VA: usize = 0x3000_0000;
 pub const SHM_VA: usize = 0x6000_0000;
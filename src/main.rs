@@ -1,5 +1,5 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 #[macro_use]
 extern crate glenda;
@@ -8,6 +8,7 @@ extern crate alloc;
 mod device;
 mod gopher;
 mod layout;
+mod shm;
 
 use crate::gopher::GopherServer;
 use crate::layout::{DEVICE_CAP, DEVICE_SLOT, INIT_CAP, INIT_SLOT, TIME_CAP, TIME_SLOT};
@@ -24,7 +25,8 @@ use glenda::utils::manager::{CSpaceManager, VSpaceManager};
 
 pub use device::GlendaNetDevice;
 
-#[unsafe(no_mangle)]
+// Under `cargo test` the harness supplies the entry point
+#[cfg_attr(not(test), unsafe(no_mangle))]
 fn main() -> usize {
     glenda::console::init_logging("Gopher");
     log!("Starting Network Stack...");
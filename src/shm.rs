@@ -0,0 +1,133 @@
+use crate::device::{DEFAULT_RX_BUFFERS, DEFAULT_TX_BUFFERS, GlendaNetDevice};
+use alloc::vec::Vec;
+
+pub const SHM_SLOT_SIZE: usize = 4096;
+
+/// A device's share of the pool: `slots` pages from slot `base` on, holding
+/// `rx_buffers` RX buffers followed by `tx_buffers` TX staging pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmWindow {
+    pub base: usize,
+    pub slots: usize,
+    pub rx_buffers: usize,
+    pub tx_buffers: usize,
+}
+
+impl ShmWindow {
+    /// Byte offset of the window from the start of the global SHM.
+    pub fn offset(&self) -> usize {
+        self.base * SHM_SLOT_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        self.slots * SHM_SLOT_SIZE
+    }
+}
+
+/// Page-sized slots of the global packet SHM region. Devices take their RX buffers and
+/// TX staging pages from here and hand them back when they go away, so no two users
+/// ever share a page.
+pub struct ShmPool {
+    free: Vec<usize>,
+    slots: usize,
+}
+
+impl ShmPool {
+    pub fn new(size: usize) -> Self {
+        let slots = size / SHM_SLOT_SIZE;
+        Self { free: (0..slots).rev().collect(), slots }
+    }
+
+    pub fn alloc_slot(&mut self) -> Option<usize> {
+        self.free.pop()
+    }
+
+    pub fn free_slot(&mut self, idx: usize) {
+        if idx >= self.slots || self.free.contains(&idx) {
+            warn!("Ignoring free of invalid SHM slot {}", idx);
+            return;
+        }
+        self.free.push(idx);
+    }
+
+    /// Take `count` adjacent slots, returning the index of the first one.
+    pub fn reserve(&mut self, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        let mut free = self.free.clone();
+        free.sort_unstable();
+        let start = free.windows(count).find(|run| run[count - 1] - run[0] == count - 1)?[0];
+        self.free.retain(|idx| !(start..start + count).contains(idx));
+        Some(start)
+    }
+
+    /// Reserve a window for a device with the given buffer counts, falling back to the
+    /// defaults when those do not fit but the defaults do.
+    pub fn reserve_window(&mut self, rx_buffers: usize, tx_buffers: usize) -> Option<ShmWindow> {
+        let slots = GlendaNetDevice::shm_slots(rx_buffers, tx_buffers);
+        if let Some(base) = self.reserve(slots) {
+            return Some(ShmWindow { base, slots, rx_buffers, tx_buffers });
+        }
+        let slots = GlendaNetDevice::shm_slots(DEFAULT_RX_BUFFERS, DEFAULT_TX_BUFFERS);
+        let base = self.reserve(slots)?;
+        Some(ShmWindow {
+            base,
+            slots,
+            rx_buffers: DEFAULT_RX_BUFFERS,
+            tx_buffers: DEFAULT_TX_BUFFERS,
+        })
+    }
+
+    pub fn release(&mut self, start: usize, count: usize) {
+        for idx in start..start + count {
+            self.free_slot(idx);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Two devices probed from the same pool, the way `probe_pending` does it, stand in
+    /// for two loopback devices: filling every page of one window must leave the other
+    /// untouched, and a window given back is handed out again.
+    #[test]
+    fn device_windows_are_disjoint() {
+        let mut pool = ShmPool::new(64 * SHM_SLOT_SIZE);
+        let mut shm = vec![0u8; 64 * SHM_SLOT_SIZE];
+        let a = pool.reserve_window(8, 8).unwrap();
+        let b = pool.reserve_window(8, 8).unwrap();
+        assert!(a.offset() + a.size() <= b.offset() || b.offset() + b.size() <= a.offset());
+
+        for (window, marker) in [(a, 0xAA), (b, 0xBB)] {
+            for slot in 0..window.slots {
+                let start = window.offset() + slot * SHM_SLOT_SIZE;
+                shm[start..start + SHM_SLOT_SIZE].fill(marker);
+            }
+        }
+        assert!(shm[a.offset()..a.offset() + a.size()].iter().all(|&byte| byte == 0xAA));
+        assert!(shm[b.offset()..b.offset() + b.size()].iter().all(|&byte| byte == 0xBB));
+
+        pool.release(a.base, a.slots);
+        assert_eq!(pool.reserve_window(8, 8), Some(a));
+    }
+
+    #[test]
+    fn window_falls_back_to_default_buffers() {
+        let slots = GlendaNetDevice::shm_slots(DEFAULT_RX_BUFFERS, DEFAULT_TX_BUFFERS);
+        let mut pool = ShmPool::new(slots * SHM_SLOT_SIZE);
+        let window = pool.reserve_window(1024, 1024).unwrap();
+        assert_eq!(
+            (window.rx_buffers, window.tx_buffers),
+            (DEFAULT_RX_BUFFERS, DEFAULT_TX_BUFFERS)
+        );
+        assert_eq!(pool.reserve_window(1, 1), None);
+    }
+}